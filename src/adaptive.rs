@@ -0,0 +1,107 @@
+use crate::color::luminance;
+use crate::utils::clamp;
+use crate::vec3::Color;
+use std::io::{self, Write};
+
+/// Running statistics of the samples of a pixel
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PixelStats {
+    pub sum: Color,
+    pub luminance_sum: f64,
+    pub luminance_squared_sum: f64,
+    pub count: u32,
+}
+
+impl PixelStats {
+    /// Adds a sample
+    pub fn add(&mut self, color: Color) {
+        let y = luminance(&color);
+        self.sum += color;
+        self.luminance_sum += y;
+        self.luminance_squared_sum += y * y;
+        self.count += 1;
+    }
+    /// Relative error of the mean luminance: its standard error over its value.
+    /// Dark pixels are compared to a small floor instead, so that they can converge.
+    pub fn error(&self) -> f64 {
+        if self.count < 2 {
+            return f64::INFINITY;
+        }
+        let n = self.count as f64;
+        let mean = self.luminance_sum / n;
+        let variance = ((self.luminance_squared_sum - n * mean * mean) / (n - 1.)).max(0.);
+        (variance / n).sqrt() / mean.max(0.01)
+    }
+}
+
+/// Color of a sample count on a black, red, yellow, white ramp up to `max`
+pub fn heat(count: u32, max: u32) -> Color {
+    let t = 3. * count as f64 / max.max(1) as f64;
+    Color::new(
+        clamp(t, 0., 1.),
+        clamp(t - 1., 0., 1.),
+        clamp(t - 2., 0., 1.),
+    )
+}
+
+/// Writes the sample counts of the pixels as a PPM image, from the top row.
+/// `counts` is row by row from the bottom, like the rendered image.
+pub fn write_heatmap<T: Write>(
+    out: &mut T,
+    counts: &[u32],
+    width: usize,
+    height: usize,
+    max: u32,
+) -> io::Result<()> {
+    write!(out, "P3\n{} {}\n255\n", width, height)?;
+    for j in (0..height).rev() {
+        for i in 0..width {
+            let color = heat(counts[j * width + i], max) * 255.;
+            writeln!(
+                out,
+                "{} {} {}",
+                color.x() as u8,
+                color.y() as u8,
+                color.z() as u8
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixel_error_shrinks_with_samples() {
+        let mut flat = PixelStats::default();
+        let mut noisy = PixelStats::default();
+        assert_eq!(flat.error(), f64::INFINITY);
+        for i in 0..100 {
+            flat.add(Color::one());
+            noisy.add(Color::one() * (i % 2) as f64 * 2.);
+        }
+        assert_eq!(flat.error(), 0.);
+        assert_eq!(flat.sum, Color::one() * 100.);
+        assert_eq!(noisy.sum, Color::one() * 100.);
+        let error = noisy.error();
+        assert!(error > 0.05 && error < 0.2, "error was {}", error);
+        for i in 0..300 {
+            noisy.add(Color::one() * (i % 2) as f64 * 2.);
+        }
+        assert!(noisy.error() < error / 1.5);
+    }
+
+    #[test]
+    fn heatmap() {
+        assert_eq!(heat(0, 100), Color::zero());
+        assert_eq!(heat(100, 100), Color::one());
+        let mut out = Vec::new();
+        write_heatmap(&mut out, &[0, 10], 2, 1, 10).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "P3\n2 1\n255\n0 0 0\n255 255 255\n"
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use crate::exr::{self, Layer};
+use crate::film::write_pfm;
+use crate::hittable::HitRecord;
+use crate::ray::Ray;
+use crate::scene::Scene;
+use crate::vec3::Color;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufWriter};
+
+/// Arbitrary output variables: passes of the render that compositors use separately
+/// from the image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Aov {
+    /// Light that reached the camera after one bounce, sampled from a diffuse lobe
+    DiffuseDirect,
+    /// Light that reached the camera after more bounces, the first sampled from a
+    /// diffuse lobe
+    DiffuseIndirect,
+    SpecularDirect,
+    SpecularIndirect,
+    /// Light of the emitters and of the background seen by the camera
+    Emission,
+    Albedo,
+    Normal,
+    /// Distance from the camera
+    Depth,
+    Position,
+    /// Index of the object seen, plus one so that 0 is the background
+    ObjectId,
+    /// Index of the material seen in the scene's materials, plus one
+    MaterialId,
+}
+
+/// Names of the AOVs, as given on the command line
+pub const AOV_NAMES: [&str; 11] = [
+    "diffuse-direct",
+    "diffuse-indirect",
+    "specular-direct",
+    "specular-indirect",
+    "emission",
+    "albedo",
+    "normal",
+    "depth",
+    "position",
+    "object-id",
+    "material-id",
+];
+
+/// Every AOV, in the order of `AOV_NAMES`
+pub const ALL_AOVS: [Aov; 11] = [
+    Aov::DiffuseDirect,
+    Aov::DiffuseIndirect,
+    Aov::SpecularDirect,
+    Aov::SpecularIndirect,
+    Aov::Emission,
+    Aov::Albedo,
+    Aov::Normal,
+    Aov::Depth,
+    Aov::Position,
+    Aov::ObjectId,
+    Aov::MaterialId,
+];
+
+impl Aov {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match AOV_NAMES.iter().position(|name| *name == s) {
+            Some(index) => Ok(ALL_AOVS[index]),
+            None => Err(format!(
+                "Unknown AOV `{}`, expected one of {:?}",
+                s, AOV_NAMES
+            )),
+        }
+    }
+    /// Parses a comma separated list of AOVs, or `all`
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        if s == "all" {
+            return Ok(ALL_AOVS.to_vec());
+        }
+        s.split(',').map(Aov::parse).collect()
+    }
+    /// Name of the AOV, as given on the command line
+    pub fn name(self) -> &'static str {
+        AOV_NAMES[self as usize]
+    }
+    /// Names of the channels of the pass in an image
+    pub fn channels(self) -> &'static [&'static str] {
+        match self {
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId | Aov::MaterialId => &["Y"],
+            _ => &["R", "G", "B"],
+        }
+    }
+    /// True for the identifiers, that would be meaningless averaged over the samples of
+    /// a pixel, so they are taken from its first sample
+    pub fn is_id(self) -> bool {
+        self == Aov::ObjectId || self == Aov::MaterialId
+    }
+}
+
+/// The values of the AOVs for a sample, or their sums over samples. Values with a single
+/// channel are in every channel.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SampleAovs {
+    pub values: [Color; 11],
+}
+
+impl SampleAovs {
+    pub fn get(&self, aov: Aov) -> Color {
+        self.values[aov as usize]
+    }
+    /// Adds the light that reached the camera after `bounces` bounces, the first sampled
+    /// from a specular lobe or not
+    pub fn add_light(&mut self, bounces: u32, specular: bool, light: Color) {
+        let aov = match (bounces, specular) {
+            (0, _) => Aov::Emission,
+            (1, false) => Aov::DiffuseDirect,
+            (_, false) => Aov::DiffuseIndirect,
+            (1, true) => Aov::SpecularDirect,
+            (_, true) => Aov::SpecularIndirect,
+        };
+        self.values[aov as usize] += light;
+    }
+    /// Sets the features of the first hit of the camera ray `r`, on the object of the
+    /// world with the given index
+    pub fn first_hit(&mut self, scene: &Scene, r: &Ray, rec: &HitRecord, object: usize) {
+        let id = |index: Option<usize>| Color::one() * index.map_or(0., |index| index as f64 + 1.);
+        self.values[Aov::Albedo as usize] = rec.material.albedo(rec);
+        self.values[Aov::Normal as usize] = rec.normal;
+        self.values[Aov::Depth as usize] = Color::one() * (rec.point - r.orig()).norm();
+        self.values[Aov::Position as usize] = rec.point;
+        self.values[Aov::ObjectId as usize] = id(Some(object));
+        self.values[Aov::MaterialId as usize] = id(scene.material_index(&rec.material));
+    }
+    /// Adds the values of a sample. The identifiers are only kept for the first sample.
+    pub fn add(&mut self, sample: &SampleAovs, first: bool) {
+        for aov in ALL_AOVS.iter() {
+            let index = *aov as usize;
+            if !aov.is_id() {
+                self.values[index] += sample.values[index];
+            } else if first {
+                self.values[index] = sample.values[index];
+            }
+        }
+    }
+}
+
+thread_local! {
+    /// The AOVs of the sample being traced on this thread, when they are recorded
+    static RECORDER: RefCell<Option<SampleAovs>> = const { RefCell::new(None) };
+}
+
+/// Updates the AOVs of the sample being traced, if they are recorded.
+/// Integrators call it with the light they find and at the first hit.
+pub fn record<F: FnOnce(&mut SampleAovs)>(update: F) {
+    RECORDER.with(|recorder| {
+        if let Some(aovs) = recorder.borrow_mut().as_mut() {
+            update(aovs);
+        }
+    });
+}
+
+/// Traces a sample with `trace`, recording its AOVs if `enabled`. Only the integrators
+/// that call `record` fill them in.
+pub fn recording<F: FnOnce() -> Color>(enabled: bool, trace: F) -> (Color, Option<SampleAovs>) {
+    if !enabled {
+        return (trace(), None);
+    }
+    RECORDER.with(|recorder| *recorder.borrow_mut() = Some(SampleAovs::default()));
+    let color = trace();
+    (
+        color,
+        RECORDER.with(|recorder| recorder.borrow_mut().take()),
+    )
+}
+
+/// The sums of the values of the AOVs over the samples of each pixel
+#[derive(Debug, Clone, Default)]
+pub struct AovBuffers {
+    pub aovs: Vec<Aov>,
+    /// For each AOV, the sums of the pixels, row by row from the bottom
+    pub sums: Vec<Vec<Color>>,
+}
+
+impl AovBuffers {
+    pub fn new(aovs: &[Aov], pixels: usize) -> Self {
+        Self {
+            aovs: aovs.to_vec(),
+            sums: vec![vec![Color::zero(); pixels]; aovs.len()],
+        }
+    }
+    /// Adds the sums of the AOVs of some samples of a pixel
+    pub fn add(&mut self, pixel: usize, sums: &SampleAovs) {
+        for (aov, image) in self.aovs.iter().zip(self.sums.iter_mut()) {
+            image[pixel] += sums.get(*aov);
+        }
+    }
+    /// The images of the AOVs: the means of the samples, or the identifiers of the first
+    /// samples, of pixels with the given numbers of samples
+    pub fn images(&self, counts: &[u32]) -> Vec<Vec<Color>> {
+        self.aovs
+            .iter()
+            .zip(self.sums.iter())
+            .map(|(aov, sums)| {
+                sums.iter()
+                    .zip(counts.iter())
+                    .map(|(sum, count)| {
+                        if aov.is_id() {
+                            *sum
+                        } else {
+                            *sum / (*count).max(1) as f64
+                        }
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+/// Writes the images of the AOVs to `path`. If it ends with `.exr` they are the layers
+/// of a multi-layer EXR, whose unnamed layer is the image. Otherwise each AOV is written
+/// to its own PFM image, at `path.aov.pfm`.
+pub fn write_aovs(
+    path: &str,
+    aovs: &[Aov],
+    images: &[Vec<Color>],
+    image: &[Color],
+    width: usize,
+    height: usize,
+) -> io::Result<()> {
+    if path.ends_with(".exr") {
+        let mut layers = vec![Layer {
+            name: "",
+            channels: &["R", "G", "B"],
+            pixels: image,
+        }];
+        for (aov, pixels) in aovs.iter().zip(images.iter()) {
+            layers.push(Layer {
+                name: aov.name(),
+                channels: aov.channels(),
+                pixels,
+            });
+        }
+        let mut out = BufWriter::new(File::create(path)?);
+        exr::write_exr(&mut out, &layers, width, height)
+    } else {
+        for (aov, pixels) in aovs.iter().zip(images.iter()) {
+            let mut out = BufWriter::new(File::create(format!("{}.{}.pfm", path, aov.name()))?);
+            write_pfm(&mut out, pixels, aov.channels().len(), width, height)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::camera::Camera;
+    use crate::hittable::HittableList;
+    use crate::integrator::{Integrator, PathTracer};
+    use crate::principled::Principled;
+    use crate::ray::Ray;
+    use crate::sampler::Seeded;
+    use crate::scene::{self, Background, Scene};
+    use crate::settings::{DirectLighting, Settings};
+    use crate::sphere::Sphere;
+    use crate::vec3::{Point3, Vec3};
+    use std::sync::Arc;
+
+    #[test]
+    fn aov_names() {
+        for (aov, name) in ALL_AOVS.iter().zip(AOV_NAMES.iter()) {
+            assert_eq!(Aov::parse(name), Ok(*aov));
+            assert_eq!(aov.name(), *name);
+        }
+        assert_eq!(
+            Aov::parse_list("albedo,depth"),
+            Ok(vec![Aov::Albedo, Aov::Depth])
+        );
+        assert_eq!(Aov::parse_list("all").unwrap().len(), 11);
+        assert!(Aov::parse_list("albedo,shadow").is_err());
+    }
+
+    #[test]
+    fn light_passes_add_up_to_the_image() {
+        let scene = scene::lights_scene(crate::ASPECT_RATIO as f64);
+        let settings = Settings::default();
+        for direct_lighting in [DirectLighting::Bsdf, DirectLighting::Mis].iter() {
+            let path_tracer = PathTracer {
+                max_depth: 6,
+                rr_depth: 3,
+                direct_lighting: *direct_lighting,
+            };
+            let mut buffers = AovBuffers::new(&ALL_AOVS, 1);
+            let mut seen_objects = Vec::new();
+            for index in 0..64 {
+                let pixel = 200 * crate::IMAGE_WIDTH as u64 + 256;
+                let mut sampler = settings.sampler.pixel_sampler(1, pixel, index, 64);
+                let (color, aovs) = recording(true, || {
+                    crate::shoot_ray(
+                        (256.5, 200.5),
+                        &scene,
+                        &path_tracer,
+                        sampler.as_mut(),
+                        &mut Vec::new(),
+                    )
+                });
+                let aovs = aovs.unwrap();
+                let light = [
+                    Aov::DiffuseDirect,
+                    Aov::DiffuseIndirect,
+                    Aov::SpecularDirect,
+                    Aov::SpecularIndirect,
+                    Aov::Emission,
+                ]
+                .iter()
+                .map(|aov| aovs.get(*aov))
+                .sum::<Color>();
+                assert!((light - color).near_zero(), "{:?} {:?}", light, color);
+                seen_objects.push(aovs.get(Aov::ObjectId).x());
+                buffers.add(0, &{
+                    let mut sums = SampleAovs::default();
+                    sums.add(&aovs, index == 0);
+                    sums
+                });
+            }
+            let images = buffers.images(&[64]);
+            // The identifiers are the ones of the first sample, not averaged
+            assert_eq!(images[Aov::ObjectId as usize][0].x(), seen_objects[0]);
+            assert!(seen_objects[0] >= 1.);
+            let normal = images[Aov::Normal as usize][0];
+            assert!(normal.norm() > 0.5 && normal.norm() <= 1. + 1e-9);
+            assert!(images[Aov::Depth as usize][0].x() > 0.);
+        }
+        // Nothing is recorded outside of `recording`
+        record(|_| unreachable!());
+    }
+
+    /// The light of the diffuse and of the specular passes of a sphere with the material,
+    /// lit by the sky
+    fn diffuse_and_specular(material: Principled) -> (f64, f64) {
+        let mut world = HittableList::new();
+        world.add(Box::new(Sphere::new(
+            Point3::new(0., 0., -2.),
+            0.5,
+            Arc::new(material),
+        )));
+        let scene = Scene::new(world, Background::Sky, Camera::default());
+        let path_tracer = PathTracer {
+            max_depth: 4,
+            rr_depth: 4,
+            direct_lighting: DirectLighting::Mis,
+        };
+        let r = Ray::new(Point3::zero(), Vec3::new(0.1, 0.1, -1.));
+        let mut sampler = Seeded::new(0);
+        let (mut diffuse, mut specular) = (0., 0.);
+        for _ in 0..256 {
+            let (_, aovs) = recording(true, || {
+                path_tracer.li(&r, &scene, &mut sampler, &mut Vec::new())
+            });
+            let aovs = aovs.unwrap();
+            let sum = |passes: [Aov; 2]| passes.iter().map(|aov| aovs.get(*aov).x()).sum::<f64>();
+            diffuse += sum([Aov::DiffuseDirect, Aov::DiffuseIndirect]);
+            specular += sum([Aov::SpecularDirect, Aov::SpecularIndirect]);
+        }
+        (diffuse, specular)
+    }
+
+    #[test]
+    fn light_passes_follow_the_sampled_lobe() {
+        // The specular reflections of a dielectric surface and the diffuse light of a
+        // mostly metallic one both have their passes
+        let plastic = Principled {
+            roughness: 0.2,
+            clearcoat: 1.,
+            ..Principled::new(Color::new(0.8, 0.1, 0.1))
+        };
+        let (diffuse, specular) = diffuse_and_specular(plastic);
+        assert!(diffuse > 0. && specular > 0., "{} {}", diffuse, specular);
+        let metal = Principled {
+            metallic: 0.6,
+            ..Principled::new(Color::new(0.8, 0.7, 0.5))
+        };
+        let (diffuse, specular) = diffuse_and_specular(metal);
+        assert!(
+            diffuse > 0. && specular > diffuse,
+            "{} {}",
+            diffuse,
+            specular
+        );
+        // A diffuse surface has no specular light
+        let matte = Principled {
+            specular: 0.,
+            ..Principled::new(Color::new(0.5, 0.5, 0.5))
+        };
+        let (diffuse, specular) = diffuse_and_specular(matte);
+        assert!(diffuse > 0. && specular == 0., "{} {}", diffuse, specular);
+    }
+}
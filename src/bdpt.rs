@@ -0,0 +1,688 @@
+use crate::camera::Projection;
+use crate::hittable::{HitRecord, Hittable};
+use crate::integrator::{Integrator, Splat};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::vec3::{random_cosine_direction, Color, Point3, Vec3};
+use std::f64::consts::PI;
+
+/// Bidirectional path tracer (Veach 1997), following the formulation of PBRT.
+/// A subpath is traced from the camera and another one from a light, then every
+/// prefix of one is connected to every prefix of the other. The strategies are
+/// combined with multiple importance sampling (power heuristic).
+/// Connections straight to the camera (t = 1) land on arbitrary pixels and
+/// are returned as splats.
+#[derive(Debug, Clone, Copy)]
+pub struct Bdpt {
+    /// Maximum number of bounces of a full path
+    pub max_depth: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum VertexKind {
+    Camera,
+    Light,
+    Surface,
+}
+
+/// A vertex of a camera or light subpath
+#[derive(Debug, Clone)]
+struct Vertex {
+    kind: VertexKind,
+    point: Point3,
+    /// Lights: the outward normal. Surfaces: the normal facing the ray that reached them.
+    /// Camera: the viewing direction
+    normal: Vec3,
+    /// Direction towards the previous vertex of the subpath
+    wo: Vec3,
+    /// The hit, for lights and surfaces
+    rec: Option<HitRecord>,
+    /// Throughput of the subpath up to this vertex
+    beta: Color,
+    /// Area density of sampling this vertex from the previous one
+    pdf_fwd: f64,
+    /// Area density of sampling this vertex from the next one, going backwards
+    pdf_rev: f64,
+    /// True if the vertex was scattered by a delta lobe
+    delta: bool,
+    /// Camera: true for the parallel rays of an orthographic camera, whose densities are
+    /// over the area of the film rather than over solid angle
+    parallel: bool,
+}
+
+impl Vertex {
+    fn camera(scene: &Scene, point: Point3, forward: Vec3, beta: Color) -> Self {
+        Self {
+            kind: VertexKind::Camera,
+            point,
+            normal: forward,
+            wo: Vec3::zero(),
+            rec: None,
+            beta,
+            pdf_fwd: 0.,
+            pdf_rev: 0.,
+            delta: false,
+            parallel: scene.camera.projection() == Projection::Orthographic,
+        }
+    }
+    fn light(rec: HitRecord, beta: Color, pdf_fwd: f64) -> Self {
+        Self {
+            kind: VertexKind::Light,
+            point: rec.point,
+            normal: rec.normal,
+            wo: Vec3::zero(),
+            rec: Some(rec),
+            beta,
+            pdf_fwd,
+            pdf_rev: 0.,
+            delta: false,
+            parallel: false,
+        }
+    }
+    fn surface(rec: HitRecord, wo: Vec3, beta: Color) -> Self {
+        Self {
+            kind: VertexKind::Surface,
+            point: rec.point,
+            normal: rec.normal,
+            wo,
+            rec: Some(rec),
+            beta,
+            pdf_fwd: 0.,
+            pdf_rev: 0.,
+            delta: false,
+            parallel: false,
+        }
+    }
+
+    fn rec(&self) -> &HitRecord {
+        self.rec
+            .as_ref()
+            .expect("only lights and surfaces have a hit record")
+    }
+    fn is_on_surface(&self) -> bool {
+        self.kind != VertexKind::Camera
+    }
+    fn is_light(&self) -> bool {
+        match self.kind {
+            VertexKind::Light => true,
+            VertexKind::Surface => self.rec().material.is_emissive(),
+            VertexKind::Camera => false,
+        }
+    }
+    /// False for vertices that can only scatter through delta lobes
+    fn is_connectible(&self) -> bool {
+        match self.kind {
+            VertexKind::Surface => !self.rec().material.is_delta(),
+            _ => true,
+        }
+    }
+    /// Normal on the side the light is emitted to
+    fn outward_normal(&self) -> Vec3 {
+        match self.kind {
+            VertexKind::Surface if !self.rec().front_face => -self.normal,
+            _ => self.normal,
+        }
+    }
+
+    /// BSDF for light arriving from `next` and leaving towards the previous vertex
+    fn f(&self, next: &Vertex) -> Color {
+        match self.kind {
+            VertexKind::Surface => {
+                let wi = (next.point - self.point).unit_vector();
+                bsdf_f(self.rec(), &self.wo, &wi)
+            }
+            _ => Color::zero(),
+        }
+    }
+    /// Light emitted towards `toward`
+    fn le(&self, toward: &Vertex) -> Color {
+        if !self.is_light() {
+            return Color::zero();
+        }
+        let w = toward.point - self.point;
+        if w.dot(&self.outward_normal()) <= 0. {
+            return Color::zero();
+        }
+        let mut rec = self.rec().clone();
+        rec.front_face = true;
+        rec.material.emitted(&rec)
+    }
+    /// Converts a solid angle density of sampling `next` from this vertex to an area density
+    fn convert_density(&self, pdf: f64, next: &Vertex) -> f64 {
+        let w = next.point - self.point;
+        let distance_squared = w.norm_squared();
+        if distance_squared == 0. {
+            return 0.;
+        }
+        // Parallel rays don't spread out with the distance
+        let mut pdf = if self.parallel {
+            pdf
+        } else {
+            pdf / distance_squared
+        };
+        if next.is_on_surface() {
+            pdf *= next.normal.dot(&w.unit_vector()).abs();
+        }
+        pdf
+    }
+    /// Area density of sampling `next` from this vertex, reached from `prev`
+    fn pdf(&self, scene: &Scene, prev: Option<&Vertex>, next: &Vertex) -> f64 {
+        if self.kind == VertexKind::Light {
+            return self.pdf_light(next);
+        }
+        let wn = (next.point - self.point).unit_vector();
+        let pdf = match self.kind {
+            VertexKind::Camera => scene.camera.pdf_we(&Ray::new(self.point, wn)).1,
+            _ => match prev {
+                Some(prev) => {
+                    let wp = (prev.point - self.point).unit_vector();
+                    bsdf_pdf(self.rec(), &wp, &wn)
+                }
+                None => 0.,
+            },
+        };
+        self.convert_density(pdf, next)
+    }
+    /// Area density of a light at this vertex emitting towards `next`
+    fn pdf_light(&self, next: &Vertex) -> f64 {
+        let w = next.point - self.point;
+        let distance_squared = w.norm_squared();
+        if distance_squared == 0. {
+            return 0.;
+        }
+        let w = w.unit_vector();
+        let mut pdf = w.dot(&self.outward_normal()).max(0.) / PI / distance_squared;
+        if next.is_on_surface() {
+            pdf *= next.normal.dot(&w).abs();
+        }
+        pdf
+    }
+    /// Area density of sampling this vertex as the start of a light subpath
+    fn pdf_light_origin(&self, scene: &Scene) -> f64 {
+        scene.lights.surface_pdf(&self.point)
+    }
+}
+
+/// Calls `f` with the hit and a shading frame oriented towards the side `wo` is on
+fn with_frame<T>(rec: &HitRecord, wo: &Vec3, f: impl FnOnce(&HitRecord, &Onb) -> T) -> T {
+    if wo.dot(&rec.normal) >= 0. {
+        f(rec, &Onb::build_from_w(&rec.normal))
+    } else {
+        let mut flipped = rec.clone();
+        flipped.normal = -rec.normal;
+        flipped.front_face = !rec.front_face;
+        f(&flipped, &Onb::build_from_w(&flipped.normal))
+    }
+}
+/// BSDF of a hit for world space directions
+fn bsdf_f(rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> Color {
+    with_frame(rec, wo, |rec, onb| {
+        rec.material.eval(&onb.to_local(wo), &onb.to_local(wi), rec)
+    })
+}
+/// BSDF density of a hit for world space directions
+fn bsdf_pdf(rec: &HitRecord, wo: &Vec3, wi: &Vec3) -> f64 {
+    with_frame(rec, wo, |rec, onb| {
+        rec.material.pdf(&onb.to_local(wo), &onb.to_local(wi), rec)
+    })
+}
+
+/// True if nothing blocks the segment between `a` and `b`
+fn visible(scene: &Scene, a: &Point3, b: &Point3) -> bool {
+    let d = *b - *a;
+    let distance = d.norm();
+    let r = Ray::new(*a, d / distance);
+    scene.world.hit(&r, 0.001, distance - 0.001).is_none()
+}
+/// Geometry term between two vertices, zero if they don't see each other
+fn g(scene: &Scene, v0: &Vertex, v1: &Vertex) -> f64 {
+    let d = v0.point - v1.point;
+    let mut g = 1. / d.norm_squared();
+    let d = d.unit_vector();
+    if v0.is_on_surface() {
+        g *= v0.normal.dot(&d).abs();
+    }
+    if v1.is_on_surface() {
+        g *= v1.normal.dot(&d).abs();
+    }
+    if g > 0. && visible(scene, &v0.point, &v1.point) {
+        g
+    } else {
+        0.
+    }
+}
+
+impl Bdpt {
+    /// Extends `path`, which holds its first vertex, by tracing `ray` through the scene.
+    /// `pdf` is the solid angle density of the ray's direction.
+    /// Returns the light of the background if a camera subpath escapes the scene.
+    fn random_walk(
+        &self,
+        scene: &Scene,
+        mut ray: Ray,
+        mut beta: Color,
+        pdf: f64,
+        sampler: &mut dyn Sampler,
+        path: &mut Vec<Vertex>,
+    ) -> Color {
+        let camera_path = path[0].kind == VertexKind::Camera;
+        // Camera subpaths have one more vertex, to hit lights by themselves
+        let max_vertices = self.max_depth as usize + if camera_path { 2 } else { 1 };
+        let mut pdf_fwd = pdf;
+        while path.len() < max_vertices {
+            let rec = match scene.world.hit(&ray, 0.001, f64::INFINITY) {
+                Some(rec) => rec,
+                None => {
+                    if camera_path {
+                        return beta * scene.background.color(&ray);
+                    }
+                    break;
+                }
+            };
+            beta *= rec.material.transmittance(&ray, &rec);
+            let wo = -ray.dir().unit_vector();
+            let mut vertex = Vertex::surface(rec, wo, beta);
+            vertex.pdf_fwd = path
+                .last()
+                .expect("subpaths start at the camera or a light")
+                .convert_density(pdf_fwd, &vertex);
+            path.push(vertex);
+            if path.len() >= max_vertices {
+                break;
+            }
+
+            let n = path.len();
+            let (previous, last) = path.split_at_mut(n - 1);
+            let vertex = &mut last[0];
+            let rec = vertex.rec.as_ref().unwrap();
+            let onb = Onb::build_from_w(&rec.normal);
+            let sample = match rec.material.sample(&onb.to_local(&wo), rec, sampler) {
+                Some(sample) if sample.pdf > 0. => sample,
+                _ => break,
+            };
+            let wi = onb.local(&sample.wi);
+            beta *= sample.weight();
+            let pdf_rev = if sample.delta {
+                pdf_fwd = 0.;
+                0.
+            } else {
+                pdf_fwd = sample.pdf;
+                bsdf_pdf(rec, &wi, &wo)
+            };
+            vertex.delta = sample.delta;
+            let prev = &mut previous[n - 2];
+            prev.pdf_rev = vertex.convert_density(pdf_rev, prev);
+            ray = Ray::new(vertex.point, wi);
+        }
+        Color::zero()
+    }
+
+    /// Traces a subpath from the camera. Returns the light of the background if it escapes
+    fn camera_subpath(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        path: &mut Vec<Vertex>,
+    ) -> Color {
+        let forward = r.dir().unit_vector();
+        path.push(Vertex::camera(scene, r.orig(), forward, Color::one()));
+        let (_, pdf_dir) = scene.camera.pdf_we(r);
+        self.random_walk(scene, *r, Color::one(), pdf_dir, sampler, path)
+    }
+
+    /// Traces a subpath from a point sampled on a light
+    fn light_subpath(&self, scene: &Scene, sampler: &mut dyn Sampler, path: &mut Vec<Vertex>) {
+        let (rec, pdf_pos) = match scene.lights.sample_surface(sampler) {
+            Some(sample) => sample,
+            None => return,
+        };
+        let onb = Onb::build_from_w(&rec.normal);
+        let local = random_cosine_direction(sampler);
+        let pdf_dir = local.z() / PI;
+        if pdf_pos <= 0. || pdf_dir <= 0. {
+            return;
+        }
+        let le = rec.material.emitted(&rec);
+        let ray = Ray::new(rec.point, onb.local(&local));
+        path.push(Vertex::light(rec, le, pdf_pos));
+        let beta = le * local.z() / (pdf_pos * pdf_dir);
+        self.random_walk(scene, ray, beta, pdf_dir, sampler, path);
+    }
+
+    /// Connects the first `s` vertices of the light subpath with the first `t` of the camera subpath.
+    /// Returns the weighted contribution and, for t = 1, the film coordinates it lands on
+    fn connect(
+        &self,
+        scene: &Scene,
+        light: &[Vertex],
+        camera: &[Vertex],
+        s: usize,
+        t: usize,
+        sampler: &mut dyn Sampler,
+    ) -> (Color, Option<(f64, f64)>) {
+        let mut sampled: Option<Vertex> = None;
+        let mut film = None;
+        let l = if s == 0 {
+            // The camera subpath hit a light by itself
+            let pt = &camera[t - 1];
+            pt.le(&camera[t - 2]) * pt.beta
+        } else if t == 1 {
+            // Connect a light subpath vertex to the camera
+            let qs = &light[s - 1];
+            if !qs.is_connectible() {
+                return (Color::zero(), None);
+            }
+            let sample = match scene.camera.sample_wi(&qs.point, sampler) {
+                Some(sample) if sample.importance > 0. && sample.pdf > 0. => sample,
+                _ => return (Color::zero(), None),
+            };
+            let forward = (qs.point - sample.lens_point).unit_vector();
+            let camera_vertex = Vertex::camera(
+                scene,
+                sample.lens_point,
+                forward,
+                Color::one() * (sample.importance / sample.pdf),
+            );
+            let mut l = qs.beta * qs.f(&camera_vertex) * camera_vertex.beta;
+            if qs.is_on_surface() {
+                l *= forward.dot(&qs.normal).abs();
+            }
+            if !l.near_zero() && !visible(scene, &qs.point, &sample.lens_point) {
+                l = Color::zero();
+            }
+            film = Some((sample.s, sample.t));
+            sampled = Some(camera_vertex);
+            l
+        } else if s == 1 {
+            // Connect a camera subpath vertex to a point sampled on a light
+            let pt = &camera[t - 1];
+            if !pt.is_connectible() {
+                return (Color::zero(), None);
+            }
+            let (rec, pdf_area) = match scene.lights.sample_surface(sampler) {
+                Some(sample) => sample,
+                None => return (Color::zero(), None),
+            };
+            let w = rec.point - pt.point;
+            let distance_squared = w.norm_squared();
+            let w = w.unit_vector();
+            let cos_light = -rec.normal.dot(&w);
+            if cos_light <= 0. || pdf_area <= 0. {
+                return (Color::zero(), None);
+            }
+            let pdf = pdf_area * distance_squared / cos_light;
+            let le = rec.material.emitted(&rec);
+            let mut light_vertex = Vertex::light(rec, le / pdf, 0.);
+            light_vertex.pdf_fwd = light_vertex.pdf_light_origin(scene);
+            let mut l = pt.beta * pt.f(&light_vertex) * light_vertex.beta;
+            if pt.is_on_surface() {
+                l *= w.dot(&pt.normal).abs();
+            }
+            if !l.near_zero() && !visible(scene, &pt.point, &light_vertex.point) {
+                l = Color::zero();
+            }
+            sampled = Some(light_vertex);
+            l
+        } else {
+            let qs = &light[s - 1];
+            let pt = &camera[t - 1];
+            if !qs.is_connectible() || !pt.is_connectible() {
+                return (Color::zero(), None);
+            }
+            let l = qs.beta * qs.f(pt) * pt.f(qs) * pt.beta;
+            if l.near_zero() {
+                l
+            } else {
+                l * g(scene, qs, pt)
+            }
+        };
+        if l.near_zero() {
+            return (Color::zero(), film);
+        }
+        let weight = mis_weight(scene, light, camera, sampled, s, t);
+        (l * weight, film)
+    }
+}
+
+/// Weight of the strategy (s, t) against all the other ways of sampling the same path
+fn mis_weight(
+    scene: &Scene,
+    light: &[Vertex],
+    camera: &[Vertex],
+    sampled: Option<Vertex>,
+    s: usize,
+    t: usize,
+) -> f64 {
+    if s + t == 2 {
+        return 1.;
+    }
+    let mut light = light[..s].to_vec();
+    let mut camera = camera[..t].to_vec();
+    if let Some(vertex) = sampled {
+        if s == 1 {
+            light[0] = vertex;
+        } else if t == 1 {
+            camera[0] = vertex;
+        }
+    }
+
+    // The connection vertices are not sampled from their BSDFs
+    camera[t - 1].delta = false;
+    if s > 0 {
+        light[s - 1].delta = false;
+    }
+
+    // Densities of sampling the connection vertices the other way around
+    let pt = &camera[t - 1];
+    let pt_rev = if s > 0 {
+        let qs_minus = if s > 1 { Some(&light[s - 2]) } else { None };
+        light[s - 1].pdf(scene, qs_minus, pt)
+    } else {
+        pt.pdf_light_origin(scene)
+    };
+    let pt_minus_rev = if t > 1 {
+        Some(if s > 0 {
+            pt.pdf(scene, Some(&light[s - 1]), &camera[t - 2])
+        } else {
+            pt.pdf_light(&camera[t - 2])
+        })
+    } else {
+        None
+    };
+    let qs_rev = if s > 0 {
+        let pt_minus = if t > 1 { Some(&camera[t - 2]) } else { None };
+        Some(pt.pdf(scene, pt_minus, &light[s - 1]))
+    } else {
+        None
+    };
+    let qs_minus_rev = if s > 1 {
+        Some(light[s - 1].pdf(scene, Some(pt), &light[s - 2]))
+    } else {
+        None
+    };
+    camera[t - 1].pdf_rev = pt_rev;
+    if let Some(pdf) = pt_minus_rev {
+        camera[t - 2].pdf_rev = pdf;
+    }
+    if let Some(pdf) = qs_rev {
+        light[s - 1].pdf_rev = pdf;
+    }
+    if let Some(pdf) = qs_minus_rev {
+        light[s - 2].pdf_rev = pdf;
+    }
+
+    // Delta densities are stored as 0, they cancel out in the ratios
+    let remap = |pdf: f64| if pdf != 0. { pdf } else { 1. };
+    let mut sum_ri = 0.;
+    let mut ri = 1.;
+    for i in (1..t).rev() {
+        ri *= remap(camera[i].pdf_rev) / remap(camera[i].pdf_fwd);
+        if !camera[i].delta && !camera[i - 1].delta {
+            sum_ri += ri * ri;
+        }
+    }
+    let mut ri = 1.;
+    for i in (0..s).rev() {
+        ri *= remap(light[i].pdf_rev) / remap(light[i].pdf_fwd);
+        let delta_light_vertex = if i > 0 { light[i - 1].delta } else { false };
+        if !light[i].delta && !delta_light_vertex {
+            sum_ri += ri * ri;
+        }
+    }
+    1. / (1. + sum_ri)
+}
+
+impl Integrator for Bdpt {
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        splats: &mut Vec<Splat>,
+    ) -> Color {
+        let mut camera = Vec::new();
+        let mut light = Vec::new();
+        let mut l = self.camera_subpath(r, scene, sampler, &mut camera);
+        self.light_subpath(scene, sampler, &mut light);
+
+        for t in 1..=camera.len() {
+            for s in 0..=light.len() {
+                let depth = s as i64 + t as i64 - 2;
+                if (s == 1 && t == 1) || depth < 0 || depth > self.max_depth as i64 {
+                    continue;
+                }
+                let (contribution, film) = self.connect(scene, &light, &camera, s, t, sampler);
+                if t == 1 {
+                    if let Some((s, t)) = film {
+                        if !contribution.near_zero() {
+                            splats.push(Splat {
+                                s,
+                                t,
+                                color: contribution,
+                            });
+                        }
+                    }
+                } else {
+                    l += contribution;
+                }
+            }
+        }
+        l
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::luminance;
+    use crate::film::{film_coordinates, film_pixel};
+    use crate::integrator::PathTracer;
+    use crate::sampler::Seeded;
+    use crate::scene;
+    use crate::settings::DirectLighting;
+
+    #[test]
+    fn bdpt_matches_path_tracer_without_lights() {
+        // Without lights only the background contributes, through the camera subpath,
+        // so both integrators estimate the same thing
+        let scene = scene::simple_scene(1.);
+        let r = Ray::new(Point3::zero(), Vec3::new(0., -0.2, -1.));
+        let bdpt = Bdpt { max_depth: 5 };
+        let path = PathTracer {
+            max_depth: 5,
+            rr_depth: 5,
+            direct_lighting: DirectLighting::Mis,
+        };
+        let n = 4000;
+        let mut splats = Vec::new();
+        let mut a = Color::zero();
+        let mut b = Color::zero();
+        let mut sampler = Seeded::new(0);
+        for _ in 0..n {
+            a += bdpt.li(&r, &scene, &mut sampler, &mut splats);
+            b += path.li(&r, &scene, &mut sampler, &mut splats);
+        }
+        let (a, b) = (a / n as f64, b / n as f64);
+        assert!(splats.is_empty());
+        assert!((a - b).norm() < 0.05 * b.norm(), "{:?} {:?}", a, b);
+    }
+
+    /// Renders a small image of the scene, adding the splats to the pixels they land on
+    fn render(
+        integrator: &dyn Integrator,
+        scene: &Scene,
+        width: usize,
+        height: usize,
+        seed: u64,
+    ) -> Vec<Color> {
+        let n = 4000;
+        let mut image = vec![Color::zero(); width * height];
+        let mut sampler = Seeded::new(seed);
+        let mut splats = Vec::new();
+        for j in 0..height {
+            for i in 0..width {
+                for _ in 0..n {
+                    let (u, v) = sampler.next_2d();
+                    let (s, t) = film_coordinates((i as f64 + u, j as f64 + v), (width, height));
+                    let r = scene.camera.get_ray(s, t, &mut sampler);
+                    image[j * width + i] +=
+                        integrator.li(&r, scene, &mut sampler, &mut splats) / n as f64;
+                }
+            }
+        }
+        for splat in splats {
+            image[film_pixel((splat.s, splat.t), (width, height))] += splat.color / n as f64;
+        }
+        image
+    }
+
+    #[test]
+    fn bdpt_matches_path_tracer_with_lights() {
+        // With a light the camera subpaths, the light subpaths and their connections all
+        // contribute, and together they must estimate the same image as the path tracer
+        let scene = scene::lights_scene(4. / 3.);
+        let bdpt = Bdpt { max_depth: 4 };
+        let path = PathTracer {
+            max_depth: 4,
+            rr_depth: 4,
+            direct_lighting: DirectLighting::Mis,
+        };
+        let (width, height) = (8, 6);
+        let a = render(&bdpt, &scene, width, height, 0);
+        let b = render(&path, &scene, width, height, 0);
+        // Caustics through the glass and the metal give rare bright samples, so a few
+        // pixels may stay far apart. Most must agree, and so must the whole image.
+        let (a, b): (Vec<f64>, Vec<f64>) = (
+            a.iter().map(luminance).collect(),
+            b.iter().map(luminance).collect(),
+        );
+        let close = a
+            .iter()
+            .zip(b.iter())
+            .filter(|(a, b)| (*a - *b).abs() < 0.15 * *b + 1e-3)
+            .count();
+        assert!(close >= a.len() * 85 / 100, "{:?} {:?}", a, b);
+        let (a, b) = (a.iter().sum::<f64>(), b.iter().sum::<f64>());
+        assert!((a - b).abs() < 0.1 * b, "{} {}", a, b);
+    }
+
+    #[test]
+    fn bdpt_splats_on_the_film() {
+        let scene = scene::lights_scene(1.);
+        let bdpt = Bdpt { max_depth: 3 };
+        let mut sampler = Seeded::new(0);
+        let r = scene.camera.get_ray(0.5, 0.3, &mut sampler);
+        let mut splats = Vec::new();
+        for _ in 0..200 {
+            let color = bdpt.li(&r, &scene, &mut sampler, &mut splats);
+            assert!(color.x() >= 0. && color.x().is_finite());
+        }
+        assert!(!splats.is_empty());
+        for splat in splats {
+            assert!((0. ..=1.).contains(&splat.s) && (0. ..=1.).contains(&splat.t));
+        }
+    }
+}
@@ -0,0 +1,123 @@
+use crate::integrator::Integrator;
+use crate::sampler::{SamplerKind, SAMPLER_NAMES};
+use crate::scene::Scene;
+use crate::vec3::Color;
+use rayon::prelude::*;
+
+/// Samplers compared by the benchmark, in the order of `SAMPLER_NAMES`
+const KINDS: [SamplerKind; 4] = [
+    SamplerKind::Independent,
+    SamplerKind::Stratified,
+    SamplerKind::Halton,
+    SamplerKind::Sobol,
+];
+
+/// Renders a `width` by `height` image with `samples` samples per pixel.
+/// Returns the mean of the samples of the pixels, row by row from the bottom.
+/// Light splatted by the integrator is left out.
+pub fn render(
+    scene: &Scene,
+    integrator: &dyn Integrator,
+    kind: SamplerKind,
+    (width, height): (usize, usize),
+    samples: u32,
+    seed: u64,
+) -> Vec<Color> {
+    (0..width * height)
+        .into_par_iter()
+        .map(|pixel| {
+            let (i, j) = (pixel % width, pixel / width);
+            let mut color = Color::zero();
+            for sample in 0..samples {
+                let mut sampler = kind.pixel_sampler(seed, pixel as u64, sample, samples);
+                let (du, dv) = sampler.next_2d();
+                let s = (i as f64 + du) / width as f64;
+                let t = (j as f64 + dv) / height as f64;
+                let r = scene.camera.get_ray(s, t, sampler.as_mut());
+                color += integrator.li(&r, scene, sampler.as_mut(), &mut Vec::new());
+            }
+            color / samples as f64
+        })
+        .collect()
+}
+
+/// Root mean square difference of the channels of two images
+fn rmse(image: &[Color], reference: &[Color]) -> f64 {
+    let sum: f64 = image
+        .iter()
+        .zip(reference)
+        .map(|(a, b)| (*a - *b).norm_squared())
+        .sum();
+    (sum / (3 * image.len()) as f64).sqrt()
+}
+
+/// Measures how the noise decreases with the number of samples for each sampler.
+/// Renders the image at 1, 2, 4... up to `max_samples` samples per pixel and compares it
+/// to a reference with 16 times more samples.
+/// Returns a row per number of samples, with the error of each sampler in the order
+/// of `SAMPLER_NAMES`.
+pub fn sampler_noise(
+    scene: &Scene,
+    integrator: &dyn Integrator,
+    size: (usize, usize),
+    max_samples: u32,
+    seed: u64,
+) -> Vec<(u32, Vec<f64>)> {
+    // A different seed keeps the reference independent of the images
+    let reference_seed = seed.wrapping_add(1);
+    let reference = render(
+        scene,
+        integrator,
+        SamplerKind::Sobol,
+        size,
+        16 * max_samples,
+        reference_seed,
+    );
+    let mut rows = Vec::new();
+    let mut samples = 1;
+    while samples <= max_samples {
+        let errors = KINDS
+            .iter()
+            .map(|kind| {
+                rmse(
+                    &render(scene, integrator, *kind, size, samples, seed),
+                    &reference,
+                )
+            })
+            .collect();
+        rows.push((samples, errors));
+        samples *= 2;
+    }
+    rows
+}
+
+/// Prints the curves of `sampler_noise` as tab separated columns
+pub fn print_sampler_noise(rows: &[(u32, Vec<f64>)]) {
+    println!("spp\t{}", SAMPLER_NAMES.join("\t"));
+    for (samples, errors) in rows {
+        let errors: Vec<String> = errors.iter().map(|error| format!("{:.6}", error)).collect();
+        println!("{}\t{}", samples, errors.join("\t"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::AmbientOcclusion;
+    use crate::scene;
+
+    #[test]
+    fn sampler_noise_decreases() {
+        let scene = scene::simple_scene(1.);
+        let ao = AmbientOcclusion { distance: 1. };
+        let rows = sampler_noise(&scene, &ao, (8, 8), 16, 0);
+        assert_eq!(
+            rows.iter().map(|(samples, _)| *samples).collect::<Vec<_>>(),
+            vec![1, 2, 4, 8, 16]
+        );
+        let (first, last) = (&rows[0].1, &rows[4].1);
+        for (kind, (first, last)) in SAMPLER_NAMES.iter().zip(first.iter().zip(last)) {
+            assert!(last < first, "{}: {} then {}", kind, first, last);
+        }
+    }
+}
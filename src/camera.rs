@@ -1,18 +1,44 @@
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::{random_in_unit_disk, Point3, Vec3};
 
+/// How the camera projects the scene onto the film
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Projection {
+    /// Rays leave the lens towards the film, distant objects look smaller
+    Perspective,
+    /// Rays leave the film parallel to the viewing direction, objects keep their size
+    /// whatever their distance
+    Orthographic,
+}
+
 #[derive(Debug)]
 pub struct Camera {
+    projection: Projection,
     origin: Point3,
     lower_left_corner: Point3,
     horizontal: Vec3,
     vertical: Vec3,
     lens_radius: f64,
+    focus_dist: f64,
     u: Vec3,
     v: Vec3,
     w: Vec3,
 }
 
+/// A point on the lens sampled to see a point of the scene
+#[derive(Debug, Clone, Copy)]
+pub struct CameraSample {
+    pub lens_point: Point3,
+    /// The importance emitted from `lens_point` towards the scene point
+    pub importance: f64,
+    /// Density of the sample, in solid angle as seen from the scene point
+    pub pdf: f64,
+    /// Film coordinates the connection goes through, in [0, 1]
+    pub s: f64,
+    pub t: f64,
+}
+
 impl Camera {
     pub fn new(
         aspect_ratio: f64,
@@ -27,11 +53,7 @@ impl Camera {
         let h = (theta / 2.).tan();
         let viewport_height = 2.0 * h;
         let viewport_width = aspect_ratio * viewport_height;
-
-        // Compute the orthonormal basis for our camera rotation
-        let w = (origin - lookat).unit_vector();
-        let u = vup.cross(&w).unit_vector();
-        let v = w.cross(&u);
+        let (u, v, w) = basis(origin, lookat, vup);
 
         let horizontal = focus_dist * viewport_width * u;
         let vertical = focus_dist * viewport_height * v;
@@ -40,18 +62,85 @@ impl Camera {
         let lens_radius = aperture / 2.;
 
         Self {
+            projection: Projection::Perspective,
             origin,
             lower_left_corner,
             horizontal,
             vertical,
             lens_radius,
+            focus_dist,
             u,
             v,
             w,
         }
     }
-    pub fn get_ray(&self, s: f64, t: f64) -> Ray {
-        let rd: Vec3 = self.lens_radius * random_in_unit_disk();
+    /// An orthographic camera at `origin` looking at `lookat`, whose film is `view_width`
+    /// wide in the units of the scene
+    pub fn orthographic(
+        aspect_ratio: f64,
+        view_width: f64,
+        origin: Point3,
+        lookat: Point3,
+        vup: Vec3,
+    ) -> Self {
+        let (u, v, w) = basis(origin, lookat, vup);
+        let horizontal = view_width * u;
+        let vertical = view_width / aspect_ratio * v;
+        Self {
+            projection: Projection::Orthographic,
+            origin,
+            lower_left_corner: origin - horizontal / 2. - vertical / 2.,
+            horizontal,
+            vertical,
+            lens_radius: 0.,
+            focus_dist: 1.,
+            u,
+            v,
+            w,
+        }
+    }
+    /// An orthographic camera with the position, orientation and aspect ratio of this one
+    pub fn to_orthographic(&self, view_width: f64) -> Self {
+        let aspect_ratio = self.horizontal.norm() / self.vertical.norm();
+        Self::orthographic(
+            aspect_ratio,
+            view_width,
+            self.origin,
+            self.origin - self.w,
+            self.v,
+        )
+    }
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+    /// Parameters of the camera, for the fingerprint of a scene
+    pub fn fingerprint(&self) -> Vec<f64> {
+        let mut parameters = vec![
+            self.projection as u8 as f64,
+            self.lens_radius,
+            self.focus_dist,
+        ];
+        for v in [
+            self.origin,
+            self.lower_left_corner,
+            self.horizontal,
+            self.vertical,
+        ]
+        .iter()
+        {
+            parameters.extend_from_slice(&[v.x(), v.y(), v.z()]);
+        }
+        parameters
+    }
+
+    pub fn get_ray(&self, s: f64, t: f64, sampler: &mut dyn Sampler) -> Ray {
+        if self.projection == Projection::Orthographic {
+            return Ray::new(
+                self.lower_left_corner + s * self.horizontal + t * self.vertical,
+                -self.w,
+            );
+        }
+        let rd: Vec3 = self.lens_radius * random_in_unit_disk(sampler);
         let offset: Vec3 = self.u * rd.x() + self.v * rd.y();
 
         Ray::new(
@@ -59,6 +148,121 @@ impl Camera {
             self.lower_left_corner + s * self.horizontal + t * self.vertical - self.origin - offset,
         )
     }
+
+    /// Area of the lens, 1 for a pinhole so that densities stay finite
+    fn lens_area(&self) -> f64 {
+        if self.lens_radius > 0. {
+            std::f64::consts::PI * self.lens_radius * self.lens_radius
+        } else {
+            1.
+        }
+    }
+    /// Area of the film, on a plane at distance 1 from the lens for a perspective camera
+    fn film_area(&self) -> f64 {
+        self.horizontal.norm() * self.vertical.norm() / (self.focus_dist * self.focus_dist)
+    }
+    /// Cosine between a direction and the viewing direction
+    fn cos_theta(&self, dir: &Vec3) -> f64 {
+        -dir.unit_vector().dot(&self.w)
+    }
+
+    /// Film coordinates `(s, t)` that `get_ray` maps to a ray leaving the lens,
+    /// or `None` if the ray doesn't go through the film. The rays of an orthographic
+    /// camera are taken to leave the film where they start.
+    pub fn film_coordinates(&self, r: &Ray) -> Option<(f64, f64)> {
+        let cos = self.cos_theta(&r.dir());
+        if cos <= 0. {
+            return None;
+        }
+        let on_film = match self.projection {
+            Projection::Perspective => {
+                let distance =
+                    (self.lower_left_corner - r.orig()).dot(&self.w) / r.dir().dot(&self.w);
+                r.at(distance) - self.lower_left_corner
+            }
+            Projection::Orthographic => r.orig() - self.lower_left_corner,
+        };
+        let s = on_film.dot(&self.horizontal) / self.horizontal.norm_squared();
+        let t = on_film.dot(&self.vertical) / self.vertical.norm_squared();
+        if (0. ..=1.).contains(&s) && (0. ..=1.).contains(&t) {
+            Some((s, t))
+        } else {
+            None
+        }
+    }
+
+    /// Importance emitted along a ray leaving the lens, zero outside the film.
+    /// An orthographic camera only emits along its viewing direction, the importance is
+    /// then per unit area of the film.
+    pub fn importance(&self, r: &Ray) -> f64 {
+        if self.film_coordinates(r).is_none() {
+            return 0.;
+        }
+        match self.projection {
+            Projection::Perspective => {
+                let cos = self.cos_theta(&r.dir());
+                1. / (self.film_area() * self.lens_area() * cos.powi(4))
+            }
+            Projection::Orthographic => 1. / self.film_area(),
+        }
+    }
+
+    /// Densities of `get_ray` generating a ray: over the lens area and over solid angle.
+    /// The rays of an orthographic camera start anywhere on the film in a single direction,
+    /// both densities are then over the area of the film.
+    pub fn pdf_we(&self, r: &Ray) -> (f64, f64) {
+        if self.film_coordinates(r).is_none() {
+            return (0., 0.);
+        }
+        match self.projection {
+            Projection::Perspective => {
+                let cos = self.cos_theta(&r.dir());
+                (1. / self.lens_area(), 1. / (self.film_area() * cos.powi(3)))
+            }
+            Projection::Orthographic => (1. / self.film_area(), 1. / self.film_area()),
+        }
+    }
+
+    /// Samples a point on the lens to connect a point of the scene to the camera.
+    /// An orthographic camera only sees the point from the spot of the film in front of it,
+    /// which is then sampled with a density of 1.
+    pub fn sample_wi(&self, point: &Point3, sampler: &mut dyn Sampler) -> Option<CameraSample> {
+        if self.projection == Projection::Orthographic {
+            let lens_point = *point - (*point - self.origin).dot(&self.w) * self.w;
+            let r = Ray::new(lens_point, *point - lens_point);
+            let (s, t) = self.film_coordinates(&r)?;
+            return Some(CameraSample {
+                lens_point,
+                importance: self.importance(&r),
+                pdf: 1.,
+                s,
+                t,
+            });
+        }
+        let rd: Vec3 = self.lens_radius * random_in_unit_disk(sampler);
+        let lens_point = self.origin + self.u * rd.x() + self.v * rd.y();
+        let to_point = *point - lens_point;
+        let distance_squared = to_point.norm_squared();
+        let r = Ray::new(lens_point, to_point);
+        let (s, t) = self.film_coordinates(&r)?;
+        let cos = self.cos_theta(&to_point);
+        Some(CameraSample {
+            lens_point,
+            importance: self.importance(&r),
+            pdf: distance_squared / (cos * self.lens_area()),
+            s,
+            t,
+        })
+    }
+}
+
+/// The orthonormal basis of a camera at `origin` looking at `lookat`: `u` points right,
+/// `v` up and `w` backwards
+fn basis(origin: Point3, lookat: Point3, vup: Vec3) -> (Vec3, Vec3, Vec3) {
+    let w = (origin - lookat).unit_vector();
+    let u = vup.cross(&w).unit_vector();
+    let v = w.cross(&u);
+    (u, v, w)
 }
 
 impl Default for Camera {
@@ -72,17 +276,16 @@ impl Default for Camera {
         let vup = Vec3::new(0., 1., 0.);
         let horizontal = Vec3::new(viewport_width as f64, 0., 0.);
         let vertical = Vec3::new(0., viewport_height as f64, 0.);
+        let focus_dist = focal_length;
         let lower_left_corner =
             origin - horizontal / 2. - vertical / 2. - Vec3::new(0., 0., focal_length);
 
-        // Compute the orthonormal basis for our camera rotation
-        let w = (origin - lookat).unit_vector();
-        let u = vup.cross(&w).unit_vector();
-        let v = w.cross(&u);
+        let (u, v, w) = basis(origin, lookat, vup);
         let aperture = 2.0;
         let lens_radius = aperture / 2.;
 
         Self {
+            projection: Projection::Perspective,
             origin,
             lower_left_corner,
             horizontal,
@@ -91,6 +294,71 @@ impl Default for Camera {
             u,
             v,
             lens_radius,
+            focus_dist,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Seeded;
+
+    #[test]
+    fn camera_film_coordinates_roundtrip() {
+        let cam = Camera::new(
+            1.5,
+            40.,
+            0.,
+            3.,
+            Point3::new(1., 2., 3.),
+            Point3::zero(),
+            Vec3::new(0., 1., 0.),
+        );
+        let r = cam.get_ray(0.25, 0.75, &mut Seeded::new(0));
+        let (s, t) = cam.film_coordinates(&r).unwrap();
+        assert!((s - 0.25).abs() < 1e-9 && (t - 0.75).abs() < 1e-9);
+
+        // Connecting a point seen by the ray back to the camera lands on the same spot
+        let sample = cam.sample_wi(&r.at(5.), &mut Seeded::new(0)).unwrap();
+        assert!((sample.s - 0.25).abs() < 1e-9 && (sample.t - 0.75).abs() < 1e-9);
+        assert!(sample.importance > 0. && sample.pdf > 0.);
+
+        // Rays outside the film carry no importance
+        let behind = Ray::new(r.orig(), -r.dir());
+        assert_eq!(cam.importance(&behind), 0.);
+    }
+
+    #[test]
+    fn orthographic_rays_are_parallel() {
+        let from = Point3::new(1., 2., 3.);
+        let cam = Camera::orthographic(2., 4., from, Point3::zero(), Vec3::new(0., 1., 0.));
+        let mut sampler = Seeded::new(0);
+        let forward = (Point3::zero() - from).unit_vector();
+        let center = cam.get_ray(0.5, 0.5, &mut sampler);
+        assert!((center.orig() - from).near_zero());
+        let corner = cam.get_ray(1., 1., &mut sampler);
+        assert!((corner.dir() - forward).near_zero());
+        // The view is 4 wide and 2 high
+        let diagonal = corner.orig() - cam.get_ray(0., 0., &mut sampler).orig();
+        assert!((diagonal.norm_squared() - 20.).abs() < 1e-9);
+        assert!(diagonal.dot(&forward).abs() < 1e-9);
+
+        // A point is seen from the spot of the film in front of it, whatever its distance
+        let r = cam.get_ray(0.25, 0.75, &mut sampler);
+        for distance in [1., 10.].iter() {
+            let sample = cam.sample_wi(&r.at(*distance), &mut sampler).unwrap();
+            assert!((sample.s - 0.25).abs() < 1e-9 && (sample.t - 0.75).abs() < 1e-9);
+            assert!((sample.importance - 1. / 8.).abs() < 1e-9 && sample.pdf == 1.);
         }
+        assert!(cam.sample_wi(&r.at(-1.), &mut sampler).is_none());
+
+        // Switching a perspective camera keeps its position and orientation
+        let perspective = Camera::new(2., 40., 0., 3., from, Point3::zero(), Vec3::new(0., 1., 0.));
+        let switched = perspective.to_orthographic(4.);
+        assert_eq!(switched.projection(), Projection::Orthographic);
+        let r = switched.get_ray(0.25, 0.75, &mut sampler);
+        let expected = cam.get_ray(0.25, 0.75, &mut sampler);
+        assert!((r.orig() - expected.orig()).near_zero() && (r.dir() - expected.dir()).near_zero());
     }
 }
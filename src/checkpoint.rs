@@ -0,0 +1,362 @@
+use crate::adaptive::PixelStats;
+use crate::aov::{AovBuffers, ALL_AOVS};
+use crate::film::Film;
+use crate::filter::{Filter, FilterKind, FILTER_NAMES};
+use crate::hittable::Hittable;
+use crate::sampler::hash;
+use crate::scene::{Background, Scene};
+use crate::settings::Settings;
+use crate::vec3::Color;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+/// First bytes of a checkpoint file, with the version of the format
+const MAGIC: &[u8; 8] = b"RTCKPT03";
+
+/// The state of an interrupted render: the accumulated film, the pixels that
+/// still take samples and the next round. The samplers are seeded from the seed, the
+/// pixel and the sample index, so the round is all there is to their state.
+#[derive(Debug, Clone)]
+pub struct Checkpoint {
+    /// Hash of the settings that change the image, see `settings_hash`
+    pub settings_hash: u64,
+    /// Fingerprint of the scene, see `scene_hash`
+    pub scene_hash: u64,
+    /// The round that the render continues from
+    pub round: u32,
+    pub active: Vec<bool>,
+    pub film: Film,
+}
+
+/// Hash of the settings that change the rendered image. Settings that only change
+/// how the render runs, like the tiles or the time budget, are left out.
+pub fn settings_hash(settings: &Settings) -> u64 {
+    let key = format!(
+        "{:?} {:?} {:?} {} {} {} {} {} {} {} {:?} {:?} {} {:?} {:?} {:?} {:?}",
+        settings.scene,
+        settings.integrator,
+        settings.direct_lighting,
+        settings.max_depth,
+        settings.rr_depth,
+        settings.photons,
+        settings.photon_radius,
+        settings.passes,
+        settings.samples,
+        settings.seed,
+        settings.sampler,
+        settings.adaptive,
+        settings.min_samples,
+        settings.crop,
+        settings.filter,
+        settings.filter_radius,
+        settings.aovs,
+    );
+    let words: Vec<u64> = key.bytes().map(u64::from).collect();
+    hash(&words)
+}
+
+/// Fingerprint of a scene: the description of the camera, the background and every
+/// object with its material. It changes when the scene is edited, even if its name doesn't.
+pub fn scene_hash(scene: &Scene) -> u64 {
+    let mut words = Vec::new();
+    push_part(&mut words, "camera", &scene.camera.fingerprint());
+    let background = match scene.background {
+        Background::Sky => vec![0.],
+        Background::Solid(color) => vec![1., color.x(), color.y(), color.z()],
+    };
+    push_part(&mut words, "background", &background);
+    scene.world.fingerprint(&mut words);
+    hash(&words)
+}
+
+/// Appends a part of a scene to the words of its fingerprint: its kind, then its parameters
+pub fn push_part(words: &mut Vec<u64>, kind: &str, parameters: &[f64]) {
+    let kind: Vec<u64> = kind.bytes().map(u64::from).collect();
+    words.push(hash(&kind));
+    words.push(parameters.len() as u64);
+    words.extend(parameters.iter().map(|x| x.to_bits()));
+}
+
+impl Checkpoint {
+    /// Writes the checkpoint to a file. The file is replaced at once, so an interruption
+    /// while writing keeps the previous checkpoint.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let temporary = format!("{}.tmp", path);
+        {
+            let mut out = BufWriter::new(File::create(&temporary)?);
+            self.write(&mut out)?;
+            out.flush()?;
+        }
+        fs::rename(&temporary, path)
+    }
+
+    /// Reads a checkpoint written by `save`
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write<T: Write>(&self, out: &mut T) -> io::Result<()> {
+        let film = &self.film;
+        out.write_all(MAGIC)?;
+        write_u64(out, self.settings_hash)?;
+        write_u64(out, self.scene_hash)?;
+        out.write_all(&self.round.to_le_bytes())?;
+        write_u64(out, film.width as u64)?;
+        write_u64(out, film.height as u64)?;
+        let filter = film.filtered.filter;
+        let kind = FILTER_NAMES
+            .iter()
+            .position(|name| *name == filter.kind.name());
+        out.write_all(&[kind.unwrap() as u8])?;
+        write_f64(out, filter.radius)?;
+        for pixel in 0..film.stats.len() {
+            write_stats(out, &film.stats[pixel])?;
+            write_color(out, film.filtered.weighted[pixel])?;
+            write_f64(out, film.filtered.weights[pixel])?;
+            write_color(out, film.light[pixel])?;
+            out.write_all(&[self.active[pixel] as u8])?;
+        }
+        let aovs = &film.aovs;
+        out.write_all(&[aovs.aovs.len() as u8])?;
+        for aov in &aovs.aovs {
+            out.write_all(&[*aov as u8])?;
+        }
+        for sum in aovs.sums.iter().flatten() {
+            write_color(out, *sum)?;
+        }
+        Ok(())
+    }
+
+    pub fn read<T: Read>(input: &mut T) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a checkpoint file"));
+        }
+        let settings_hash = read_u64(input)?;
+        let scene_hash = read_u64(input)?;
+        let round = read_u32(input)?;
+        let width = read_u64(input)? as usize;
+        let height = read_u64(input)? as usize;
+        let mut kind = [0];
+        input.read_exact(&mut kind)?;
+        let kind = match FILTER_NAMES.get(kind[0] as usize) {
+            Some(name) => FilterKind::parse(name).map_err(|err| invalid(&err))?,
+            None => return Err(invalid("unknown filter")),
+        };
+        let filter = Filter::new(kind, Some(read_f64(input)?));
+        let mut film = Film::new(width, height, filter);
+        let mut active = Vec::with_capacity(width * height);
+        for pixel in 0..width * height {
+            film.stats[pixel] = read_stats(input)?;
+            film.filtered.weighted[pixel] = read_color(input)?;
+            film.filtered.weights[pixel] = read_f64(input)?;
+            film.light[pixel] = read_color(input)?;
+            let mut flag = [0];
+            input.read_exact(&mut flag)?;
+            active.push(flag[0] != 0);
+        }
+        let mut count = [0];
+        input.read_exact(&mut count)?;
+        let mut aovs = Vec::new();
+        for _ in 0..count[0] {
+            let mut aov = [0];
+            input.read_exact(&mut aov)?;
+            match ALL_AOVS.get(aov[0] as usize) {
+                Some(aov) => aovs.push(*aov),
+                None => return Err(invalid("unknown AOV")),
+            }
+        }
+        film.aovs = AovBuffers::new(&aovs, width * height);
+        for sums in film.aovs.sums.iter_mut() {
+            for sum in sums.iter_mut() {
+                *sum = read_color(input)?;
+            }
+        }
+        Ok(Self {
+            settings_hash,
+            scene_hash,
+            round,
+            active,
+            film,
+        })
+    }
+
+    /// Checks that the checkpoint was written by a render of the same scene with the
+    /// same settings and image size
+    pub fn check(
+        &self,
+        settings: &Settings,
+        scene: &Scene,
+        width: usize,
+        height: usize,
+    ) -> Result<(), String> {
+        if self.settings_hash != settings_hash(settings) {
+            return Err(String::from("the settings changed since the checkpoint"));
+        }
+        if self.scene_hash != scene_hash(scene) {
+            return Err(String::from("the scene changed since the checkpoint"));
+        }
+        if (self.film.width, self.film.height) != (width, height) {
+            return Err(format!(
+                "the checkpoint is {}x{} but the image is {}x{}",
+                self.film.width, self.film.height, width, height
+            ));
+        }
+        Ok(())
+    }
+}
+
+pub fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Little endian encoding of the values, also used by the distributed renderer
+pub fn write_u64<T: Write>(out: &mut T, x: u64) -> io::Result<()> {
+    out.write_all(&x.to_le_bytes())
+}
+pub fn write_f64<T: Write>(out: &mut T, x: f64) -> io::Result<()> {
+    out.write_all(&x.to_le_bytes())
+}
+pub fn write_color<T: Write>(out: &mut T, color: Color) -> io::Result<()> {
+    write_f64(out, color.x())?;
+    write_f64(out, color.y())?;
+    write_f64(out, color.z())
+}
+pub fn read_u32<T: Read>(input: &mut T) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+pub fn read_u64<T: Read>(input: &mut T) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+pub fn read_f64<T: Read>(input: &mut T) -> io::Result<f64> {
+    Ok(f64::from_bits(read_u64(input)?))
+}
+pub fn read_color<T: Read>(input: &mut T) -> io::Result<Color> {
+    Ok(Color::new(
+        read_f64(input)?,
+        read_f64(input)?,
+        read_f64(input)?,
+    ))
+}
+
+pub fn write_stats<T: Write>(out: &mut T, stats: &PixelStats) -> io::Result<()> {
+    write_color(out, stats.sum)?;
+    write_f64(out, stats.luminance_sum)?;
+    write_f64(out, stats.luminance_squared_sum)?;
+    out.write_all(&stats.count.to_le_bytes())
+}
+pub fn read_stats<T: Read>(input: &mut T) -> io::Result<PixelStats> {
+    Ok(PixelStats {
+        sum: read_color(input)?,
+        luminance_sum: read_f64(input)?,
+        luminance_squared_sum: read_f64(input)?,
+        count: read_u32(input)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::aov::Aov;
+    use crate::material::{Dielectric, DiffuseLight, Material};
+    use crate::scene;
+    use crate::sphere::Sphere;
+    use crate::vec3::Point3;
+    use std::sync::Arc;
+
+    #[test]
+    fn checkpoint_round_trip() {
+        let mut film = Film::new(3, 2, Filter::new(FilterKind::Tent, Some(1.5)));
+        for (point, color) in [
+            ((1.2, 0.5), Color::new(0.25, 0.5, 1.)),
+            ((1.7, 0.1), Color::one()),
+        ]
+        .iter()
+        {
+            film.stats[1].add(*color);
+            film.filtered.add(*point, *color);
+        }
+        film.splat(4, Color::new(0.1, 0.2, 0.3));
+        film.aovs = AovBuffers::new(&[Aov::Normal, Aov::ObjectId], 6);
+        film.aovs.sums[1][5] = Color::one() * 3.;
+        let checkpoint = Checkpoint {
+            settings_hash: 7,
+            scene_hash: 11,
+            round: 2,
+            active: vec![true, false, true, true, false, true],
+            film,
+        };
+        let mut bytes = Vec::new();
+        checkpoint.write(&mut bytes).unwrap();
+        let read = Checkpoint::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.round, 2);
+        assert_eq!(read.active, checkpoint.active);
+        assert_eq!(read.film.filtered.filter, checkpoint.film.filtered.filter);
+        assert_eq!(read.film.image(), checkpoint.film.image());
+        assert_eq!(read.film.stats[1].error(), checkpoint.film.stats[1].error());
+        assert_eq!(read.film.aovs.aovs, checkpoint.film.aovs.aovs);
+        assert_eq!(read.film.aovs.sums, checkpoint.film.aovs.sums);
+        // Truncated or foreign files are refused
+        assert!(Checkpoint::read(&mut &bytes[..bytes.len() - 1]).is_err());
+        assert!(Checkpoint::read(&mut &b"P3\n3 2\n255\n"[..]).is_err());
+    }
+
+    #[test]
+    fn resuming_checks_the_scene_and_settings() {
+        let settings = Settings::default();
+        let simple = scene::simple_scene(1.);
+        let checkpoint = Checkpoint {
+            settings_hash: settings_hash(&settings),
+            scene_hash: scene_hash(&simple),
+            round: 0,
+            active: vec![true; 4],
+            film: Film::new(2, 2, Filter::new(FilterKind::Box, None)),
+        };
+        assert!(checkpoint.check(&settings, &simple, 2, 2).is_ok());
+        assert!(checkpoint
+            .check(&settings, &scene::simple_scene(1.), 2, 2)
+            .is_ok());
+        assert!(checkpoint
+            .check(&settings, &scene::lights_scene(1.), 2, 2)
+            .is_err());
+        assert!(checkpoint.check(&settings, &simple, 4, 1).is_err());
+        let more_samples = Settings {
+            samples: 200,
+            ..settings.clone()
+        };
+        assert!(checkpoint.check(&more_samples, &simple, 2, 2).is_err());
+        let other_tiles = Settings {
+            tile_size: 64,
+            ..settings
+        };
+        assert!(checkpoint.check(&other_tiles, &simple, 2, 2).is_ok());
+    }
+
+    #[test]
+    fn scene_hash_sees_objects_off_screen() {
+        let with_sphere = |material: Arc<dyn Material>| {
+            let mut scene = scene::simple_scene(1.);
+            // Tiny and behind the camera, no camera ray ever hits it
+            let center = Point3::new(0., 0., 10.);
+            scene
+                .world
+                .add(Box::new(Sphere::new(center, 0.01, material)));
+            scene_hash(&scene)
+        };
+        let light = |emit: f64| Arc::new(DiffuseLight::new(Color::one() * emit));
+        let base = scene_hash(&scene::simple_scene(1.));
+        assert_ne!(with_sphere(light(4.)), base);
+        assert_ne!(with_sphere(light(4.)), with_sphere(light(5.)));
+        assert_eq!(with_sphere(light(4.)), with_sphere(light(4.)));
+        let glass = |ir: f64| Arc::new(Dielectric::new(ir));
+        assert_ne!(with_sphere(glass(1.5)), with_sphere(glass(1.6)));
+        let mut sky = scene::simple_scene(1.);
+        sky.background = Background::Solid(Color::zero());
+        assert_ne!(scene_hash(&sky), base);
+    }
+}
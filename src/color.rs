@@ -1,22 +1,21 @@
-use crate::utils::clamp;
+use crate::tonemap::srgb_encode;
 use crate::vec3::Color;
 use std::io::Write;
-pub fn write_color<T: Write>(out: &mut T, pixel_color: Color, samples_per_pixel: u32) {
-    // Divide the color by the number of samples
-    let scale = 1.0 / samples_per_pixel as f64;
-    let mut r = pixel_color.x() * scale;
-    let mut g = pixel_color.y() * scale;
-    let mut b = pixel_color.z() * scale;
 
-    // Apply gamma = 2 <=> raise the color to the power of 1/gamma
-    r = r.sqrt();
-    g = g.sqrt();
-    b = b.sqrt();
-
-    let ix = 256. * clamp(r, 0.0, 0.999);
-    let iy = 256. * clamp(g, 0.0, 0.999);
-    let iz = 256. * clamp(b, 0.0, 0.999);
-    let outstream = format!("{} {} {}\n", ix, iy, iz);
+/// Relative luminance of a linear RGB color (Rec. 709 weights)
+pub fn luminance(c: &Color) -> f64 {
+    0.2126 * c.x() + 0.7152 * c.y() + 0.0722 * c.z()
+}
+/// Writes a linear color in [0, 1], encoded with the sRGB transfer function, as the
+/// 8 bit values of a PPM pixel
+pub fn write_color<T: Write>(out: &mut T, pixel_color: Color) {
+    let quantize = |v: f64| (255. * srgb_encode(v) + 0.5) as u8;
+    let outstream = format!(
+        "{} {} {}\n",
+        quantize(pixel_color.x()),
+        quantize(pixel_color.y()),
+        quantize(pixel_color.z())
+    );
     match out.write_all(outstream.as_bytes()) {
         Ok(_) => {}
         Err(_) => println!("Couldn't write"),
@@ -31,6 +30,9 @@ mod tests {
     #[test]
     fn test_write() {
         let p = Color::new(1. / 255., 2. / 255., 0.25);
-        write_color(&mut io::stdout(), p, 100);
+        write_color(&mut io::stdout(), p);
+        let mut out = Vec::new();
+        write_color(&mut out, Color::new(0., 0.22, 2.));
+        assert_eq!(String::from_utf8(out).unwrap(), "0 129 255\n");
     }
 }
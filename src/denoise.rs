@@ -0,0 +1,298 @@
+use crate::color::luminance;
+use crate::hittable::Hittable;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::settings::Settings;
+use crate::tiles::Tile;
+use crate::vec3::{Color, Vec3};
+use rayon::prelude::*;
+
+/// Features of the surfaces seen by the camera rays, averaged over the samples of each
+/// pixel. They tell the denoiser where the edges of the scene are, which the noise hides.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxBuffers {
+    /// Color of the surfaces, white where the rays miss the scene
+    pub albedo: Vec<Color>,
+    /// Normal of the surfaces, zero where the rays miss the scene
+    pub normal: Vec<Vec3>,
+    /// Distance to the surfaces over the rays that hit one, 0 where every ray missed
+    pub depth: Vec<f64>,
+}
+
+/// Most samples of a pixel traced for its features
+const AUX_SAMPLES: u32 = 16;
+/// Most mirror and glass surfaces followed to the surface whose features are taken
+const MAX_SPECULAR_BOUNCES: u32 = 8;
+
+/// Follows a camera ray through the mirrors and glass it hits, which have no features of
+/// their own, up to the surface they show. Returns its albedo, tinted by the specular
+/// surfaces, and its normal and distance along the path if the ray hit it.
+fn features(
+    scene: &Scene,
+    mut ray: Ray,
+    sampler: &mut dyn Sampler,
+) -> (Color, Option<(Vec3, f64)>) {
+    let (mut tint, mut distance) = (Color::one(), 0.);
+    for _ in 0..MAX_SPECULAR_BOUNCES {
+        let rec = match scene.world.hit(&ray, 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => return (tint, None),
+        };
+        distance += (rec.point - ray.orig()).norm();
+        if !rec.material.is_delta() {
+            return (
+                tint * rec.material.albedo(&rec),
+                Some((rec.normal, distance)),
+            );
+        }
+        match rec.material.scatter(&ray, &rec, sampler) {
+            Some((attenuation, scattered)) => {
+                tint *= attenuation;
+                ray = scattered;
+            }
+            None => return (tint, Some((rec.normal, distance))),
+        }
+    }
+    (tint, None)
+}
+
+/// Traces the camera rays of the first samples of the pixels of `region`, in an image of
+/// the given width, and returns the features of the surfaces they see, row by row from the
+/// bottom of the region. The rays are the ones of the render, drawn from the same samplers.
+pub fn aux_buffers(scene: &Scene, settings: &Settings, region: &Tile, width: usize) -> AuxBuffers {
+    let samples = settings.samples.max(1);
+    let features: Vec<_> = region
+        .j
+        .clone()
+        .flat_map(|j| region.i.clone().map(move |i| (i, j)))
+        .collect::<Vec<_>>()
+        .par_iter()
+        .map(|&(i, j)| {
+            let (mut albedo, mut normal, mut depth) = (Color::zero(), Vec3::zero(), 0.);
+            let mut hits = 0;
+            let traced = samples.min(AUX_SAMPLES);
+            for index in 0..traced {
+                let mut sampler = settings.sampler.pixel_sampler(
+                    settings.seed,
+                    (j * width + i) as u64,
+                    index,
+                    samples,
+                );
+                let (du, dv) = sampler.next_2d();
+                let ray =
+                    crate::camera_ray((i as f64 + du, j as f64 + dv), scene, sampler.as_mut());
+                let (color, hit) = features(scene, ray, sampler.as_mut());
+                albedo += color;
+                if let Some((n, distance)) = hit {
+                    normal += n;
+                    depth += distance;
+                    hits += 1;
+                }
+            }
+            let depth = if hits > 0 { depth / hits as f64 } else { 0. };
+            (albedo / traced as f64, normal / traced as f64, depth)
+        })
+        .collect();
+    AuxBuffers {
+        albedo: features.iter().map(|feature| feature.0).collect(),
+        normal: features.iter().map(|feature| feature.1).collect(),
+        depth: features.iter().map(|feature| feature.2).collect(),
+    }
+}
+
+/// Number of passes of the filter, whose taps are 2^pass pixels apart
+const PASSES: u32 = 5;
+/// The B3 spline, the kernel of the à-trous wavelet transform
+const KERNEL: [f64; 5] = [1. / 16., 1. / 4., 3. / 8., 1. / 4., 1. / 16.];
+/// How many standard deviations of the noise of a pixel a neighbour can differ by
+const COLOR_SIGMA: f64 = 4.;
+/// Exponent of the cosine between the normals
+const NORMAL_POWER: i32 = 64;
+/// Relative difference of depth allowed per pixel of distance
+const DEPTH_SIGMA: f64 = 0.02;
+const ALBEDO_SIGMA: f64 = 0.1;
+/// Smallest albedo that the lighting is divided by
+const MIN_ALBEDO: f64 = 0.01;
+
+/// Standard deviation of the luminance in the 3x3 pixels around each pixel, the noise
+/// that the colors of the neighbours are compared to
+fn local_deviation(image: &[Color], width: usize, height: usize) -> Vec<f64> {
+    (0..width * height)
+        .into_par_iter()
+        .map(|pixel| {
+            let (i, j) = (pixel % width, pixel / width);
+            let (mut sum, mut squared_sum, mut n) = (0., 0., 0.);
+            for y in j.saturating_sub(1)..(j + 2).min(height) {
+                for x in i.saturating_sub(1)..(i + 2).min(width) {
+                    let l = luminance(&image[y * width + x]);
+                    sum += l;
+                    squared_sum += l * l;
+                    n += 1.;
+                }
+            }
+            let mean = sum / n;
+            (squared_sum / n - mean * mean).max(0.).sqrt()
+        })
+        .collect()
+}
+
+/// A pass of the edge avoiding à-trous filter, with taps `step` pixels apart
+fn filter_pass(
+    lighting: &[Color],
+    aux: &AuxBuffers,
+    (width, height): (usize, usize),
+    step: usize,
+) -> Vec<Color> {
+    let deviation = local_deviation(lighting, width, height);
+    (0..width * height)
+        .into_par_iter()
+        .map(|p| {
+            let (i, j) = ((p % width) as isize, (p / width) as isize);
+            let l_p = luminance(&lighting[p]);
+            let color_scale = COLOR_SIGMA * deviation[p] + 1e-6;
+            let (mut sum, mut weights) = (Color::zero(), 0.);
+            for (dy, ky) in KERNEL.iter().enumerate() {
+                let y = j + (dy as isize - 2) * step as isize;
+                if y < 0 || y >= height as isize {
+                    continue;
+                }
+                for (dx, kx) in KERNEL.iter().enumerate() {
+                    let x = i + (dx as isize - 2) * step as isize;
+                    if x < 0 || x >= width as isize {
+                        continue;
+                    }
+                    let q = y as usize * width + x as usize;
+                    let distance = (((x - i).pow(2) + (y - j).pow(2)) as f64).sqrt();
+                    let color = (-(luminance(&lighting[q]) - l_p).abs() / color_scale).exp();
+                    let (n_p, n_q) = (aux.normal[p], aux.normal[q]);
+                    let normal = if n_p.near_zero() || n_q.near_zero() {
+                        1.
+                    } else {
+                        (n_p.dot(&n_q) / (n_p.norm() * n_q.norm()))
+                            .max(0.)
+                            .powi(NORMAL_POWER)
+                    };
+                    let (z_p, z_q) = (aux.depth[p], aux.depth[q]);
+                    let depth = if z_p == z_q {
+                        1.
+                    } else {
+                        let relative = (z_p - z_q).abs() / z_p.max(z_q);
+                        (-relative / (DEPTH_SIGMA * distance)).exp()
+                    };
+                    let albedo = (-(aux.albedo[p] - aux.albedo[q]).norm_squared()
+                        / (ALBEDO_SIGMA * ALBEDO_SIGMA))
+                        .exp();
+                    let weight = kx * ky * color * normal * depth * albedo;
+                    sum += lighting[q] * weight;
+                    weights += weight;
+                }
+            }
+            // The center always has a positive weight
+            sum / weights
+        })
+        .collect()
+}
+
+/// Denoises an image, row by row from the bottom, with the edge avoiding à-trous wavelet
+/// filter of Dammertz et al. The colors are divided by the albedo so that only the
+/// lighting is blurred, and the features of the pixels keep the filter from blurring
+/// across the edges of the objects.
+pub fn denoise(image: &[Color], aux: &AuxBuffers, width: usize, height: usize) -> Vec<Color> {
+    let albedo = |a: Color| {
+        Color::new(
+            a.x().max(MIN_ALBEDO),
+            a.y().max(MIN_ALBEDO),
+            a.z().max(MIN_ALBEDO),
+        )
+    };
+    let mut lighting: Vec<Color> = image
+        .iter()
+        .zip(aux.albedo.iter())
+        .map(|(color, a)| {
+            let a = albedo(*a);
+            Color::new(color.x() / a.x(), color.y() / a.y(), color.z() / a.z())
+        })
+        .collect();
+    for pass in 0..PASSES {
+        lighting = filter_pass(&lighting, aux, (width, height), 1 << pass);
+    }
+    lighting
+        .iter()
+        .zip(aux.albedo.iter())
+        .map(|(light, a)| *light * albedo(*a))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Seeded;
+    use crate::scene;
+
+    #[test]
+    fn first_hit_features() {
+        let scene = scene::simple_scene(crate::ASPECT_RATIO as f64);
+        let settings = Settings {
+            samples: 4,
+            ..Settings::default()
+        };
+        let (width, height) = (crate::IMAGE_WIDTH as usize, crate::IMAGE_HEIGHT as usize);
+        // A column through the middle of the image, from the ground to the sky
+        let region = Tile {
+            i: width / 2..width / 2 + 1,
+            j: 0..height,
+        };
+        let aux = aux_buffers(&scene, &settings, &region, width);
+        assert_eq!(aux.depth.len(), height);
+        let (ground, sky) = (0, height - 1);
+        assert!(aux.depth[ground] > 0. && aux.normal[ground].y() > 0.9);
+        assert_eq!(aux.albedo[ground], Color::new(0.8, 0.8, 0.));
+        assert_eq!(aux.depth[sky], 0.);
+        assert_eq!(aux.normal[sky], Vec3::zero());
+        assert_eq!(aux.albedo[sky], Color::one());
+        // Same rays, same features
+        assert_eq!(aux_buffers(&scene, &settings, &region, width), aux);
+    }
+
+    #[test]
+    fn denoising_keeps_the_edges() {
+        // Two walls of different colors and orientations, lit by a noisy light
+        let (width, height) = (32, 16);
+        let mut sampler = Seeded::new(3);
+        let left = |pixel: usize| pixel % width < width / 2;
+        let mut aux = AuxBuffers {
+            albedo: Vec::new(),
+            normal: Vec::new(),
+            depth: vec![5.; width * height],
+        };
+        let mut image = Vec::new();
+        for pixel in 0..width * height {
+            let (albedo, normal) = if left(pixel) {
+                (Color::new(0.8, 0.2, 0.2), Vec3::new(1., 0., 0.))
+            } else {
+                (Color::new(0.2, 0.2, 0.8), Vec3::new(0., 0., 1.))
+            };
+            aux.albedo.push(albedo);
+            aux.normal.push(normal);
+            image.push(albedo * (0.5 + sampler.next_1d()));
+        }
+        let denoised = denoise(&image, &aux, width, height);
+        // The noise of each wall is smoothed out, without bleeding into the other wall
+        let error = |image: &[Color]| {
+            image
+                .iter()
+                .zip(aux.albedo.iter())
+                .map(|(color, albedo)| (*color - *albedo).norm_squared())
+                .sum::<f64>()
+        };
+        assert!(error(&denoised) < 0.05 * error(&image));
+        for (pixel, color) in denoised.iter().enumerate() {
+            if left(pixel) {
+                assert!(color.z() < 0.3 && color.x() > 0.6, "{:?}", color);
+            } else {
+                assert!(color.x() < 0.3 && color.z() > 0.6, "{:?}", color);
+            }
+        }
+    }
+}
@@ -0,0 +1,598 @@
+use crate::adaptive::PixelStats;
+use crate::checkpoint::{
+    invalid, read_color, read_f64, read_stats, read_u64, scene_hash, write_color, write_f64,
+    write_stats, write_u64,
+};
+use crate::film::{Film, FilteredImage};
+use crate::filter::Filter;
+use crate::integrator::{self, Integrator};
+use crate::scene::{self, Scene};
+use crate::settings::Settings;
+use crate::tiles::{self, Tile, TileOrder};
+use crate::vec3::Color;
+use indicatif::ProgressBar;
+use std::collections::VecDeque;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::ops::Range;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Width and height of the tiles handed out to the workers. Workers split them into
+/// tiles of `tile_size` that they render in parallel.
+const ASSIGNED_TILE_SIZE: usize = 128;
+
+/// Messages of a worker rendering a tile: heartbeats while it renders, then the result
+const HEARTBEAT: u8 = 0;
+const RESULT: u8 = 1;
+
+/// The samples of a tile rendered by a worker: the statistics of its pixels, its
+/// filtered samples, which reach the pixels around it, and the light splatted onto the
+/// image
+#[derive(Debug, Clone)]
+struct TileResult {
+    pixels: Vec<(usize, PixelStats)>,
+    filtered: FilteredImage,
+    light: Vec<(usize, Color)>,
+}
+
+/// Tiles waiting for a worker, shared by the threads talking to the workers
+struct Queue {
+    tiles: VecDeque<usize>,
+    /// Set when every tile is rendered
+    done: bool,
+}
+
+type SharedQueue = (Mutex<Queue>, Condvar);
+
+fn write_strings<T: Write>(out: &mut T, strings: &[String]) -> io::Result<()> {
+    write_u64(out, strings.len() as u64)?;
+    for string in strings {
+        write_u64(out, string.len() as u64)?;
+        out.write_all(string.as_bytes())?;
+    }
+    Ok(())
+}
+fn read_strings<T: Read>(input: &mut T) -> io::Result<Vec<String>> {
+    let count = read_u64(input)?;
+    let mut strings = Vec::new();
+    for _ in 0..count {
+        let mut bytes = Vec::new();
+        let length = read_u64(input)?;
+        input.take(length).read_to_end(&mut bytes)?;
+        if bytes.len() as u64 != length {
+            return Err(invalid("truncated string"));
+        }
+        strings.push(String::from_utf8(bytes).map_err(|_| invalid("invalid string"))?);
+    }
+    Ok(strings)
+}
+fn write_region<T: Write>(out: &mut T, tile: &Tile) -> io::Result<()> {
+    for x in [tile.i.start, tile.i.end, tile.j.start, tile.j.end].iter() {
+        write_u64(out, *x as u64)?;
+    }
+    Ok(())
+}
+/// Reads a rectangle of pixels, checking that it is inside of `image`
+fn read_region<T: Read>(input: &mut T, image: &Tile) -> io::Result<Tile> {
+    let mut x = [0; 4];
+    for x in x.iter_mut() {
+        *x = read_u64(input)? as usize;
+    }
+    let inside = |start: usize, end: usize, range: &Range<usize>| {
+        range.start <= start && start <= end && end <= range.end
+    };
+    if !inside(x[0], x[1], &image.i) || !inside(x[2], x[3], &image.j) {
+        return Err(invalid("region outside of the image"));
+    }
+    Ok(Tile {
+        i: x[0]..x[1],
+        j: x[2]..x[3],
+    })
+}
+/// Writes the next tile to render, or None when there are no tiles left
+fn write_tile<T: Write>(out: &mut T, tile: Option<&Tile>) -> io::Result<()> {
+    match tile {
+        Some(tile) => {
+            out.write_all(&[1])?;
+            write_region(out, tile)
+        }
+        None => out.write_all(&[0]),
+    }
+}
+fn read_tile<T: Read>(input: &mut T, image: &Tile) -> io::Result<Option<Tile>> {
+    let mut tag = [0];
+    input.read_exact(&mut tag)?;
+    if tag[0] == 0 {
+        return Ok(None);
+    }
+    read_region(input, image).map(Some)
+}
+fn write_result<T: Write>(out: &mut T, result: &TileResult) -> io::Result<()> {
+    write_u64(out, result.pixels.len() as u64)?;
+    for (pixel, stats) in &result.pixels {
+        write_u64(out, *pixel as u64)?;
+        write_stats(out, stats)?;
+    }
+    let filtered = &result.filtered;
+    write_region(out, &filtered.region)?;
+    for (weighted, weight) in filtered.weighted.iter().zip(filtered.weights.iter()) {
+        write_color(out, *weighted)?;
+        write_f64(out, *weight)?;
+    }
+    write_u64(out, result.light.len() as u64)?;
+    for (pixel, color) in &result.light {
+        write_u64(out, *pixel as u64)?;
+        write_color(out, *color)?;
+    }
+    Ok(())
+}
+/// Reads the result of a tile, checking that its pixels are in the image
+fn read_result<T: Read>(input: &mut T, image: &Tile, filter: Filter) -> io::Result<TileResult> {
+    let pixel_count = image.i.len() * image.j.len();
+    let read_pixel = |input: &mut T| match read_u64(input)? as usize {
+        pixel if pixel < pixel_count => Ok(pixel),
+        _ => Err(invalid("pixel outside of the image")),
+    };
+    let mut pixels = Vec::new();
+    for _ in 0..read_u64(input)? {
+        pixels.push((read_pixel(input)?, read_stats(input)?));
+    }
+    let mut filtered = FilteredImage::new(filter, read_region(input, image)?);
+    for index in 0..filtered.weights.len() {
+        filtered.weighted[index] = read_color(input)?;
+        filtered.weights[index] = read_f64(input)?;
+    }
+    let mut light = Vec::new();
+    for _ in 0..read_u64(input)? {
+        light.push((read_pixel(input)?, read_color(input)?));
+    }
+    Ok(TileResult {
+        pixels,
+        filtered,
+        light,
+    })
+}
+/// The whole image, which the tiles and the results must be in
+fn image() -> Tile {
+    Tile {
+        i: 0..crate::IMAGE_WIDTH as usize,
+        j: 0..crate::IMAGE_HEIGHT as usize,
+    }
+}
+
+/// Takes all the samples of the pixels of a tile, split into smaller tiles rendered in
+/// parallel. The samples are split between the passes of the integrator like in
+/// `render_pixels`, so the image doesn't depend on which worker rendered which tile.
+/// Multi-pass integrators prepare each pass again for every tile.
+fn render_tile(
+    scene: &Scene,
+    integrator: &mut dyn Integrator,
+    settings: &Settings,
+    tile: &Tile,
+    prepared_pass: &mut Option<u32>,
+) -> TileResult {
+    let (width, height) = (crate::IMAGE_WIDTH as usize, crate::IMAGE_HEIGHT as usize);
+    let passes = integrator.passes().max(1);
+    let target = settings.samples.max(1);
+    let pass_samples = if passes > 1 {
+        (target / passes).max(1)
+    } else {
+        target
+    };
+    let samples_per_pixel = pass_samples * passes;
+    let tiles = tiles::tiles_in(tile, settings.tile_size, TileOrder::Scanline);
+    let filter = Filter::new(settings.filter, settings.filter_radius);
+    let mut film = Film::new(width, height, filter);
+    let active = vec![true; width * height];
+    for pass in 0..passes {
+        if *prepared_pass != Some(pass) {
+            integrator.preprocess(scene, pass);
+            *prepared_pass = Some(pass);
+        }
+        let first = pass * pass_samples;
+        crate::sample_pixels(
+            scene,
+            integrator,
+            settings,
+            &tiles,
+            &active,
+            first..first + pass_samples,
+            samples_per_pixel,
+            &mut film,
+        );
+    }
+    let mut filtered = film.tile_image(tile);
+    filtered.merge(&film.filtered);
+    TileResult {
+        pixels: tile
+            .pixels(width)
+            .map(|pixel| (pixel, film.stats[pixel]))
+            .collect(),
+        filtered,
+        light: film
+            .light
+            .iter()
+            .enumerate()
+            .filter(|(_, color)| **color != Color::zero())
+            .map(|(pixel, color)| (pixel, *color))
+            .collect(),
+    }
+}
+
+/// Adds the samples of a tile to the film
+fn merge(film: &mut Film, result: TileResult) {
+    for (pixel, stats) in result.pixels {
+        film.stats[pixel] = stats;
+    }
+    film.filtered.merge(&result.filtered);
+    for (pixel, color) in result.light {
+        film.splat(pixel, color);
+    }
+}
+
+/// Sends heartbeats to the coordinator every `interval` on its own thread, until the
+/// returned sender is dropped. The thread must be joined before writing anything else.
+fn send_heartbeats(
+    mut stream: TcpStream,
+    interval: Duration,
+) -> (Sender<()>, thread::JoinHandle<()>) {
+    let (stop, stopped) = mpsc::channel();
+    let heartbeats = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            if stream.write_all(&[HEARTBEAT]).is_err() {
+                break;
+            }
+        }
+    });
+    (stop, heartbeats)
+}
+
+/// Renders tiles for the coordinator at `address` until it has no tile left.
+/// The scene and the settings come from the coordinator, with the interval of the
+/// heartbeats that tell it that the worker is still rendering.
+pub fn work(address: &str) -> io::Result<()> {
+    let stream = TcpStream::connect(address)?;
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    let settings =
+        Settings::from_args(read_strings(&mut input)?.into_iter()).map_err(|err| invalid(&err))?;
+    let interval = Duration::from_secs_f64(read_f64(&mut input)?.max(0.001));
+    let scene = scene::from_settings(&settings, crate::ASPECT_RATIO as f64)
+        .ok_or_else(|| invalid(&format!("unknown scene `{}`", settings.scene)))?;
+    let mut integrator = integrator::by_name(&settings.integrator, &settings)
+        .ok_or_else(|| invalid(&format!("unknown integrator `{}`", settings.integrator)))?;
+    // The coordinator checks that the worker built the same scene
+    write_u64(&mut out, scene_hash(&scene))?;
+    out.flush()?;
+    let mut prepared_pass = None;
+    while let Some(tile) = read_tile(&mut input, &image())? {
+        let (stop, heartbeats) = send_heartbeats(out.get_ref().try_clone()?, interval);
+        let result = render_tile(
+            &scene,
+            integrator.as_mut(),
+            &settings,
+            &tile,
+            &mut prepared_pass,
+        );
+        drop(stop);
+        heartbeats.join().unwrap();
+        out.write_all(&[RESULT])?;
+        write_result(&mut out, &result)?;
+        out.flush()?;
+    }
+    Ok(())
+}
+
+/// Reads the messages of a worker rendering a tile until its result
+fn read_answer<T: Read>(input: &mut T, filter: Filter) -> io::Result<TileResult> {
+    loop {
+        let mut tag = [0];
+        input.read_exact(&mut tag)?;
+        match tag[0] {
+            HEARTBEAT => {}
+            RESULT => return read_result(input, &image(), filter),
+            _ => return Err(invalid("unknown message")),
+        }
+    }
+}
+
+/// Takes the next tile from the queue, waiting for one if the tiles left are being
+/// rendered by other workers. Returns None once every tile is rendered.
+fn next_tile(queue: &SharedQueue) -> Option<usize> {
+    let (lock, condvar) = queue;
+    let mut queue = lock.lock().unwrap();
+    loop {
+        if queue.done {
+            return None;
+        }
+        if let Some(index) = queue.tiles.pop_front() {
+            return Some(index);
+        }
+        queue = condvar.wait(queue).unwrap();
+    }
+}
+
+/// Hands out tiles to a worker and forwards its results, until there are no tiles left.
+/// When the worker fails, or sends nothing, not even a heartbeat, within the read timeout
+/// of the stream, the tile it was rendering goes back to the queue.
+#[allow(clippy::too_many_arguments)]
+fn serve(
+    stream: TcpStream,
+    tiles: &[Tile],
+    queue: &SharedQueue,
+    args: &[String],
+    heartbeat: Duration,
+    scene_hash: u64,
+    filter: Filter,
+    results: &Sender<(usize, TileResult)>,
+) -> io::Result<()> {
+    let mut input = BufReader::new(stream.try_clone()?);
+    let mut out = BufWriter::new(stream);
+    write_strings(&mut out, args)?;
+    write_f64(&mut out, heartbeat.as_secs_f64())?;
+    out.flush()?;
+    if read_u64(&mut input)? != scene_hash {
+        return Err(invalid("the worker built a different scene"));
+    }
+    while let Some(index) = next_tile(queue) {
+        let result = write_tile(&mut out, Some(&tiles[index]))
+            .and_then(|_| out.flush())
+            .and_then(|_| read_answer(&mut input, filter));
+        match result {
+            Ok(result) => {
+                // The coordinator only stops listening once it has every tile
+                let _ = results.send((index, result));
+            }
+            Err(err) => {
+                let (lock, condvar) = queue;
+                lock.lock().unwrap().tiles.push_back(index);
+                condvar.notify_one();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "nothing heard within the worker timeout",
+                    )),
+                    _ => Err(err),
+                };
+            }
+        }
+    }
+    write_tile(&mut out, None)?;
+    out.flush()
+}
+
+/// Renders the region of the image on the workers that connect to `listener`. Workers
+/// can join at any time, and the tiles of the workers that fail are given to the others.
+/// The results are merged in the order of the tiles, whatever the order they arrive in,
+/// so that the samples that overlapping tiles share are always summed in the same order.
+/// Tiles are rendered with `li`, so integrators that render the whole image at once
+/// can't be used.
+pub fn coordinate(
+    listener: TcpListener,
+    scene: &Scene,
+    settings: &Settings,
+    region: &Tile,
+) -> io::Result<Film> {
+    let (width, height) = (crate::IMAGE_WIDTH as usize, crate::IMAGE_HEIGHT as usize);
+    let tiles = Arc::new(tiles::tiles_in(
+        region,
+        ASSIGNED_TILE_SIZE,
+        settings.tile_order,
+    ));
+    let queue: Arc<SharedQueue> = Arc::new((
+        Mutex::new(Queue {
+            tiles: (0..tiles.len()).collect(),
+            done: false,
+        }),
+        Condvar::new(),
+    ));
+    let args = Arc::new(settings.to_args());
+    let scene_hash = scene_hash(scene);
+    let (sender, receiver) = mpsc::channel();
+    // Accepting workers is polled between the results
+    listener.set_nonblocking(true)?;
+    let filter = Filter::new(settings.filter, settings.filter_radius);
+    let timeout = Duration::from_secs_f64(settings.worker_timeout.max(0.001));
+    // Several heartbeats per timeout, so that a late one doesn't drop a worker
+    let heartbeat = timeout / 4;
+    let mut film = Film::new(width, height, filter);
+    // Results that arrived before the ones of the tiles before them
+    let mut pending: Vec<Option<TileResult>> = vec![None; tiles.len()];
+    let mut next_merged = 0;
+    let mut remaining = tiles.len();
+    let pb = ProgressBar::new(remaining as u64);
+    while remaining > 0 {
+        match listener.accept() {
+            Ok((stream, address)) => {
+                stream.set_nonblocking(false)?;
+                // A worker that is cut off without closing the connection stops sending
+                // heartbeats
+                stream.set_read_timeout(Some(timeout))?;
+                let (tiles, queue, args, sender) = (
+                    Arc::clone(&tiles),
+                    Arc::clone(&queue),
+                    Arc::clone(&args),
+                    sender.clone(),
+                );
+                thread::spawn(move || {
+                    let served = serve(
+                        stream, &tiles, &queue, &args, heartbeat, scene_hash, filter, &sender,
+                    );
+                    if let Err(err) = served {
+                        eprintln!("Worker {} failed: {}", address, err);
+                    }
+                });
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err),
+        }
+        if let Ok((index, result)) = receiver.recv_timeout(Duration::from_millis(10)) {
+            if index >= next_merged && pending[index].is_none() {
+                pending[index] = Some(result);
+                remaining -= 1;
+                pb.inc(1);
+            }
+            while let Some(result) = pending.get_mut(next_merged).and_then(Option::take) {
+                merge(&mut film, result);
+                next_merged += 1;
+            }
+        }
+    }
+    pb.finish();
+    let (lock, condvar) = &*queue;
+    lock.lock().unwrap().done = true;
+    condvar.notify_all();
+    Ok(film)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterKind;
+
+    /// Renders the image with the settings on two workers on localhost, after two other
+    /// workers took a tile and never sent its result
+    fn render_on_workers(settings: &Settings) -> Film {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let coordinator = {
+            let settings = settings.clone();
+            thread::spawn(move || {
+                let scene = scene::simple_scene(crate::ASPECT_RATIO as f64);
+                coordinate(listener, &scene, &settings, &image()).unwrap()
+            })
+        };
+
+        // Workers that take a tile and never send its result: one fails, the other one
+        // stays connected without answering, as if it was cut off from the network
+        let scene = scene::simple_scene(crate::ASPECT_RATIO as f64);
+        let take_tile = || {
+            let stream = TcpStream::connect(&address).unwrap();
+            let mut input = BufReader::new(stream.try_clone().unwrap());
+            let mut out = BufWriter::new(stream);
+            let args = read_strings(&mut input).unwrap();
+            assert_eq!(args, settings.to_args());
+            read_f64(&mut input).unwrap();
+            write_u64(&mut out, scene_hash(&scene)).unwrap();
+            out.flush().unwrap();
+            assert!(read_tile(&mut input, &image()).unwrap().is_some());
+            (input, out)
+        };
+        drop(take_tile());
+        let _silent = take_tile();
+
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let address = address.clone();
+                thread::spawn(move || work(&address).unwrap())
+            })
+            .collect();
+        let film = coordinator.join().unwrap();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(film.counts(), vec![2; film.stats.len()]);
+        film
+    }
+
+    #[test]
+    fn render_on_workers_on_localhost() {
+        let settings = Settings {
+            scene: String::from("simple"),
+            integrator: String::from("ao"),
+            samples: 2,
+            worker_timeout: 2.,
+            ..Settings::default()
+        };
+        let film = render_on_workers(&settings);
+
+        // Same image as rendering all the tiles here
+        let scene = scene::simple_scene(crate::ASPECT_RATIO as f64);
+        let ao = integrator::by_name("ao", &settings).unwrap();
+        let filter = Filter::new(settings.filter, settings.filter_radius);
+        let mut expected = Film::new(film.width, film.height, filter);
+        let all = tiles::tiles(expected.width, expected.height, 16, TileOrder::Scanline);
+        let active = vec![true; expected.stats.len()];
+        crate::sample_pixels(
+            &scene,
+            ao.as_ref(),
+            &settings,
+            &all,
+            &active,
+            0..2,
+            2,
+            &mut expected,
+        );
+        assert_eq!(film.image(), expected.image());
+
+        // A filter wider than a pixel sums the samples of up to 4 tiles in the corners.
+        // They are merged in the order of the tiles, whichever worker is the fastest.
+        let gaussian = Settings {
+            filter: FilterKind::Gaussian,
+            filter_radius: Some(2.),
+            ..settings
+        };
+        let film = render_on_workers(&gaussian);
+        let mut ao = integrator::by_name("ao", &gaussian).unwrap();
+        let mut expected = Film::new(film.width, film.height, film.filtered.filter);
+        let tiles = tiles::tiles_in(&image(), ASSIGNED_TILE_SIZE, gaussian.tile_order);
+        for tile in &tiles {
+            let result = render_tile(&scene, ao.as_mut(), &gaussian, tile, &mut None);
+            merge(&mut expected, result);
+        }
+        assert_eq!(film.image(), expected.image());
+    }
+
+    #[test]
+    fn slow_workers_send_heartbeats() {
+        // The only worker takes much longer than the timeout to send its tile, but it
+        // keeps sending heartbeats, so the coordinator waits for it
+        let settings = Settings {
+            scene: String::from("simple"),
+            integrator: String::from("ao"),
+            samples: 1,
+            worker_timeout: 0.2,
+            ..Settings::default()
+        };
+        let region = Tile { i: 0..16, j: 0..16 };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (done, finished) = mpsc::channel();
+        {
+            let (settings, region) = (settings.clone(), region.clone());
+            thread::spawn(move || {
+                let scene = scene::simple_scene(crate::ASPECT_RATIO as f64);
+                let film = coordinate(listener, &scene, &settings, &region).unwrap();
+                done.send(film).unwrap();
+            });
+        }
+
+        let scene = scene::simple_scene(crate::ASPECT_RATIO as f64);
+        let stream = TcpStream::connect(&address).unwrap();
+        let mut input = BufReader::new(stream.try_clone().unwrap());
+        let mut out = BufWriter::new(stream);
+        read_strings(&mut input).unwrap();
+        let interval = Duration::from_secs_f64(read_f64(&mut input).unwrap());
+        assert!(interval < Duration::from_secs_f64(settings.worker_timeout));
+        write_u64(&mut out, scene_hash(&scene)).unwrap();
+        out.flush().unwrap();
+        let tile = read_tile(&mut input, &image()).unwrap().unwrap();
+        let (stop, heartbeats) = send_heartbeats(out.get_ref().try_clone().unwrap(), interval);
+        thread::sleep(Duration::from_secs(1));
+        drop(stop);
+        heartbeats.join().unwrap();
+        let mut ao = integrator::by_name("ao", &settings).unwrap();
+        let result = render_tile(&scene, ao.as_mut(), &settings, &tile, &mut None);
+        out.write_all(&[RESULT]).unwrap();
+        write_result(&mut out, &result).unwrap();
+        out.flush().unwrap();
+        assert!(read_tile(&mut input, &image()).unwrap().is_none());
+
+        let film = finished.recv_timeout(Duration::from_secs(10)).unwrap();
+        let counts = region.extract(&film.counts(), film.width);
+        assert_eq!(counts, vec![1; 16 * 16]);
+    }
+}
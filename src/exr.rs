@@ -0,0 +1,185 @@
+use crate::vec3::Color;
+use std::io::{self, Write};
+
+/// A layer of an EXR image: its channels take the components of the colors in order,
+/// and are named `layer.channel`, or just `channel` for the layer without a name
+pub struct Layer<'a> {
+    pub name: &'a str,
+    pub channels: &'a [&'a str],
+    /// The pixels, row by row from the bottom
+    pub pixels: &'a [Color],
+}
+
+/// Type of the channels in the header: 32 bit floats
+const FLOAT: i32 = 2;
+
+fn attribute<T: Write>(out: &mut T, name: &str, kind: &str, value: &[u8]) -> io::Result<()> {
+    out.write_all(name.as_bytes())?;
+    out.write_all(&[0])?;
+    out.write_all(kind.as_bytes())?;
+    out.write_all(&[0])?;
+    out.write_all(&(value.len() as i32).to_le_bytes())?;
+    out.write_all(value)
+}
+
+/// Writes the layers as a multi-layer OpenEXR image: a single part, uncompressed, of
+/// 32 bit float channels, one scanline per block
+pub fn write_exr<T: Write>(
+    out: &mut T,
+    layers: &[Layer],
+    width: usize,
+    height: usize,
+) -> io::Result<()> {
+    // The channels are sorted by name, in the header and in the scanlines
+    let mut channels: Vec<(String, &[Color], usize)> = layers
+        .iter()
+        .flat_map(|layer| {
+            layer
+                .channels
+                .iter()
+                .enumerate()
+                .map(move |(component, channel)| {
+                    let name = if layer.name.is_empty() {
+                        channel.to_string()
+                    } else {
+                        format!("{}.{}", layer.name, channel)
+                    };
+                    (name, layer.pixels, component)
+                })
+        })
+        .collect();
+    channels.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut header = vec![0x76, 0x2f, 0x31, 0x01];
+    header.extend_from_slice(&2u32.to_le_bytes());
+    let mut list = Vec::new();
+    for (name, _, _) in &channels {
+        list.extend_from_slice(name.as_bytes());
+        list.push(0);
+        list.extend_from_slice(&FLOAT.to_le_bytes());
+        // Not perceptually linear, 3 reserved bytes, then the x and y sampling
+        list.extend_from_slice(&[0, 0, 0, 0]);
+        list.extend_from_slice(&1i32.to_le_bytes());
+        list.extend_from_slice(&1i32.to_le_bytes());
+    }
+    list.push(0);
+    attribute(&mut header, "channels", "chlist", &list)?;
+    attribute(&mut header, "compression", "compression", &[0])?;
+    let mut window = Vec::new();
+    for x in [0, 0, width as i32 - 1, height as i32 - 1].iter() {
+        window.extend_from_slice(&x.to_le_bytes());
+    }
+    attribute(&mut header, "dataWindow", "box2i", &window)?;
+    attribute(&mut header, "displayWindow", "box2i", &window)?;
+    // Increasing y, from the top row
+    attribute(&mut header, "lineOrder", "lineOrder", &[0])?;
+    attribute(
+        &mut header,
+        "pixelAspectRatio",
+        "float",
+        &1f32.to_le_bytes(),
+    )?;
+    attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8])?;
+    attribute(
+        &mut header,
+        "screenWindowWidth",
+        "float",
+        &1f32.to_le_bytes(),
+    )?;
+    header.push(0);
+    out.write_all(&header)?;
+
+    // The offsets of the scanlines from the start of the file
+    let line_size = 8 + (channels.len() * width * 4) as u64;
+    for y in 0..height as u64 {
+        let offset = (header.len() + 8 * height) as u64 + y * line_size;
+        out.write_all(&offset.to_le_bytes())?;
+    }
+    for y in 0..height {
+        let row = height - 1 - y;
+        out.write_all(&(y as i32).to_le_bytes())?;
+        out.write_all(&((channels.len() * width * 4) as i32).to_le_bytes())?;
+        for (_, pixels, component) in &channels {
+            for color in &pixels[row * width..(row + 1) * width] {
+                out.write_all(&(color[*component] as f32).to_le_bytes())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_string(bytes: &[u8], at: &mut usize) -> String {
+        let end = *at + bytes[*at..].iter().position(|b| *b == 0).unwrap();
+        let s = String::from_utf8(bytes[*at..end].to_vec()).unwrap();
+        *at = end + 1;
+        s
+    }
+    fn read_i32(bytes: &[u8], at: usize) -> i32 {
+        i32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    #[test]
+    fn multi_layer_exr() {
+        let (width, height) = (3, 2);
+        let image: Vec<Color> = (0..6).map(|k| Color::one() * k as f64).collect();
+        let depth: Vec<Color> = (0..6).map(|k| Color::one() * (10 + k) as f64).collect();
+        let mut bytes = Vec::new();
+        write_exr(
+            &mut bytes,
+            &[
+                Layer {
+                    name: "depth",
+                    channels: &["Z"],
+                    pixels: &depth,
+                },
+                Layer {
+                    name: "",
+                    channels: &["R", "G", "B"],
+                    pixels: &image,
+                },
+            ],
+            width,
+            height,
+        )
+        .unwrap();
+        assert_eq!(&bytes[..8], &[0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+        // The attributes of the header, up to the empty name that ends it
+        let mut at = 8;
+        let mut channels = Vec::new();
+        loop {
+            let name = read_string(&bytes, &mut at);
+            if name.is_empty() {
+                break;
+            }
+            read_string(&bytes, &mut at);
+            let size = read_i32(&bytes, at) as usize;
+            at += 4;
+            if name == "channels" {
+                let mut list = at;
+                while bytes[list] != 0 {
+                    channels.push(read_string(&bytes, &mut list));
+                    assert_eq!(read_i32(&bytes, list), FLOAT);
+                    list += 16;
+                }
+            }
+            at += size;
+        }
+        assert_eq!(channels, vec!["B", "G", "R", "depth.Z"]);
+        // The offset table, then the scanlines of the 4 channels, from the top row
+        let line_size = 8 + 4 * width * 4;
+        let first = at + 8 * height;
+        assert_eq!(read_i32(&bytes, at), first as i32);
+        assert_eq!(bytes.len(), first + height * line_size);
+        assert_eq!(read_i32(&bytes, first + line_size), 1);
+        let value = |at: usize| {
+            f32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        // The first pixel of the top row, in the B and depth.Z channels
+        assert_eq!(value(first + 8), 3.);
+        assert_eq!(value(first + 8 + 3 * width * 4), 13.);
+    }
+}
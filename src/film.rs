@@ -0,0 +1,271 @@
+use crate::adaptive::PixelStats;
+use crate::aov::AovBuffers;
+use crate::color::write_color;
+use crate::filter::Filter;
+use crate::tiles::Tile;
+use crate::vec3::Color;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/// The samples of a region of the film weighted by the reconstruction filter.
+/// A sample counts for every pixel whose center is within the radius of the filter.
+#[derive(Debug, Clone)]
+pub struct FilteredImage {
+    pub filter: Filter,
+    /// The pixels of the film covered by the image
+    pub region: Tile,
+    /// Sums of the weighted samples and of their weights, row by row from the bottom
+    pub weighted: Vec<Color>,
+    pub weights: Vec<f64>,
+}
+
+impl FilteredImage {
+    pub fn new(filter: Filter, region: Tile) -> Self {
+        let size = region.i.len() * region.j.len();
+        Self {
+            filter,
+            region,
+            weighted: vec![Color::zero(); size],
+            weights: vec![0.; size],
+        }
+    }
+    fn index(&self, i: usize, j: usize) -> usize {
+        (j - self.region.j.start) * self.region.i.len() + i - self.region.i.start
+    }
+    /// Adds a sample at the point `(x, y)` of the film, in pixels from its bottom left
+    /// corner, to the pixels of the region around it
+    pub fn add(&mut self, (x, y): (f64, f64), color: Color) {
+        let radius = self.filter.radius;
+        // The pixels whose center can be within the radius along an axis
+        let reach = |p: f64, pixels: &Range<usize>| {
+            let first = (p - 0.5 - radius).floor().max(pixels.start as f64) as usize;
+            let end = (p + 0.5 + radius).ceil().min(pixels.end as f64) as usize;
+            first..end.max(first)
+        };
+        for j in reach(y, &self.region.j) {
+            let dy = y - (j as f64 + 0.5);
+            for i in reach(x, &self.region.i) {
+                let weight = self.filter.evaluate(x - (i as f64 + 0.5), dy);
+                if weight != 0. {
+                    let index = self.index(i, j);
+                    self.weighted[index] += color * weight;
+                    self.weights[index] += weight;
+                }
+            }
+        }
+    }
+    /// Adds the sums of another image, on the pixels where the regions overlap
+    pub fn merge(&mut self, other: &FilteredImage) {
+        let overlap = |a: &Range<usize>, b: &Range<usize>| a.start.max(b.start)..a.end.min(b.end);
+        let columns = overlap(&self.region.i, &other.region.i);
+        for j in overlap(&self.region.j, &other.region.j) {
+            for i in columns.clone() {
+                let (index, other_index) = (self.index(i, j), other.index(i, j));
+                self.weighted[index] += other.weighted[other_index];
+                self.weights[index] += other.weights[other_index];
+            }
+        }
+    }
+}
+
+/// The image being rendered, accumulated over the passes: the statistics of the samples
+/// of each pixel, the samples weighted by the reconstruction filter and the light that
+/// the integrator splatted onto the pixels.
+/// Pixels are stored row by row from the bottom.
+#[derive(Debug, Clone)]
+pub struct Film {
+    pub width: usize,
+    pub height: usize,
+    /// Statistics of the samples taken in each pixel, for adaptive sampling
+    pub stats: Vec<PixelStats>,
+    pub filtered: FilteredImage,
+    /// Light splatted onto the pixels
+    pub light: Vec<Color>,
+    /// The AOVs of the samples of the pixels, empty unless AOVs are rendered
+    pub aovs: AovBuffers,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: Filter) -> Self {
+        Self {
+            width,
+            height,
+            stats: vec![PixelStats::default(); width * height],
+            filtered: FilteredImage::new(
+                filter,
+                Tile {
+                    i: 0..width,
+                    j: 0..height,
+                },
+            ),
+            light: vec![Color::zero(); width * height],
+            aovs: AovBuffers::default(),
+        }
+    }
+    /// An empty filtered image for the samples of a tile: the tile and the pixels
+    /// around it that the filter reaches. Tiles rendered separately are then merged
+    /// into `filtered`.
+    pub fn tile_image(&self, tile: &Tile) -> FilteredImage {
+        let pad = self.filtered.filter.radius.ceil() as usize;
+        let region = Tile {
+            i: tile.i.start.saturating_sub(pad)..(tile.i.end + pad).min(self.width),
+            j: tile.j.start.saturating_sub(pad)..(tile.j.end + pad).min(self.height),
+        };
+        FilteredImage::new(self.filtered.filter, region)
+    }
+    /// Adds light that lands on a pixel from another pixel's sample
+    pub fn splat(&mut self, pixel: usize, color: Color) {
+        self.light[pixel] += color;
+    }
+    /// The image so far: the filtered samples of the pixels, plus the splatted light.
+    /// The splats are spread over the whole image, so they are divided by the mean number
+    /// of samples of the pixels.
+    pub fn image(&self) -> Vec<Color> {
+        let total: u64 = self.stats.iter().map(|pixel| pixel.count as u64).sum();
+        let mean_samples = (total as f64 / self.stats.len() as f64).max(1.);
+        let filtered = &self.filtered;
+        filtered
+            .weighted
+            .iter()
+            .zip(filtered.weights.iter())
+            .zip(self.light.iter())
+            .map(|((weighted, weight), light)| {
+                let color = if *weight != 0. {
+                    *weighted / *weight
+                } else {
+                    Color::zero()
+                };
+                color + *light / mean_samples
+            })
+            .collect()
+    }
+    /// Number of samples of the pixels
+    pub fn counts(&self) -> Vec<u32> {
+        self.stats.iter().map(|pixel| pixel.count).collect()
+    }
+}
+
+/// Film coordinates of the point `(x, y)` of a film of `size` pixels, in pixels from its
+/// bottom left corner. The camera spans [0, 1] from the first pixel to the last one.
+pub fn film_coordinates((x, y): (f64, f64), (width, height): (usize, usize)) -> (f64, f64) {
+    (x / (width - 1) as f64, y / (height - 1) as f64)
+}
+
+/// Index in the image of the pixel that the film coordinates `(s, t)` fall in, the inverse
+/// of `film_coordinates`
+pub fn film_pixel((s, t): (f64, f64), (width, height): (usize, usize)) -> usize {
+    let clamp = |x: f64, size: usize| ((x * (size - 1) as f64).max(0.) as usize).min(size - 1);
+    clamp(t, height) * width + clamp(s, width)
+}
+
+/// Writes an image with 1 or 3 channels, row by row from the bottom, as a PFM with 32 bit
+/// floats. Images with one channel take the first channel of the colors.
+pub fn write_pfm<T: Write>(
+    out: &mut T,
+    image: &[Color],
+    channels: usize,
+    width: usize,
+    height: usize,
+) -> io::Result<()> {
+    // A negative scale tells that the floats are little endian
+    let kind = if channels == 1 { "Pf" } else { "PF" };
+    write!(out, "{}\n{} {}\n-1.0\n", kind, width, height)?;
+    // Rows are stored from the bottom, like the image
+    for color in &image[..width * height] {
+        for channel in 0..channels {
+            out.write_all(&(color[channel] as f32).to_le_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a post processed image, row by row from the bottom, as a PPM from the top row
+pub fn write_ppm<T: Write>(out: &mut T, image: &[Color], width: usize, height: usize) {
+    if write!(out, "P3\n{} {}\n255\n", width, height).is_err() {
+        eprintln!("Couldn't write");
+        return;
+    }
+    for j in (0..height).rev() {
+        for i in 0..width {
+            write_color(out, image[j * width + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterKind;
+
+    /// Adds a sample to the pixel it falls in
+    fn add(film: &mut Film, point: (f64, f64), color: Color) {
+        let pixel = point.1 as usize * film.width + point.0 as usize;
+        film.stats[pixel].add(color);
+        film.filtered.add(point, color);
+    }
+
+    #[test]
+    fn film_image() {
+        let mut film = Film::new(2, 1, Filter::new(FilterKind::Box, None));
+        assert_eq!(film.image(), vec![Color::zero(); 2]);
+        add(&mut film, (0.2, 0.5), Color::one());
+        add(&mut film, (0.9, 0.1), Color::zero());
+        add(&mut film, (1., 0.), Color::one());
+        add(&mut film, (1.5, 0.99), Color::one());
+        film.splat(1, Color::one());
+        assert_eq!(film.counts(), vec![2, 2]);
+        // Splats are divided by the 2 samples of the pixels
+        assert_eq!(film.image(), vec![Color::one() * 0.5, Color::one() * 1.5]);
+    }
+
+    #[test]
+    fn filtered_samples_reach_the_neighbouring_tiles() {
+        let filter = Filter::new(FilterKind::Gaussian, Some(1.5));
+        let mut film = Film::new(8, 4, filter);
+        let mut samples = Vec::new();
+        for k in 0..64 {
+            let point = ((k % 8) as f64 + 0.37, (k / 16) as f64 + 0.81);
+            samples.push((point, Color::one() * (k % 5) as f64));
+        }
+        // Sampling the whole film at once
+        let mut whole = film.filtered.clone();
+        for (point, color) in &samples {
+            whole.add(*point, *color);
+        }
+        // Or tile by tile, merging the tiles
+        for tile in crate::tiles::tiles(8, 4, 2, crate::tiles::TileOrder::Hilbert) {
+            let mut image = film.tile_image(&tile);
+            for (point, color) in &samples {
+                if tile.i.contains(&(point.0 as usize)) && tile.j.contains(&(point.1 as usize)) {
+                    image.add(*point, *color);
+                }
+            }
+            assert!(image.region.i.len() > tile.i.len());
+            film.filtered.merge(&image);
+        }
+        for (a, b) in whole.weights.iter().zip(film.filtered.weights.iter()) {
+            assert!((a - b).abs() < 1e-12);
+        }
+        for (a, b) in whole.weighted.iter().zip(film.filtered.weighted.iter()) {
+            assert!((*a - *b).near_zero());
+        }
+        // A sample spreads over the pixels around it
+        let mut image = FilteredImage::new(filter, film.filtered.region.clone());
+        image.add((4.5, 2.5), Color::one());
+        assert_eq!(
+            image.weights.iter().filter(|weight| **weight > 0.).count(),
+            9
+        );
+    }
+
+    #[test]
+    fn film_pixel_of_film_coordinates() {
+        let size = (8, 4);
+        for pixel in 0..32 {
+            let point = ((pixel % 8) as f64 + 0.5, (pixel / 8) as f64 + 0.5);
+            assert_eq!(film_pixel(film_coordinates(point, size), size), pixel);
+        }
+        // Splats just outside of the film land on its edges
+        assert_eq!(film_pixel((-0.1, 1.2), size), 24);
+    }
+}
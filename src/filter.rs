@@ -0,0 +1,159 @@
+use std::f64::consts::PI;
+
+/// Reconstruction filter of the film, weighting the samples by their distance to the
+/// center of the pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterKind {
+    /// Samples only count for the pixel they fall in: the plain mean of the samples
+    Box,
+    /// Weight decreasing linearly with the distance
+    Tent,
+    Gaussian,
+    /// The cubic of Mitchell and Netravali with B = C = 1/3, sharper than the Gaussian
+    Mitchell,
+    /// Windowed sinc, the sharpest, with negative lobes that can ring around edges
+    Lanczos,
+}
+
+/// Names of the filters, as given on the command line
+pub const FILTER_NAMES: [&str; 5] = ["box", "tent", "gaussian", "mitchell", "lanczos"];
+
+impl FilterKind {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "box" => Ok(FilterKind::Box),
+            "tent" => Ok(FilterKind::Tent),
+            "gaussian" => Ok(FilterKind::Gaussian),
+            "mitchell" => Ok(FilterKind::Mitchell),
+            "lanczos" => Ok(FilterKind::Lanczos),
+            _ => Err(format!(
+                "Unknown filter `{}`, expected one of {:?}",
+                s, FILTER_NAMES
+            )),
+        }
+    }
+    /// Name of the filter, as given on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterKind::Box => "box",
+            FilterKind::Tent => "tent",
+            FilterKind::Gaussian => "gaussian",
+            FilterKind::Mitchell => "mitchell",
+            FilterKind::Lanczos => "lanczos",
+        }
+    }
+    /// Radius used when none is given, in pixels
+    pub fn default_radius(self) -> f64 {
+        match self {
+            FilterKind::Box => 0.5,
+            FilterKind::Tent => 1.,
+            FilterKind::Gaussian => 1.5,
+            FilterKind::Mitchell | FilterKind::Lanczos => 2.,
+        }
+    }
+}
+
+/// A separable reconstruction filter, zero beyond `radius` pixels on each axis
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Filter {
+    pub kind: FilterKind,
+    pub radius: f64,
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-5 {
+        1.
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+impl Filter {
+    pub fn new(kind: FilterKind, radius: Option<f64>) -> Self {
+        Self {
+            kind,
+            radius: radius.unwrap_or_else(|| kind.default_radius()),
+        }
+    }
+    /// Weight of a sample at offset `x` from the center of a pixel, along one axis
+    fn evaluate_1d(&self, x: f64) -> f64 {
+        let r = self.radius;
+        match self.kind {
+            // Half open, so that a sample on the border of two pixels only counts once
+            FilterKind::Box => (-r <= x && x < r) as u8 as f64,
+            FilterKind::Tent => (1. - x.abs() / r).max(0.),
+            FilterKind::Gaussian => {
+                // Shifted down so that it reaches 0 at the radius
+                let sigma = r / 3.;
+                let gaussian = |x: f64| (-x * x / (2. * sigma * sigma)).exp();
+                (gaussian(x) - gaussian(r)).max(0.)
+            }
+            FilterKind::Mitchell => {
+                let (b, c) = (1. / 3., 1. / 3.);
+                let x = (2. * x / r).abs();
+                let weight = if x < 1. {
+                    (12. - 9. * b - 6. * c) * x.powi(3)
+                        + (-18. + 12. * b + 6. * c) * x * x
+                        + (6. - 2. * b)
+                } else if x < 2. {
+                    (-b - 6. * c) * x.powi(3)
+                        + (6. * b + 30. * c) * x * x
+                        + (-12. * b - 48. * c) * x
+                        + (8. * b + 24. * c)
+                } else {
+                    0.
+                };
+                weight / 6.
+            }
+            FilterKind::Lanczos => {
+                if x.abs() >= r {
+                    0.
+                } else {
+                    sinc(x) * sinc(x / r)
+                }
+            }
+        }
+    }
+    /// Weight of a sample at offset `(x, y)` from the center of a pixel
+    pub fn evaluate(&self, x: f64, y: f64) -> f64 {
+        self.evaluate_1d(x) * self.evaluate_1d(y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters() {
+        for (kind, name) in [
+            FilterKind::Box,
+            FilterKind::Tent,
+            FilterKind::Gaussian,
+            FilterKind::Mitchell,
+            FilterKind::Lanczos,
+        ]
+        .iter()
+        .zip(FILTER_NAMES.iter())
+        {
+            assert_eq!(FilterKind::parse(name), Ok(*kind));
+            assert_eq!(kind.name(), *name);
+            let filter = Filter::new(*kind, None);
+            // Peaks at the center, symmetric, and zero beyond the radius
+            let center = filter.evaluate(0., 0.);
+            assert!(center > 0.);
+            for x in [0.1, 0.3, 0.7, 1.2].iter() {
+                assert!(filter.evaluate(*x, 0.) <= center, "{}", name);
+                assert!((filter.evaluate(*x, 0.) - filter.evaluate(-*x, 0.)).abs() < 1e-12);
+            }
+            assert_eq!(filter.evaluate(filter.radius + 0.01, 0.), 0.);
+            assert_eq!(filter.evaluate(0., -filter.radius - 0.01), 0.);
+        }
+        assert!(FilterKind::parse("sinc").is_err());
+        let wide = Filter::new(FilterKind::Tent, Some(2.));
+        assert_eq!(wide.evaluate(1., 0.), 0.5);
+        // Only the Mitchell and Lanczos filters have negative lobes
+        assert!(Filter::new(FilterKind::Lanczos, None).evaluate(1.5, 0.) < 0.);
+        assert!(Filter::new(FilterKind::Mitchell, None).evaluate(1.5, 0.) < 0.);
+    }
+}
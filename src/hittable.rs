@@ -1,18 +1,24 @@
 use crate::material::Material;
 use crate::ray::Ray;
+use crate::sampler::Sampler;
 use crate::vec3::{Point3, Vec3};
 use std::rc::Rc;
 use std::sync::Arc;
 
 /// A hit record keeps track of a "hit"s details
 /// It keeps the point, normal to the surface, the material type, wether the hit is inside or outside the object.
-//#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct HitRecord {
     pub point: Point3,
     pub normal: Vec3,
     pub material: Arc<dyn Material>,
     pub t: f64,
+    /// Surface coordinates of the hit point, in [0, 1]
+    pub u: f64,
+    pub v: f64,
     pub front_face: bool,
+    /// Wavelength in nanometers of the light carried by the ray, when rendering spectrally
+    pub wavelength: Option<f64>,
 }
 impl HitRecord {
     /// Given a ray and a normal that points outside it sets if the we hit the front face or the back face of the surface
@@ -31,11 +37,39 @@ pub trait Hittable: Sync + Send {
     /// A hit takes a ray and the min and max timepoints it travels and
     /// returns a record of something hit or None
     fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord>;
+    /// True if the object emits light and should be sampled as a light
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    /// The solid angle density of sampling `dir` from `origin` with `random`
+    fn pdf_value(&self, _origin: &Point3, _dir: &Vec3) -> f64 {
+        0.
+    }
+    /// Samples a direction from `origin` towards the object
+    fn random(&self, _origin: &Point3, _sampler: &mut dyn Sampler) -> Vec3 {
+        Vec3::new(1., 0., 0.)
+    }
+    /// Samples a point uniformly on the surface of the object.
+    /// Returns a record of the point, with the outward normal, and its area density
+    fn sample_surface(&self, _sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        None
+    }
+    /// The area density of `sample_surface` returning `point`
+    fn surface_pdf(&self, _point: &Point3) -> f64 {
+        0.
+    }
+    /// The material of the object, if it has a single one
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        None
+    }
+    /// Appends the description of the object and of its materials to the words of the
+    /// fingerprint of a scene, see `checkpoint::scene_hash`
+    fn fingerprint(&self, words: &mut Vec<u64>);
 }
 /// A list of Hittable objects
 pub struct HittableList {
     // reference list
-    objects: Vec<Box<dyn Hittable>>,
+    objects: Vec<Arc<dyn Hittable>>,
 }
 impl HittableList {
     /// Creates an empty list
@@ -46,7 +80,7 @@ impl HittableList {
     }
     /// Adds a reference of an object to the list
     pub fn add(&mut self, object: Box<dyn Hittable>) {
-        self.objects.push(object);
+        self.objects.push(Arc::from(object));
     }
     /// Clears the list
     pub fn clear(&mut self) {
@@ -56,26 +90,105 @@ impl HittableList {
     pub fn len(&self) -> usize {
         self.objects.len()
     }
-}
-impl Hittable for HittableList {
-    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+    /// True if the list has no objects
+    pub fn is_empty(&self) -> bool {
+        self.objects.is_empty()
+    }
+    /// Returns a list with the emissive objects of this list
+    pub fn lights(&self) -> HittableList {
+        Self {
+            objects: self
+                .objects
+                .iter()
+                .filter(|object| object.is_emissive())
+                .cloned()
+                .collect(),
+        }
+    }
+    /// Returns the closest hit and the index of the object that was hit
+    pub fn hit_object(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<(usize, HitRecord)> {
         let mut temp_rec = None;
         let mut closest_so_far = t_max;
 
-        for object in self.objects.iter() {
+        for (index, object) in self.objects.iter().enumerate() {
             if let Some(rec) = object.hit(r, t_min, closest_so_far) {
                 closest_so_far = rec.t;
-                temp_rec = Some(rec);
+                temp_rec = Some((index, rec));
             }
         }
         temp_rec
     }
+    /// The materials of the objects, each one once, in the order of the objects
+    pub fn materials(&self) -> Vec<Arc<dyn Material>> {
+        let mut materials: Vec<Arc<dyn Material>> = Vec::new();
+        for material in self.objects.iter().filter_map(|object| object.material()) {
+            if !materials.iter().any(|known| Arc::ptr_eq(known, material)) {
+                materials.push(Arc::clone(material));
+            }
+        }
+        materials
+    }
+    /// Picks the index of an object uniformly
+    fn pick(&self, sampler: &mut dyn Sampler) -> usize {
+        let n = self.objects.len();
+        ((sampler.next_1d() * n as f64) as usize).min(n - 1)
+    }
+}
+impl Hittable for HittableList {
+    fn hit(&self, r: &Ray, t_min: f64, t_max: f64) -> Option<HitRecord> {
+        self.hit_object(r, t_min, t_max).map(|(_, rec)| rec)
+    }
+    fn fingerprint(&self, words: &mut Vec<u64>) {
+        words.push(self.objects.len() as u64);
+        for object in &self.objects {
+            object.fingerprint(words);
+        }
+    }
+    fn is_emissive(&self) -> bool {
+        self.objects.iter().any(|object| object.is_emissive())
+    }
+    /// Average of the densities of the objects, as `random` picks one uniformly
+    fn pdf_value(&self, origin: &Point3, dir: &Vec3) -> f64 {
+        if self.objects.is_empty() {
+            return 0.;
+        }
+        let sum: f64 = self
+            .objects
+            .iter()
+            .map(|object| object.pdf_value(origin, dir))
+            .sum();
+        sum / self.objects.len() as f64
+    }
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        let index = self.pick(sampler);
+        self.objects[index].random(origin, sampler)
+    }
+    /// Picks an object uniformly and samples a point on it
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        if self.objects.is_empty() {
+            return None;
+        }
+        let index = self.pick(sampler);
+        let (rec, pdf) = self.objects[index].sample_surface(sampler)?;
+        Some((rec, pdf / self.objects.len() as f64))
+    }
+    fn surface_pdf(&self, point: &Point3) -> f64 {
+        if self.objects.is_empty() {
+            return 0.;
+        }
+        let sum: f64 = self
+            .objects
+            .iter()
+            .map(|object| object.surface_pdf(point))
+            .sum();
+        sum / self.objects.len() as f64
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::material::Metal;
+    use crate::material::{DiffuseLight, Metal};
     use crate::sphere::Sphere;
     use crate::vec3::Color;
     #[test]
@@ -90,4 +203,40 @@ mod tests {
 
         assert_eq!(hl.len(), 2);
     }
+    #[test]
+    fn hittable_list_objects_and_materials() {
+        let mut hl = HittableList::new();
+        let shared: Arc<dyn Material> = Arc::new(Metal::new(Color::one(), 0.5));
+        let other: Arc<dyn Material> = Arc::new(Metal::new(Color::one(), 1.));
+        hl.add(Box::new(Sphere::new(
+            Point3::new(0., 0., -4.),
+            1.,
+            Arc::clone(&shared),
+        )));
+        hl.add(Box::new(Sphere::new(Point3::new(0., 0., -2.), 0.5, other)));
+        hl.add(Box::new(Sphere::new(Point3::new(3., 0., -2.), 0.5, shared)));
+        let materials = hl.materials();
+        assert_eq!(materials.len(), 2);
+        assert!(Arc::ptr_eq(
+            &materials[0],
+            hl.objects[2].material().unwrap()
+        ));
+        // The closest of the two spheres on the ray
+        let r = Ray::new(Point3::zero(), Vec3::new(0., 0., -1.));
+        let (index, rec) = hl.hit_object(&r, 0.001, f64::INFINITY).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(rec.t, 1.5);
+    }
+    #[test]
+    fn hittable_list_lights() {
+        let mut hl = HittableList::new();
+        let mat = Metal::new(Color::one(), 0.5);
+        let light = DiffuseLight::new(Color::one());
+        hl.add(Box::new(Sphere::new(Point3::zero(), 1., Arc::new(mat))));
+        hl.add(Box::new(Sphere::new(Point3::one(), 0.5, Arc::new(light))));
+
+        let lights = hl.lights();
+        assert_eq!(lights.len(), 1);
+        assert!(lights.is_emissive());
+    }
 }
@@ -0,0 +1,545 @@
+use crate::aov;
+use crate::bdpt::Bdpt;
+use crate::hittable::{HitRecord, Hittable};
+use crate::mlt::Mlt;
+use crate::onb::Onb;
+use crate::path_dump::{self, Bounce};
+use crate::photon::{PhotonMapper, PhotonMaps, Sppm};
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::settings::{DirectLighting, Settings};
+use crate::spectrum::SpectralPathTracer;
+use crate::utils::power_heuristic;
+use crate::vec3::{random_cosine_direction, Color, Vec3};
+
+/// Light that lands on another pixel than the one being rendered,
+/// at film coordinates `s`, `t` in [0, 1]
+#[derive(Debug, Clone, Copy)]
+pub struct Splat {
+    pub s: f64,
+    pub t: f64,
+    pub color: Color,
+}
+
+/// A rendering algorithm: computes the light arriving at the camera along a ray
+pub trait Integrator: Sync + Send {
+    /// Returns the color seen along the camera ray `r`.
+    /// Light that reaches the camera through other pixels is pushed to `splats`.
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        splats: &mut Vec<Splat>,
+    ) -> Color;
+    /// Number of passes over the image. The samples of a pixel are split between them.
+    fn passes(&self) -> u32 {
+        1
+    }
+    /// Prepares a pass, before any ray of it is traced
+    fn preprocess(&mut self, _scene: &Scene, _pass: u32) {}
+    /// True if `li` records the AOVs of its samples with `aov::record`
+    fn records_aovs(&self) -> bool {
+        false
+    }
+    /// True if `li` records the bounces of its paths with `path_dump::record`
+    fn records_paths(&self) -> bool {
+        false
+    }
+    /// True if `render` renders the whole image, so that `li` alone doesn't give the
+    /// integrator's estimate of the pixels
+    fn renders_image(&self) -> bool {
+        false
+    }
+    /// Renders the whole image at once, for integrators that don't work pixel by pixel.
+    /// Returns the sums of the `samples_per_pixel` samples of the pixels, row by row
+    /// from the bottom, or None to render the image pixel by pixel with `li`.
+    fn render(
+        &self,
+        _scene: &Scene,
+        _width: usize,
+        _height: usize,
+        _samples_per_pixel: u32,
+    ) -> Option<Vec<Color>> {
+        None
+    }
+}
+
+/// Names of the integrators that `by_name` knows about
+pub const INTEGRATOR_NAMES: [&str; 13] = [
+    "path", "spectral", "bdpt", "photons", "sppm", "mlt", "whitted", "ao", "normals", "depth",
+    "uv", "material", "faces",
+];
+
+/// Builds the integrator with the given name, configured from the settings
+pub fn by_name(name: &str, settings: &Settings) -> Option<Box<dyn Integrator>> {
+    match name {
+        "path" => Some(Box::new(PathTracer {
+            max_depth: settings.max_depth,
+            rr_depth: settings.rr_depth,
+            direct_lighting: settings.direct_lighting,
+        })),
+        "spectral" => Some(Box::new(SpectralPathTracer {
+            max_depth: settings.max_depth,
+            rr_depth: settings.rr_depth,
+        })),
+        "bdpt" => Some(Box::new(Bdpt {
+            max_depth: settings.max_depth,
+        })),
+        "photons" => Some(Box::new(PhotonMapper {
+            photons: settings.photons,
+            nearest: 50,
+            max_radius: settings.photon_radius,
+            max_depth: settings.max_depth,
+            seed: settings.seed,
+            maps: PhotonMaps::default(),
+        })),
+        "sppm" => Some(Box::new(Sppm {
+            photons: settings.photons,
+            passes: settings.passes,
+            initial_radius: settings.photon_radius,
+            alpha: 2. / 3.,
+            max_depth: settings.max_depth,
+            seed: settings.seed,
+            radius: settings.photon_radius,
+            maps: PhotonMaps::default(),
+        })),
+        "mlt" => Some(Box::new(Mlt {
+            path: PathTracer {
+                max_depth: settings.max_depth,
+                rr_depth: settings.rr_depth,
+                direct_lighting: settings.direct_lighting,
+            },
+            bootstrap: 100_000,
+            chains: 1000,
+            sigma: 0.01,
+            large_step_probability: 0.3,
+            seed: settings.seed,
+        })),
+        "whitted" => Some(Box::new(Whitted {
+            max_depth: settings.max_depth,
+        })),
+        "ao" => Some(Box::new(AmbientOcclusion { distance: 1. })),
+        "normals" => Some(Box::new(Debug::Normals)),
+        "depth" => Some(Box::new(Debug::Depth { scale: 10. })),
+        "uv" => Some(Box::new(Debug::Uv)),
+        "material" => Some(Box::new(Debug::MaterialId)),
+        "faces" => Some(Box::new(Debug::Faces)),
+        _ => None,
+    }
+}
+
+/// Estimates the light arriving directly from the scene's lights at a hit,
+/// by sampling a direction towards a light and tracing a shadow ray.
+/// With `mis` the estimate is weighted against BSDF sampling.
+pub fn sample_lights(
+    rec: &HitRecord,
+    onb: &Onb,
+    wo: &Vec3,
+    scene: &Scene,
+    sampler: &mut dyn Sampler,
+    mis: bool,
+) -> Color {
+    let dir = scene.lights.random(&rec.point, sampler);
+    let light_pdf = scene.lights.pdf_value(&rec.point, &dir);
+    if light_pdf <= 0. {
+        return Color::zero();
+    }
+    let wi = onb.to_local(&dir.unit_vector());
+    let f = rec.material.eval(wo, &wi, rec);
+    if f.near_zero() {
+        return Color::zero();
+    }
+    let weight = if mis {
+        power_heuristic(light_pdf, rec.material.pdf(wo, &wi, rec))
+    } else {
+        1.
+    };
+    // The shadow ray only brings light if the first thing it hits is an emitter
+    let shadow_ray = Ray::new(rec.point, dir);
+    match scene.world.hit(&shadow_ray, 0.001, f64::INFINITY) {
+        Some(light_rec) => {
+            let emitted = light_rec.material.emitted(&light_rec);
+            light_rec.material.transmittance(&shadow_ray, &light_rec)
+                * emitted
+                * f
+                * (weight * wi.z().abs() / light_pdf)
+        }
+        None => Color::zero(),
+    }
+}
+
+/// Unidirectional path tracer.
+/// The path is traced iteratively, carrying the throughput of the path so far.
+/// After `rr_depth` bounces paths are randomly terminated with Russian roulette,
+/// and they are always stopped after `max_depth` bounces.
+#[derive(Debug, Clone, Copy)]
+pub struct PathTracer {
+    pub max_depth: u32,
+    pub rr_depth: u32,
+    pub direct_lighting: DirectLighting,
+}
+
+impl Integrator for PathTracer {
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        _splats: &mut Vec<Splat>,
+    ) -> Color {
+        let direct = self.direct_lighting;
+        let mut ray = *r;
+        let mut throughput = Color::one();
+        let mut color = Color::zero();
+        // The density with which the previous hit sampled the ray, or None for camera rays
+        // and rays sampled from delta lobes. It is used to weight the emission of the hit
+        // against light sampling.
+        let mut bsdf_pdf: Option<f64> = None;
+        // Whether the lobe sampled at the first hit is specular, which splits the light of
+        // the path between the AOVs
+        let mut specular = false;
+
+        for depth in 0..self.max_depth {
+            let (object, rec) = match scene.world.hit_object(&ray, 0.001, f64::INFINITY) {
+                Some(hit) => hit,
+                None => {
+                    let background = scene.background.color(&ray);
+                    path_dump::record_background(background);
+                    let light = throughput * background;
+                    aov::record(|aovs| aovs.add_light(depth, specular, light));
+                    color += light;
+                    break;
+                }
+            };
+            if depth == 0 {
+                aov::record(|aovs| aovs.first_hit(scene, &ray, &rec, object));
+            }
+            throughput *= rec.material.transmittance(&ray, &rec);
+
+            let emitted = rec.material.emitted(&rec);
+            let light = match (bsdf_pdf, direct) {
+                (None, _) | (_, DirectLighting::Bsdf) => Some(throughput * emitted),
+                // Already accounted for by sampling the lights at the previous hit
+                (Some(_), DirectLighting::Lights) => None,
+                (Some(bsdf_pdf), DirectLighting::Mis) => {
+                    if !emitted.near_zero() {
+                        let light_pdf = scene.lights.pdf_value(&ray.orig(), &ray.dir());
+                        Some(throughput * emitted * power_heuristic(bsdf_pdf, light_pdf))
+                    } else {
+                        None
+                    }
+                }
+            };
+            if let Some(light) = light {
+                aov::record(|aovs| aovs.add_light(depth, specular, light));
+                color += light;
+            }
+
+            let onb = Onb::build_from_w(&rec.normal);
+            let wo = onb.to_local(&-ray.dir().unit_vector());
+            let sample_direct = direct != DirectLighting::Bsdf
+                && !rec.material.is_delta()
+                && !scene.lights.is_empty();
+            let mut direct_light = Color::zero();
+            if sample_direct {
+                let mis = direct == DirectLighting::Mis;
+                direct_light = throughput * sample_lights(&rec, &onb, &wo, scene, sampler, mis);
+                color += direct_light;
+            }
+
+            let sample = rec
+                .material
+                .sample(&wo, &rec, sampler)
+                .filter(|sample| sample.pdf > 0.);
+            // The direct light of the first hit goes with the lobe sampled there too
+            if depth == 0 {
+                specular =
+                    sample.map_or_else(|| rec.material.is_specular(), |sample| sample.specular);
+            }
+            aov::record(|aovs| aovs.add_light(depth + 1, specular, direct_light));
+            path_dump::record(|| {
+                let scattered = sample.map(|s| (onb.local(&s.wi), s.weight(), s.pdf));
+                Bounce::new(&rec, emitted, scattered)
+            });
+            let sample = match sample {
+                Some(sample) => sample,
+                None => break,
+            };
+            throughput *= sample.weight();
+            ray = Ray::new(rec.point, onb.local(&sample.wi));
+            // Emitters hit through a delta lobe can't be sampled explicitly
+            bsdf_pdf = if sample_direct && !sample.delta {
+                Some(sample.pdf)
+            } else {
+                None
+            };
+
+            // Russian roulette: dim paths are likely to stop, the survivors are boosted
+            if depth + 1 >= self.rr_depth {
+                let survival = throughput
+                    .x()
+                    .max(throughput.y())
+                    .max(throughput.z())
+                    .min(1.);
+                if sampler.next_1d() >= survival {
+                    break;
+                }
+                throughput /= survival;
+            }
+        }
+        color
+    }
+    fn records_aovs(&self) -> bool {
+        true
+    }
+    fn records_paths(&self) -> bool {
+        true
+    }
+}
+
+/// Whitted-style ray tracer.
+/// Only perfectly specular (delta) bounces are followed. Other surfaces get
+/// the direct light of the emitters, through shadow rays, plus an ambient term
+/// lit by the background seen along the normal. There is no indirect diffuse light.
+#[derive(Debug, Clone, Copy)]
+pub struct Whitted {
+    pub max_depth: u32,
+}
+
+impl Integrator for Whitted {
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        _splats: &mut Vec<Splat>,
+    ) -> Color {
+        let mut ray = *r;
+        let mut throughput = Color::one();
+        let mut color = Color::zero();
+        for _ in 0..self.max_depth {
+            let rec = match scene.world.hit(&ray, 0.001, f64::INFINITY) {
+                Some(rec) => rec,
+                None => {
+                    color += throughput * scene.background.color(&ray);
+                    break;
+                }
+            };
+            throughput *= rec.material.transmittance(&ray, &rec);
+            color += throughput * rec.material.emitted(&rec);
+
+            let onb = Onb::build_from_w(&rec.normal);
+            let wo = onb.to_local(&-ray.dir().unit_vector());
+            if rec.material.is_delta() {
+                let sample = match rec.material.sample(&wo, &rec, sampler) {
+                    Some(sample) if sample.pdf > 0. => sample,
+                    _ => break,
+                };
+                throughput *= sample.weight();
+                ray = Ray::new(rec.point, onb.local(&sample.wi));
+                continue;
+            }
+
+            if !scene.lights.is_empty() {
+                color += throughput * sample_lights(&rec, &onb, &wo, scene, sampler, false);
+            }
+            let normal = Vec3::new(0., 0., 1.);
+            let ambient = scene.background.color(&Ray::new(rec.point, rec.normal));
+            color +=
+                throughput * rec.material.eval(&wo, &normal, &rec) * std::f64::consts::PI * ambient;
+            break;
+        }
+        color
+    }
+}
+
+/// Ambient occlusion: white where the hemisphere above the first hit
+/// is not blocked closer than `distance`, black where it is
+#[derive(Debug, Clone, Copy)]
+pub struct AmbientOcclusion {
+    pub distance: f64,
+}
+
+impl Integrator for AmbientOcclusion {
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        _splats: &mut Vec<Splat>,
+    ) -> Color {
+        let rec = match scene.world.hit(r, 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => return Color::one(),
+        };
+        let onb = Onb::build_from_w(&rec.normal);
+        let dir = onb.local(&random_cosine_direction(sampler));
+        match scene
+            .world
+            .hit(&Ray::new(rec.point, dir), 0.001, self.distance)
+        {
+            Some(_) => Color::zero(),
+            None => Color::one(),
+        }
+    }
+}
+
+/// Visualizations of the first hit, for debugging scenes
+#[derive(Debug, Clone, Copy)]
+pub enum Debug {
+    /// The outward normal, mapped from [-1, 1] to [0, 1]
+    Normals,
+    /// Distance to the camera, exp(-t / scale)
+    Depth { scale: f64 },
+    /// Surface coordinates, u in red and v in green
+    Uv,
+    /// A different color for every material
+    MaterialId,
+    /// Front faces in green, back faces in red
+    Faces,
+}
+
+impl Integrator for Debug {
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        _sampler: &mut dyn Sampler,
+        _splats: &mut Vec<Splat>,
+    ) -> Color {
+        let rec = match scene.world.hit(r, 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => return Color::zero(),
+        };
+        match self {
+            Debug::Normals => {
+                let outward = if rec.front_face {
+                    rec.normal
+                } else {
+                    -rec.normal
+                };
+                0.5 * (outward + Color::one())
+            }
+            Debug::Depth { scale } => {
+                let distance = rec.t * r.dir().norm();
+                Color::one() * (-distance / scale).exp()
+            }
+            Debug::Uv => Color::new(rec.u, rec.v, 0.),
+            Debug::MaterialId => id_color(
+                scene
+                    .material_index(&rec.material)
+                    .map_or(0, |index| index + 1),
+            ),
+            Debug::Faces => {
+                if rec.front_face {
+                    Color::new(0., 1., 0.)
+                } else {
+                    Color::new(1., 0., 0.)
+                }
+            }
+        }
+    }
+}
+
+/// A pseudo random color for an identifier
+fn id_color(id: usize) -> Color {
+    // Hash the id (splitmix64 finalizer) so that close ids get different colors
+    let mut x = id as u64;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^= x >> 31;
+    Color::new(
+        (x & 0xff) as f64 / 255.,
+        ((x >> 8) & 0xff) as f64 / 255.,
+        ((x >> 16) & 0xff) as f64 / 255.,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::color::luminance;
+    use crate::sampler::Seeded;
+    use crate::scene;
+
+    #[test]
+    fn integrators_by_name() {
+        let settings = Settings::default();
+        for name in INTEGRATOR_NAMES.iter() {
+            let integrator = by_name(name, &settings).unwrap();
+            // Only the path tracer records the AOVs and the bounces of its paths
+            assert_eq!(integrator.records_aovs(), *name == "path");
+            assert_eq!(integrator.records_paths(), *name == "path");
+            assert_eq!(integrator.renders_image(), *name == "mlt");
+        }
+        assert!(by_name("nope", &settings).is_none());
+    }
+
+    #[test]
+    fn russian_roulette_is_unbiased() {
+        // Killing paths from the first bounce on makes the estimate noisier, but the
+        // survivors are boosted so that the mean radiance stays the same
+        let scene = scene::simple_scene(1.);
+        let r = Ray::new(Vec3::zero(), Vec3::new(0., -0.2, -1.));
+        // Mean luminance along the ray, and its standard error
+        let estimate = |rr_depth: u32| {
+            let path = PathTracer {
+                max_depth: 8,
+                rr_depth,
+                direct_lighting: DirectLighting::Mis,
+            };
+            let mut sampler = Seeded::new(0);
+            let (mut sum, mut squared_sum) = (0., 0.);
+            let n = 20000;
+            for _ in 0..n {
+                let y = luminance(&path.li(&r, &scene, &mut sampler, &mut Vec::new()));
+                sum += y;
+                squared_sum += y * y;
+            }
+            let n = n as f64;
+            let mean = sum / n;
+            (mean, ((squared_sum / n - mean * mean) / (n - 1.)).sqrt())
+        };
+        let (with, error_with) = estimate(1);
+        let (without, error_without) = estimate(8);
+        let tolerance = 4. * (error_with * error_with + error_without * error_without).sqrt();
+        assert!(
+            (with - without).abs() < tolerance,
+            "{} {} {}",
+            with,
+            without,
+            tolerance
+        );
+    }
+
+    #[test]
+    fn debug_faces() {
+        let scene = scene::simple_scene(1.);
+        // From the camera we see the front face of the center sphere
+        let r = Ray::new(Vec3::zero(), Vec3::new(0., 0., -1.));
+        assert_eq!(
+            Debug::Faces.li(&r, &scene, &mut Seeded::new(0), &mut Vec::new()),
+            Color::new(0., 1., 0.)
+        );
+        // From inside we see its back face
+        let r = Ray::new(Vec3::new(0., 0., -1.), Vec3::new(0., 0., -1.));
+        assert_eq!(
+            Debug::Faces.li(&r, &scene, &mut Seeded::new(0), &mut Vec::new()),
+            Color::new(1., 0., 0.)
+        );
+    }
+
+    #[test]
+    fn ambient_occlusion_open_sky() {
+        let scene = scene::simple_scene(1.);
+        // Looking up there is nothing to hit
+        let r = Ray::new(Vec3::zero(), Vec3::new(0., 1., 0.));
+        let ao = AmbientOcclusion { distance: 1. };
+        assert_eq!(
+            ao.li(&r, &scene, &mut Seeded::new(0), &mut Vec::new()),
+            Color::one()
+        );
+    }
+}
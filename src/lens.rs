@@ -0,0 +1,365 @@
+use crate::color::luminance;
+use crate::settings::Settings;
+use crate::tiles::Tile;
+use crate::vec3::Color;
+use rayon::prelude::*;
+use std::f64::consts::PI;
+
+/// Effects of the lens and of the light scattered in the camera, applied to the linear
+/// image before it is tone mapped. Each one is off unless its strength is given.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct LensEffects {
+    /// Luminance above which pixels bloom and glare
+    pub threshold: f64,
+    /// Share of the light of the bright pixels scattered around them
+    pub bloom: Option<f64>,
+    /// Standard deviation of the bloom in pixels
+    pub bloom_radius: f64,
+    /// Share of the light of the bright pixels diffracted into streaks
+    pub glare: Option<f64>,
+    /// Number of blades of the aperture. The edges of the polygon diffract the light into
+    /// a spike each way, and the spikes of parallel edges overlap.
+    pub glare_blades: u32,
+    /// Length in pixels over which the green light of the streaks fades by e
+    pub glare_length: f64,
+    /// The light falls off like cos^4 of the angle of incidence, whose tangent in the
+    /// corners is the given value
+    pub vignetting: Option<f64>,
+    /// How far apart in pixels the red and blue images are in the corners
+    pub chromatic_aberration: Option<f64>,
+}
+
+/// Wavelengths in nanometers of the channels, the streaks of diffraction grow with them
+const WAVELENGTHS: [f64; 3] = [610., 550., 465.];
+
+/// Samples a channel of the image at the point `(x, y)` in pixels, interpolating
+/// bilinearly between the centers of the pixels and clamping to the border
+fn sample(image: &[Color], (width, height): (usize, usize), (x, y): (f64, f64), c: usize) -> f64 {
+    let x = (x - 0.5).max(0.).min((width - 1) as f64);
+    let y = (y - 0.5).max(0.).min((height - 1) as f64);
+    let (i, j) = (x as usize, y as usize);
+    let (i1, j1) = ((i + 1).min(width - 1), (j + 1).min(height - 1));
+    let (fx, fy) = (x - i as f64, y - j as f64);
+    let at = |i: usize, j: usize| image[j * width + i][c];
+    (1. - fy) * ((1. - fx) * at(i, j) + fx * at(i1, j))
+        + fy * ((1. - fx) * at(i, j1) + fx * at(i1, j1))
+}
+
+/// A separable Gaussian blur, cut at 3 standard deviations
+fn blur(image: &[Color], (width, height): (usize, usize), sigma: f64) -> Vec<Color> {
+    let reach = (3. * sigma).ceil() as isize;
+    let weights: Vec<f64> = (-reach..=reach)
+        .map(|d| (-(d * d) as f64 / (2. * sigma * sigma)).exp())
+        .collect();
+    let total: f64 = weights.iter().sum();
+    let pass = |image: &[Color], (di, dj): (isize, isize)| -> Vec<Color> {
+        (0..width * height)
+            .into_par_iter()
+            .map(|pixel| {
+                let (i, j) = ((pixel % width) as isize, (pixel / width) as isize);
+                let mut sum = Color::zero();
+                for (k, weight) in weights.iter().enumerate() {
+                    let d = k as isize - reach;
+                    let x = (i + d * di).max(0).min(width as isize - 1) as usize;
+                    let y = (j + d * dj).max(0).min(height as isize - 1) as usize;
+                    sum += image[y * width + x] * *weight;
+                }
+                sum / total
+            })
+            .collect()
+    };
+    pass(&pass(image, (1, 0)), (0, 1))
+}
+
+impl LensEffects {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            threshold: settings.bright_threshold,
+            bloom: settings.bloom,
+            bloom_radius: settings.bloom_radius,
+            glare: settings.glare,
+            glare_blades: settings.glare_blades,
+            glare_length: settings.glare_length,
+            vignetting: settings.vignetting,
+            chromatic_aberration: settings.chromatic_aberration,
+        }
+    }
+
+    /// The light of the pixels above the threshold
+    fn bright(&self, image: &[Color]) -> Vec<Color> {
+        image
+            .iter()
+            .map(|color| {
+                let l = luminance(color);
+                if l > self.threshold {
+                    *color * ((l - self.threshold) / l)
+                } else {
+                    Color::zero()
+                }
+            })
+            .collect()
+    }
+
+    /// Streaks of the light of the bright pixels diffracted by the edges of the aperture
+    fn glare(&self, bright: &[Color], (width, height): (usize, usize)) -> Vec<Color> {
+        let blades = self.glare_blades.max(2) as usize;
+        // The spikes of the opposite edges of an even polygon are the same
+        let spikes = blades * (1 + blades % 2);
+        let directions: Vec<(f64, f64)> = (0..spikes)
+            .map(|k| {
+                let angle = PI / 2. + 2. * PI * k as f64 / spikes as f64;
+                (angle.cos(), angle.sin())
+            })
+            .collect();
+        let lengths = WAVELENGTHS.map(|wavelength| self.glare_length * wavelength / WAVELENGTHS[1]);
+        let reach = (4. * lengths[0]).ceil() as usize;
+        // Each streak carries the same share of the light of the pixel, whatever its length
+        let totals = lengths.map(|length| {
+            spikes as f64
+                * (1..=reach)
+                    .map(|t| (-(t as f64) / length).exp())
+                    .sum::<f64>()
+        });
+        let mut glare = vec![Color::zero(); width * height];
+        for (pixel, color) in bright.iter().enumerate() {
+            if color.near_zero() {
+                continue;
+            }
+            let (i, j) = ((pixel % width) as f64, (pixel / width) as f64);
+            for (dx, dy) in &directions {
+                for t in 1..=reach {
+                    let (x, y) = (i + dx * t as f64, j + dy * t as f64);
+                    let weights = Color::new(
+                        (-(t as f64) / lengths[0]).exp() / totals[0],
+                        (-(t as f64) / lengths[1]).exp() / totals[1],
+                        (-(t as f64) / lengths[2]).exp() / totals[2],
+                    );
+                    // Spread over the 4 pixels around the point
+                    let (x0, y0) = (x.floor(), y.floor());
+                    for (px, py, w) in [
+                        (x0, y0, (1. - (x - x0)) * (1. - (y - y0))),
+                        (x0 + 1., y0, (x - x0) * (1. - (y - y0))),
+                        (x0, y0 + 1., (1. - (x - x0)) * (y - y0)),
+                        (x0 + 1., y0 + 1., (x - x0) * (y - y0)),
+                    ]
+                    .iter()
+                    {
+                        if *px >= 0. && *py >= 0. && *px < width as f64 && *py < height as f64 {
+                            glare[*py as usize * width + *px as usize] += *color * weights * *w;
+                        }
+                    }
+                }
+            }
+        }
+        glare
+    }
+
+    /// Applies the effects to an image, row by row from the bottom
+    pub fn apply(&self, image: &[Color], width: usize, height: usize) -> Vec<Color> {
+        let frame = Tile {
+            i: 0..width,
+            j: 0..height,
+        };
+        self.apply_in(image, &frame, (width, height))
+    }
+
+    /// Applies the effects to the image of a region of a frame of the given size, such as
+    /// a crop window. The effects of the lens stay centered on the frame.
+    pub fn apply_in(&self, image: &[Color], region: &Tile, frame: (usize, usize)) -> Vec<Color> {
+        let (width, height) = (region.i.len(), region.j.len());
+        let size = (width, height);
+        let mut out = image.to_vec();
+        if self.bloom.is_some() || self.glare.is_some() {
+            let bright = self.bright(image);
+            // The light that blooms and glares is taken off the bright pixels, so that the
+            // effects only move light around. They can't take more than all of it.
+            let shares = self.bloom.unwrap_or(0.) + self.glare.unwrap_or(0.);
+            let scale = 1. / shares.max(1.);
+            if let Some(strength) = self.bloom.map(|bloom| bloom * scale) {
+                let bloom = blur(&bright, size, self.bloom_radius.max(0.5));
+                for ((color, bloom), bright) in out.iter_mut().zip(bloom.iter()).zip(bright.iter())
+                {
+                    *color += (*bloom - *bright) * strength;
+                }
+            }
+            if let Some(strength) = self.glare.map(|glare| glare * scale) {
+                let glare = self.glare(&bright, size);
+                for ((color, glare), bright) in out.iter_mut().zip(glare.iter()).zip(bright.iter())
+                {
+                    *color += (*glare - *bright) * strength;
+                }
+            }
+        }
+        // Offsets from the center of the frame, relative to its corners, in the pixels of
+        // the region
+        let half = (frame.0 as f64 / 2., frame.1 as f64 / 2.);
+        let corner = (half.0 * half.0 + half.1 * half.1).sqrt();
+        let center = (
+            half.0 - region.i.start as f64,
+            half.1 - region.j.start as f64,
+        );
+        let offset = |pixel: usize| {
+            let (i, j) = ((pixel % width) as f64 + 0.5, (pixel / width) as f64 + 0.5);
+            ((i - center.0) / corner, (j - center.1) / corner)
+        };
+        if let Some(pixels) = self.chromatic_aberration {
+            // The red image is magnified and the blue one shrunk around the center
+            let scale = pixels / 2.;
+            let lens = &out;
+            out = (0..width * height)
+                .into_par_iter()
+                .map(|pixel| {
+                    let (dx, dy) = offset(pixel);
+                    let at = |shift: f64| {
+                        let r = corner - shift;
+                        (center.0 + dx * r, center.1 + dy * r)
+                    };
+                    Color::new(
+                        sample(lens, size, at(scale), 0),
+                        lens[pixel].y(),
+                        sample(lens, size, at(-scale), 2),
+                    )
+                })
+                .collect();
+        }
+        if let Some(tangent) = self.vignetting {
+            for (pixel, color) in out.iter_mut().enumerate() {
+                let (dx, dy) = offset(pixel);
+                let t = tangent * tangent * (dx * dx + dy * dy);
+                *color *= 1. / ((1. + t) * (1. + t));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn effects() -> LensEffects {
+        LensEffects {
+            threshold: 1.,
+            bloom_radius: 2.,
+            glare_blades: 6,
+            glare_length: 4.,
+            ..LensEffects::default()
+        }
+    }
+    /// A dim image with a bright pixel in the middle
+    fn image(size: usize) -> Vec<Color> {
+        let mut image = vec![Color::one() * 0.5; size * size];
+        image[size / 2 * size + size / 2] = Color::one() * 101.;
+        image
+    }
+
+    #[test]
+    fn bloom_and_glare_spread_the_bright_pixels() {
+        let size = 33;
+        let image = image(size);
+        assert_eq!(effects().apply(&image, size, size), image);
+        let bloom = LensEffects {
+            bloom: Some(0.5),
+            ..effects()
+        }
+        .apply(&image, size, size);
+        let center = size / 2 * size + size / 2;
+        // Half of the light above the threshold is spread, without adding any, and the
+        // dim pixels stay unchanged away from the bright one
+        let total = |image: &[Color]| image.iter().cloned().sum::<Color>();
+        assert!((total(&bloom) - total(&image)).norm() < 1e-6);
+        assert!(bloom[center].x() > 51. && bloom[center].x() < 55.);
+        assert!(bloom[center + 1].x() > 0.6);
+        assert_eq!(bloom[0], image[0]);
+
+        // Spikes go up, down and along 4 diagonals of a hexagonal aperture, not sideways
+        let glare = LensEffects {
+            glare: Some(1.),
+            ..effects()
+        }
+        .apply(&image, size, size);
+        let above = center + 6 * size;
+        let beside = center + 6;
+        assert!(glare[above].y() > 0.51 && glare[beside].y() < 0.5 + 1e-9);
+        // Red light diffracts further than blue light
+        assert!(glare[above].x() > glare[above].z());
+        // Streaks that leave the image take their light with them
+        let lost = total(&image) - total(&glare);
+        assert!(lost.x() > 0. && lost.x() < 10.);
+        // Within a larger image, the light only moves
+        let large = 41;
+        let glare = LensEffects {
+            glare: Some(1.),
+            ..effects()
+        }
+        .apply(&self::image(large), large, large);
+        assert!((total(&glare) - total(&self::image(large))).norm() < 1e-6);
+        // Bloom and glare together take at most all of the light above the threshold
+        let both = LensEffects {
+            bloom: Some(1.),
+            glare: Some(1.),
+            ..effects()
+        }
+        .apply(&self::image(large), large, large);
+        assert!((total(&both) - total(&self::image(large))).norm() < 1e-6);
+        assert!(both.iter().all(|color| color.x() >= 0.5 - 1e-9));
+        // A pentagon has 10 spikes, one of which goes down
+        let pentagon = LensEffects {
+            glare: Some(1.),
+            glare_blades: 5,
+            ..effects()
+        }
+        .apply(&image, size, size);
+        assert!(pentagon[center - 6 * size].y() > 0.51 && pentagon[above].y() > 0.51);
+    }
+
+    #[test]
+    fn vignetting_and_chromatic_aberration() {
+        let size = 32;
+        let white = vec![Color::one(); size * size];
+        let vignetted = LensEffects {
+            vignetting: Some(1.),
+            ..effects()
+        }
+        .apply(&white, size, size);
+        let center = size / 2 * size + size / 2;
+        assert!(vignetted[center].x() > 0.99);
+        // cos^4 of 45 degrees in the corner
+        assert!((vignetted[0].x() - 0.25).abs() < 0.02);
+
+        // A white column on the right: its red fringe is further out than its blue one
+        let mut column = vec![Color::zero(); size * size];
+        for j in 0..size {
+            column[j * size + 28] = Color::one();
+        }
+        let fringed = LensEffects {
+            chromatic_aberration: Some(4.),
+            ..effects()
+        }
+        .apply(&column, size, size);
+        let row = size / 2 * size;
+        assert!(fringed[row + 29].x() > fringed[row + 29].z());
+        assert!(fringed[row + 27].z() > fringed[row + 27].x());
+        assert_eq!(fringed[row + 28].y(), 1.);
+
+        // A crop window gets the effects of the same pixels of the whole frame
+        let region = Tile {
+            i: 20..30,
+            j: 4..12,
+        };
+        let effects = LensEffects {
+            vignetting: Some(1.),
+            chromatic_aberration: Some(4.),
+            ..effects()
+        };
+        let whole = effects.apply(&column, size, size);
+        let cropped = effects.apply_in(&region.extract(&column, size), &region, (size, size));
+        // Away from the borders of the crop, that the aberration samples across
+        for (k, color) in cropped.iter().enumerate() {
+            let (i, j) = (region.i.start + k % 10, region.j.start + k / 10);
+            if (22..28).contains(&i) {
+                assert!((*color - whole[j * size + i]).near_zero(), "{} {}", i, j);
+            }
+        }
+    }
+}
@@ -1,175 +1,550 @@
+mod adaptive;
+mod aov;
+mod bdpt;
+mod benchmark;
 mod camera;
+mod checkpoint;
 mod color;
+mod denoise;
+mod distributed;
+mod exr;
+mod film;
+mod filter;
 mod hittable;
+mod integrator;
+mod lens;
 mod material;
+mod mlt;
+mod onb;
+mod path_dump;
+mod photon;
+mod principled;
 mod ray;
+mod sampler;
+mod scene;
+mod settings;
+mod spectrum;
 mod sphere;
+mod tiles;
+mod tonemap;
 mod utils;
 mod vec3;
-use camera::Camera;
-use color::write_color;
-use hittable::{Hittable, HittableList};
+use aov::{AovBuffers, SampleAovs};
+use checkpoint::Checkpoint;
+use film::{film_coordinates, film_pixel, write_ppm, Film};
+use filter::Filter;
 use indicatif::ProgressBar;
-use material::{Dielectric, Lambertian, Material, Metal};
+use integrator::{Integrator, Splat};
 
 use ray::Ray;
 use rayon::prelude::*;
-use sphere::Sphere;
+use sampler::Sampler;
+use scene::Scene;
+use settings::Settings;
+use std::fs::File;
 use std::io;
-use std::rc::Rc;
-use std::sync::Arc;
-use vec3::{Color, Point3, Vec3};
+use std::net::TcpListener;
+use std::ops::Range;
+use std::time::Instant;
+use tiles::Tile;
+use tonemap::PostProcess;
+use vec3::Color;
 
 const ASPECT_RATIO: f32 = 1. / 1.;
 const IMAGE_WIDTH: i32 = 512;
 const IMAGE_HEIGHT: i32 = (IMAGE_WIDTH as f32 / ASPECT_RATIO) as i32;
-const SAMPLES_PER_PIXEL: u32 = 100;
-const MAX_DEPTH: u32 = 50;
 
-fn random_scene() -> HittableList {
-    let mut world = HittableList::new();
-    let ground_material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
-    let ground_sphere = Sphere::new(Point3::new(0., -1000., 0.), 1000., ground_material);
-    world.add(Box::new(ground_sphere));
-
-    for a in -11..11 {
-        for b in -11..11 {
-            let choose_mat = rand::random::<f64>();
-            let center: Point3 = Point3::new(
-                a as f64 + 0.9 * rand::random::<f64>(),
-                0.2,
-                b as f64 + rand::random::<f64>(),
-            );
-            if (center - Point3::new(4., 0.2, 0.)).norm() > 0.9 {
-                let sphere_material: Arc<dyn Material>;
-                if choose_mat < 0.8 {
-                    // Diffuse
-                    let albedo = Color::random() * Color::random();
-                    sphere_material = Arc::new(Lambertian::new(albedo));
-                } else if choose_mat < 0.95 {
-                    // Metal
-                    let albedo = Color::random_interval(0.5, 1.);
-                    let fuzz = rand::random::<f64>() / 2.;
-                    sphere_material = Arc::new(Metal::new(albedo, fuzz));
-                } else {
-                    // Glass
-                    let ir = 1.5;
-                    sphere_material = Arc::new(Dielectric::new(ir));
+/// The camera ray through the point `(x, y)` of the film, in pixels from its bottom left
+/// corner
+fn camera_ray(point: (f64, f64), scene: &Scene, sampler: &mut dyn Sampler) -> Ray {
+    let (u, v) = film_coordinates(point, (IMAGE_WIDTH as usize, IMAGE_HEIGHT as usize));
+    scene.camera.get_ray(u, v, sampler)
+}
+/// Shoots a ray through the point `(x, y)` of the film and returns the color
+fn shoot_ray(
+    point: (f64, f64),
+    scene: &Scene,
+    integrator: &dyn Integrator,
+    sampler: &mut dyn Sampler,
+    splats: &mut Vec<Splat>,
+) -> Color {
+    let r = camera_ray(point, scene, sampler);
+    integrator.li(&r, scene, sampler, splats)
+}
+/// Index in the image of the pixel that the film coordinates fall in
+fn splat_index(splat: &Splat) -> usize {
+    film_pixel(
+        (splat.s, splat.t),
+        (IMAGE_WIDTH as usize, IMAGE_HEIGHT as usize),
+    )
+}
+/// Takes the samples with indices in `samples` out of `samples_per_pixel` of the active
+/// pixels, tile by tile, adding them and their AOVs, if the film has any, to the film.
+/// Tiles are rendered in parallel, a few per thread at a time to bound the memory used
+/// by the splats, and their results are added in the order of the tiles, including the
+/// filtered samples that fall on the pixels of the neighbouring tiles. As each sample
+/// draws its random numbers from a sampler seeded from the seed, its pixel and its index,
+/// the image doesn't depend on the threads.
+#[allow(clippy::too_many_arguments)]
+fn sample_pixels(
+    scene: &Scene,
+    integrator: &dyn Integrator,
+    settings: &Settings,
+    tiles: &[Tile],
+    active: &[bool],
+    samples: Range<u32>,
+    samples_per_pixel: u32,
+    film: &mut Film,
+) {
+    let width = film.width;
+    for batch in tiles.chunks(4 * rayon::current_num_threads()) {
+        let current: &Film = film;
+        let results: Vec<_> = batch
+            .par_iter()
+            .map(|tile| {
+                let mut pixels = Vec::new();
+                let mut image = current.tile_image(tile);
+                let mut splats = Vec::new();
+                let record_aovs = !current.aovs.aovs.is_empty();
+                for pixel in tile.pixels(width).filter(|&pixel| active[pixel]) {
+                    let (i, j) = (pixel % width, pixel / width);
+                    let mut pixel_stats = current.stats[pixel];
+                    let mut pixel_aovs = SampleAovs::default();
+                    for index in samples.clone() {
+                        let mut sampler = settings.sampler.pixel_sampler(
+                            settings.seed,
+                            pixel as u64,
+                            index,
+                            samples_per_pixel,
+                        );
+                        let (du, dv) = sampler.next_2d();
+                        let point = (i as f64 + du, j as f64 + dv);
+                        let (color, aovs) = aov::recording(record_aovs, || {
+                            shoot_ray(point, scene, integrator, sampler.as_mut(), &mut splats)
+                        });
+                        pixel_stats.add(color);
+                        image.add(point, color);
+                        if let Some(aovs) = aovs {
+                            pixel_aovs.add(&aovs, index == 0);
+                        }
+                    }
+                    pixels.push((pixel, pixel_stats, pixel_aovs));
                 }
-                let world_sphere = Sphere::new(center, 0.2, sphere_material);
-                world.add(Box::new(world_sphere));
+                (pixels, image, splats)
+            })
+            .collect();
+        for (pixels, image, splats) in results {
+            for (pixel, pixel_stats, pixel_aovs) in pixels {
+                film.stats[pixel] = pixel_stats;
+                film.aovs.add(pixel, &pixel_aovs);
+            }
+            film.filtered.merge(&image);
+            for splat in splats {
+                film.splat(splat_index(&splat), splat.color);
             }
         }
     }
-    let material1 = Arc::new(Dielectric::new(1.5));
-    let sphere1 = Sphere::new(Point3::new(0., 1., 0.), 1., material1);
-    let material2 = Arc::new(Lambertian::new(Color::new(0.4, 0.2, 0.1)));
-    let sphere2 = Sphere::new(Point3::new(-4., 1., 0.), 1., material2);
-    let material3 = Arc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0));
-    let sphere3 = Sphere::new(Point3::new(0., 1., 0.), 1., material3);
-    world.add(Box::new(sphere1));
-    world.add(Box::new(sphere2));
-    world.add(Box::new(sphere3));
-    world
 }
-/// Given a ray and a list of objects, returns the color of the ray.
-fn ray_color(r: &Ray, world: &HittableList, depth: u32) -> Color {
-    // Check recursion depth
-    if depth == 0 {
-        return Color::zero();
+/// Writes the image so far to the progress file
+fn write_progress(path: &str, film: &Film, post: &PostProcess) {
+    match File::create(path) {
+        Ok(mut file) => write_ppm(
+            &mut file,
+            &post.image(&film.image(), film.width, film.height),
+            film.width,
+            film.height,
+        ),
+        Err(err) => eprintln!("Couldn't write the progress to `{}`: {}", path, err),
     }
-    if let Some(rec) = world.hit(r, 0.001, f64::INFINITY) {
-        if let Some((attenuation, scattered)) = rec.material.scatter(r, &rec) {
-            return attenuation * ray_color(&scattered, world, depth - 1);
-        } else {
-            return Color::zero();
-        }
+}
+/// Saves the state of the render to the checkpoint file
+fn write_checkpoint(path: &str, checkpoint: Checkpoint) {
+    if let Err(err) = checkpoint.save(path) {
+        eprintln!("Couldn't write the checkpoint to `{}`: {}", path, err);
     }
-
-    let unit_direction: Vec3 = r.dir().unit_vector();
-    let t = 0.5 * (unit_direction.y() + 1.);
-    (1.0 - t) * Color::one() + t * Color::new(0.5, 0.7, 1.)
 }
-/// Shoots a ray on the pixel and returns the color
-fn shoot_ray(i: i32, j: i32, world: &HittableList, cam: &Camera) -> Color {
-    let u = (i as f64 + rand::random::<f64>()) / (IMAGE_WIDTH - 1) as f64;
-    let v = (j as f64 + rand::random::<f64>()) / (IMAGE_HEIGHT - 1) as f64;
-    let r = cam.get_ray(u, v);
-    ray_color(&r, &world, MAX_DEPTH)
+/// Renders the image progressively, in rounds that add samples to every pixel.
+/// Multi-pass integrators get one round per pass. Otherwise each round adds one sample
+/// per pixel, or `min_samples` with adaptive sampling, where pixels whose error is below
+/// the threshold stop being sampled. Each round renders the tiles of the image in parallel.
+/// The render stops at the target number of samples, when every pixel has converged or
+/// when the time budget is spent, and the image so far is written at regular intervals.
+/// The state of the render is saved at regular intervals and at the end, and the render
+/// continues from `resume` if given. Only the pixels of `region` are sampled.
+fn render_pixels(
+    scene: &Scene,
+    integrator: &mut dyn Integrator,
+    settings: &Settings,
+    region: &Tile,
+    resume: Option<Checkpoint>,
+) -> Film {
+    let (mut film, mut active, first_round) = match resume {
+        Some(checkpoint) => (checkpoint.film, checkpoint.active, checkpoint.round),
+        None => {
+            let filter = Filter::new(settings.filter, settings.filter_radius);
+            let mut film = Film::new(IMAGE_WIDTH as usize, IMAGE_HEIGHT as usize, filter);
+            film.aovs = AovBuffers::new(&settings.aovs, film.stats.len());
+            let mut active = vec![false; film.stats.len()];
+            for pixel in region.pixels(film.width) {
+                active[pixel] = true;
+            }
+            (film, active, 0)
+        }
+    };
+    let passes = integrator.passes().max(1);
+    let adaptive = match settings.adaptive {
+        Some(_) if passes > 1 => {
+            eprintln!("Adaptive sampling is not available with several passes");
+            None
+        }
+        threshold => threshold,
+    };
+    let target = settings.samples.max(1);
+    let round_samples = if passes > 1 {
+        // The samples of each pixel are split between the passes of the integrator
+        (target / passes).max(1)
+    } else if adaptive.is_some() {
+        settings.min_samples.max(2).min(target)
+    } else {
+        1
+    };
+    let samples_per_pixel = if passes > 1 {
+        round_samples * passes
+    } else {
+        target
+    };
+    let rounds = samples_per_pixel.div_ceil(round_samples);
+
+    let tiles = tiles::tiles_in(region, settings.tile_size, settings.tile_order);
+    let settings_hash = checkpoint::settings_hash(settings);
+    let scene_hash = checkpoint::scene_hash(scene);
+    let mut active_count = active.iter().filter(|active| **active).count();
+    let start = Instant::now();
+    let mut last_progress = start;
+    let mut last_checkpoint = start;
+    let region_pixels = region.i.len() * region.j.len();
+    let pb = ProgressBar::new(region_pixels as u64 * samples_per_pixel as u64);
+    pb.inc(film.counts().iter().map(|count| *count as u64).sum());
+    // The round that the render would continue from
+    let mut next_round = first_round;
+    for round in first_round..rounds {
+        if passes > 1 || round == first_round {
+            integrator.preprocess(scene, round);
+        }
+        let first = round * round_samples;
+        let samples = first..(first + round_samples).min(samples_per_pixel);
+        pb.inc((active_count * samples.len()) as u64);
+        sample_pixels(
+            scene,
+            integrator,
+            settings,
+            &tiles,
+            &active,
+            samples,
+            samples_per_pixel,
+            &mut film,
+        );
+        next_round = round + 1;
+
+        if let Some(threshold) = adaptive {
+            for (active, stats) in active.iter_mut().zip(film.stats.iter()) {
+                *active = *active && stats.error() > threshold;
+            }
+            active_count = active.iter().filter(|active| **active).count();
+            if active_count == 0 {
+                next_round = rounds;
+                break;
+            }
+        }
+        if let Some(path) = &settings.progress {
+            if last_progress.elapsed().as_secs_f64() >= settings.progress_interval {
+                write_progress(path, &film, &PostProcess::from_settings(settings));
+                last_progress = Instant::now();
+            }
+        }
+        if let Some(path) = &settings.checkpoint {
+            if last_checkpoint.elapsed().as_secs_f64() >= settings.checkpoint_interval {
+                let checkpoint = Checkpoint {
+                    settings_hash,
+                    scene_hash,
+                    round: next_round,
+                    active: active.clone(),
+                    film: film.clone(),
+                };
+                write_checkpoint(path, checkpoint);
+                last_checkpoint = Instant::now();
+            }
+        }
+        if let Some(budget) = settings.time_budget {
+            if start.elapsed().as_secs_f64() >= budget {
+                break;
+            }
+        }
+    }
+    pb.finish();
+    if let Some(path) = &settings.checkpoint {
+        let checkpoint = Checkpoint {
+            settings_hash,
+            scene_hash,
+            round: next_round,
+            active,
+            film: film.clone(),
+        };
+        write_checkpoint(path, checkpoint);
+    }
+    film
 }
 fn main() {
-    // Image
-
+    let settings = match Settings::from_args(std::env::args().skip(1)) {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(address) = &settings.worker {
+        if let Err(err) = distributed::work(address) {
+            eprintln!(
+                "Couldn't render for the coordinator at `{}`: {}",
+                address, err
+            );
+            std::process::exit(1);
+        }
+        return;
+    }
     // World
-    // let r = (std::f64::consts::PI / 4.).cos();
-    let material_ground = Arc::new(Lambertian::new(Color::new(0.8, 0.8, 0.0)));
-    let material_center = Arc::new(Lambertian::new(Color::new(0.0, 0.0, 0.25)));
-    let material_left = Arc::new(Lambertian::new(Color::new(0.0, 0.0, 0.5)));
-    let material_right = Arc::new(Lambertian::new(Color::new(0.0, 0.0, 1.)));
+    let scene = match scene::from_settings(&settings, ASPECT_RATIO as f64) {
+        Some(scene) => scene,
+        None => {
+            eprintln!(
+                "Unknown scene `{}`, expected one of {:?}",
+                settings.scene,
+                scene::SCENE_NAMES
+            );
+            std::process::exit(1);
+        }
+    };
 
-    // let mut world = HittableList::new();
+    let mut integrator = match integrator::by_name(&settings.integrator, &settings) {
+        Some(integrator) => integrator,
+        None => {
+            eprintln!(
+                "Unknown integrator `{}`, expected one of {:?}",
+                settings.integrator,
+                integrator::INTEGRATOR_NAMES
+            );
+            std::process::exit(1);
+        }
+    };
 
-    // world.add(Box::new(Sphere::new(
-    //     Point3::new(0., -100.5, -1.),
-    //     100.,
-    //     material_ground,
-    // )));
-    // world.add(Box::new(Sphere::new(
-    //     Point3::new(0., 0., -1.),
-    //     0.5,
-    //     material_center,
-    // )));
-    // world.add(Box::new(Sphere::new(
-    //     Point3::new(-1., 0., -1.),
-    //     0.5,
-    //     material_left,
-    // )));
-    // world.add(Box::new(Sphere::new(
-    //     Point3::new(1., 0., -1.),
-    //     0.5,
-    //     material_right,
-    // )));
+    if let Some(max_samples) = settings.benchmark {
+        integrator.preprocess(&scene, 0);
+        let size = ((IMAGE_WIDTH / 8) as usize, (IMAGE_HEIGHT / 8) as usize);
+        let rows = benchmark::sampler_noise(
+            &scene,
+            integrator.as_ref(),
+            size,
+            max_samples,
+            settings.seed,
+        );
+        benchmark::print_sampler_noise(&rows);
+        return;
+    }
 
-    let world = random_scene();
-    // Camera
-    //let cam = Camera::default();
-    let origin = Point3::new(11., 2., 7.);
-    let lookat = Point3::new(0., 0., 0.);
-    let vup = Vec3::new(0., 1., 0.);
-    let vfov = 20.;
-    let dist_to_focus = 10.;
-    let aperture = 0.1;
+    let (width, height) = (IMAGE_WIDTH as usize, IMAGE_HEIGHT as usize);
+    if let Some((x, y)) = settings.debug_pixel {
+        if x >= width || y >= height {
+            eprintln!(
+                "The pixel {:?} is outside of the {}x{} image",
+                (x, y),
+                width,
+                height
+            );
+            std::process::exit(1);
+        }
+        if !integrator.records_paths() {
+            eprintln!(
+                "The `{}` integrator doesn't record the bounces of its paths, only their colors are written",
+                settings.integrator
+            );
+        }
+        integrator.preprocess(&scene, 0);
+        let paths =
+            path_dump::trace_pixel(&scene, integrator.as_ref(), &settings, (x, height - 1 - y));
+        let json = path_dump::to_json((x, y), &paths);
+        match &settings.path_dump {
+            Some(path) => {
+                if let Err(err) = std::fs::write(path, json) {
+                    eprintln!("Couldn't write the paths to `{}`: {}", path, err);
+                    std::process::exit(1);
+                }
+            }
+            None => print!("{}", json),
+        }
+        return;
+    }
+    if settings.noisy.is_some() && !settings.denoise {
+        eprintln!("`--noisy` writes the image before it is denoised, it needs `--denoise`");
+        std::process::exit(1);
+    }
+    if settings.aovs.is_empty() != settings.aov_output.is_none() {
+        eprintln!("`--aovs` and `--aov-output` go together");
+        std::process::exit(1);
+    }
+    if settings.coordinator.is_some() {
+        if let Some(flag) = settings.unsupported_by_coordinator() {
+            eprintln!("`{}` is not available with `--coordinator`", flag);
+            std::process::exit(1);
+        }
+        // The workers render tiles with `li`, which isn't the integrator's algorithm
+        if integrator.renders_image() {
+            eprintln!(
+                "The `{}` integrator renders the whole image, it is not available with `--coordinator`",
+                settings.integrator
+            );
+            std::process::exit(1);
+        }
+    }
+    if integrator.renders_image() {
+        if let Some(flag) = settings.unsupported_by_whole_image() {
+            eprintln!(
+                "`{}` is not available with the `{}` integrator, which renders the whole image",
+                flag, settings.integrator
+            );
+            std::process::exit(1);
+        }
+    }
+    if !settings.aovs.is_empty() && !integrator.records_aovs() {
+        eprintln!(
+            "AOVs are not available with the `{}` integrator",
+            settings.integrator
+        );
+        std::process::exit(1);
+    }
+    // The region of the image that is rendered
+    let region = match settings.crop {
+        Some(crop) => match Tile::crop(crop, width, height) {
+            Some(region) => region,
+            None => {
+                eprintln!(
+                    "The crop window {:?} doesn't fit in the {}x{} image",
+                    crop, width, height
+                );
+                std::process::exit(1);
+            }
+        },
+        None => Tile {
+            i: 0..width,
+            j: 0..height,
+        },
+    };
 
-    let cam = Camera::new(
-        ASPECT_RATIO as f64,
-        vfov,
-        aperture,
-        dist_to_focus,
-        origin,
-        lookat,
-        vup,
-    );
+    let resume = if settings.resume {
+        let path = match &settings.checkpoint {
+            Some(path) => path,
+            None => {
+                eprintln!("`--resume` needs the path given with `--checkpoint`");
+                std::process::exit(1);
+            }
+        };
+        let checkpoint = Checkpoint::load(path).map_err(|err| err.to_string());
+        let checkpoint = checkpoint.and_then(|checkpoint| {
+            checkpoint
+                .check(&settings, &scene, width, height)
+                .map(|_| checkpoint)
+        });
+        match checkpoint {
+            Ok(checkpoint) => Some(checkpoint),
+            Err(err) => {
+                eprintln!("Refusing to resume from `{}`: {}", path, err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
 
     // Render
+    let (image, counts, aovs) = if let Some(address) = &settings.coordinator {
+        let film = TcpListener::bind(address)
+            .and_then(|listener| distributed::coordinate(listener, &scene, &settings, &region));
+        match film {
+            Ok(film) => (film.image(), film.counts(), film.aovs),
+            Err(err) => {
+                eprintln!("Couldn't coordinate the workers on `{}`: {}", address, err);
+                std::process::exit(1);
+            }
+        }
+    } else {
+        match integrator.render(&scene, width, height, settings.samples) {
+            Some(image) => {
+                let counts = vec![settings.samples; image.len()];
+                let image = image
+                    .into_iter()
+                    .map(|color| color / settings.samples as f64)
+                    .collect();
+                (image, counts, AovBuffers::default())
+            }
+            None => {
+                let film = render_pixels(&scene, integrator.as_mut(), &settings, &region, resume);
+                (film.image(), film.counts(), film.aovs)
+            }
+        }
+    };
+    let aux = if settings.denoise {
+        Some(denoise::aux_buffers(&scene, &settings, &region, width))
+    } else {
+        None
+    };
+    let aov_images: Vec<_> = aovs
+        .images(&counts)
+        .iter()
+        .map(|aov_image| region.extract(aov_image, width))
+        .collect();
+    let image = region.extract(&image, width);
+    let counts = region.extract(&counts, width);
+    // The lens effects are centered on the whole frame, not on the crop window
+    let frame = (width, height);
+    let (width, height) = (region.i.len(), region.j.len());
 
-    print!("P3\n{} {}\n255\n", IMAGE_WIDTH, IMAGE_HEIGHT);
-    let pb = ProgressBar::new(IMAGE_HEIGHT as u64);
-    for j in (0..IMAGE_HEIGHT).rev() {
-        pb.inc(1);
-        for i in 0..IMAGE_WIDTH {
-            // let mut pixel_color = Color::zero();
-            // for _ in 0..SAMPLES_PER_PIXEL {
-            //     let u = (i as f64 + rand::random::<f64>()) / (IMAGE_WIDTH - 1) as f64;
-            //     let v = (j as f64 + rand::random::<f64>()) / (IMAGE_HEIGHT - 1) as f64;
-            //     let r = cam.get_ray(u, v);
-            //     pixel_color += ray_color(&r, &world, MAX_DEPTH);
-            // }
-            let pixel_color = (0..SAMPLES_PER_PIXEL)
-                .into_par_iter()
-                .map(|_| shoot_ray(i, j, &world, &cam))
-                .sum();
+    if let Some(path) = &settings.aov_output {
+        if !aovs.aovs.is_empty() {
+            let written = aov::write_aovs(path, &aovs.aovs, &aov_images, &image, width, height);
+            if let Err(err) = written {
+                eprintln!("Couldn't write the AOVs to `{}`: {}", path, err);
+            }
+        }
+    }
 
-            write_color(&mut io::stdout(), pixel_color, SAMPLES_PER_PIXEL);
+    if let Some(path) = &settings.heatmap {
+        let written = File::create(path).and_then(|mut file| {
+            adaptive::write_heatmap(&mut file, &counts, width, height, settings.samples)
+        });
+        if let Err(err) = written {
+            eprintln!("Couldn't write the heatmap to `{}`: {}", path, err);
         }
     }
+
+    let post = PostProcess::from_settings(&settings);
+    let image = match &aux {
+        Some(aux) => {
+            if let Some(path) = &settings.noisy {
+                let written = File::create(path).map(|mut file| {
+                    let noisy = post.region_image(&image, &region, frame);
+                    write_ppm(&mut file, &noisy, width, height)
+                });
+                if let Err(err) = written {
+                    eprintln!("Couldn't write the noisy image to `{}`: {}", path, err);
+                }
+            }
+            denoise::denoise(&image, aux, width, height)
+        }
+        None => image,
+    };
+    write_ppm(
+        &mut io::stdout(),
+        &post.region_image(&image, &region, frame),
+        width,
+        height,
+    );
 }
@@ -1,11 +1,100 @@
 use crate::hittable::HitRecord;
+use crate::onb::Onb;
 use crate::ray::Ray;
-use crate::vec3::{random_in_unit_sphere, random_unit_in_unit_sphere, Color, Vec3};
+use crate::sampler::Sampler;
+use crate::vec3::{random_cosine_direction, random_in_unit_sphere, Color, Vec3};
+use std::f64::consts::PI;
+
+/// A direction sampled from a BSDF
+#[derive(Debug, Clone, Copy)]
+pub struct BsdfSample {
+    /// The incoming direction, in the local shading frame
+    pub wi: Vec3,
+    /// The value of the BSDF for the sampled direction.
+    /// For delta lobes it already includes the 1/cos(wi) factor
+    pub f: Color,
+    /// The solid angle density of `wi`.
+    /// For delta lobes it is the probability of having picked the lobe
+    pub pdf: f64,
+    /// True if `wi` was sampled from a delta (perfectly specular) lobe
+    pub delta: bool,
+    /// True if `wi` was sampled from a specular lobe, mirror-like or glossy, and false
+    /// if it was sampled from a diffuse one. It splits the light between the AOVs.
+    pub specular: bool,
+}
+
+impl BsdfSample {
+    /// How much the sample attenuates the light, f * |cos(wi)| / pdf
+    pub fn weight(&self) -> Color {
+        self.f * self.wi.z().abs() / self.pdf
+    }
+}
 
 /// Trait for Materials
+///
+/// The BSDF methods work in a local shading frame where the normal of the hit
+/// is the z axis. `wo` points towards the viewer and `wi` towards the light,
+/// and both are unit vectors.
 pub trait Material: Sync + Send {
+    /// Name of the kind of material, for debugging
+    fn name(&self) -> &'static str;
+    /// Parameters of the material, that tell it apart from the other materials of its
+    /// kind in the fingerprint of a scene
+    fn fingerprint(&self) -> Vec<f64>;
+    /// Value of the BSDF f(wo, wi). Delta lobes are not included.
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color;
+    /// Samples an incoming direction given the outgoing one
+    fn sample(&self, wo: &Vec3, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<BsdfSample>;
+    /// Density of sampling `wi` given `wo`. Delta lobes are not included.
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64;
+    /// True if the material only has delta lobes, so eval and pdf are always 0
+    fn is_delta(&self) -> bool {
+        false
+    }
+    /// True if the way light scatters depends on its wavelength, so that spectral
+    /// rendering must follow a single wavelength after the hit
+    fn is_dispersive(&self) -> bool {
+        false
+    }
+    /// Light emitted by the surface towards the viewer
+    fn emitted(&self, _rec: &HitRecord) -> Color {
+        Color::zero()
+    }
+    /// True if the material emits light
+    fn is_emissive(&self) -> bool {
+        false
+    }
+    /// Color of the surface, that the denoiser keeps apart from the lighting.
+    /// Glass and lights have none, so it is white.
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        Color::one()
+    }
+    /// True if the surface reflects mostly like a mirror, and false if it is mostly
+    /// diffuse. The AOVs only use it when no lobe could be sampled at the first hit.
+    fn is_specular(&self) -> bool {
+        self.is_delta()
+    }
+    /// How much the light is attenuated while travelling from the origin of `r_in` to the hit,
+    /// for materials that enclose a medium
+    fn transmittance(&self, _r_in: &Ray, _rec: &HitRecord) -> Color {
+        Color::one()
+    }
     /// Produce a scattered ray and how much the ray should be attenuated
-    fn scatter(&self, r: &Ray, rec: &HitRecord) -> Option<(Color, Ray)>;
+    fn scatter(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Color, Ray)> {
+        let onb = Onb::build_from_w(&rec.normal);
+        let wo = onb.to_local(&-r_in.dir().unit_vector());
+        let sample = self.sample(&wo, rec, sampler)?;
+        if sample.pdf <= 0. {
+            return None;
+        }
+        let attenuation = self.transmittance(r_in, rec) * sample.weight();
+        Some((attenuation, Ray::new(rec.point, onb.local(&sample.wi))))
+    }
 }
 
 impl std::fmt::Debug for dyn Material {
@@ -14,6 +103,11 @@ impl std::fmt::Debug for dyn Material {
     }
 }
 
+/// Mirrors the local direction `w` about the normal
+fn reflect_local(w: &Vec3) -> Vec3 {
+    Vec3::new(-w.x(), -w.y(), w.z())
+}
+
 #[derive(Debug)]
 pub struct Lambertian {
     albedo: Color,
@@ -24,17 +118,33 @@ impl Lambertian {
     }
 }
 impl Material for Lambertian {
-    fn scatter(&self, _r_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
-        let mut scatter_direction = rec.normal + random_unit_in_unit_sphere();
-
-        // catch degenerate scatter dir
-        if scatter_direction.near_zero() {
-            scatter_direction = rec.normal;
+    fn name(&self) -> &'static str {
+        "lambertian"
+    }
+    fn fingerprint(&self) -> Vec<f64> {
+        vec![self.albedo.x(), self.albedo.y(), self.albedo.z()]
+    }
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        self.albedo
+    }
+    fn eval(&self, wo: &Vec3, wi: &Vec3, _rec: &HitRecord) -> Color {
+        if wo.z() <= 0. || wi.z() <= 0. {
+            return Color::zero();
         }
-
-        let scattered = Ray::new(rec.point, scatter_direction);
-        let atteuation = self.albedo;
-        Some((atteuation, scattered))
+        self.albedo / PI
+    }
+    fn sample(&self, wo: &Vec3, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        let wi = random_cosine_direction(sampler);
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, &wi, rec),
+            pdf: self.pdf(wo, &wi, rec),
+            delta: false,
+            specular: false,
+        })
+    }
+    fn pdf(&self, _wo: &Vec3, wi: &Vec3, _rec: &HitRecord) -> f64 {
+        wi.z().max(0.) / PI
     }
 }
 
@@ -51,27 +161,169 @@ impl Metal {
         }
     }
 }
+impl Metal {
+    /// Density of the fuzzy reflection: the reflected direction is offset by a
+    /// point picked uniformly in a ball of radius `fuzz`, so the density of `wi`
+    /// is the integral of t^2 over the chord of the ball along `wi`
+    fn fuzz_pdf(&self, wo: &Vec3, wi: &Vec3) -> f64 {
+        let center = reflect_local(wo);
+        let b = wi.dot(&center);
+        let delta = b * b - 1. + self.fuzz * self.fuzz;
+        if delta <= 0. {
+            return 0.;
+        }
+        let t_far = b + delta.sqrt();
+        if t_far <= 0. {
+            return 0.;
+        }
+        let t_near = (b - delta.sqrt()).max(0.);
+        (t_far.powi(3) - t_near.powi(3)) / (4. * PI * self.fuzz.powi(3))
+    }
+}
 impl Material for Metal {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
-        let reflected: Vec3 = r_in.dir().reflect(&rec.normal);
+    fn name(&self) -> &'static str {
+        "metal"
+    }
+    fn fingerprint(&self) -> Vec<f64> {
+        let mut parameters = vec![self.albedo.x(), self.albedo.y(), self.albedo.z()];
+        parameters.push(self.fuzz);
+        parameters
+    }
+    fn is_specular(&self) -> bool {
+        true
+    }
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        self.albedo
+    }
+    fn eval(&self, wo: &Vec3, wi: &Vec3, _rec: &HitRecord) -> Color {
+        if self.is_delta() || wo.z() <= 0. || wi.z() <= 0. {
+            return Color::zero();
+        }
+        // Chosen so that f * cos / pdf is the albedo
+        self.albedo * self.fuzz_pdf(wo, wi) / wi.z()
+    }
+    fn sample(&self, wo: &Vec3, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        let reflected = reflect_local(wo);
+        if self.is_delta() {
+            return Some(BsdfSample {
+                wi: reflected,
+                f: self.albedo / reflected.z(),
+                pdf: 1.,
+                delta: true,
+                specular: true,
+            });
+        }
+        let scattered = reflected + self.fuzz * random_in_unit_sphere(sampler);
+        // Directions below the surface are absorbed
+        if scattered.z() <= 0. {
+            return None;
+        }
+        let wi = scattered.unit_vector();
+        Some(BsdfSample {
+            wi,
+            f: self.eval(wo, &wi, rec),
+            pdf: self.pdf(wo, &wi, rec),
+            delta: false,
+            specular: true,
+        })
+    }
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, _rec: &HitRecord) -> f64 {
+        if self.is_delta() || wi.z() <= 0. {
+            return 0.;
+        }
+        self.fuzz_pdf(wo, wi)
+    }
+    fn is_delta(&self) -> bool {
+        self.fuzz == 0.
+    }
+}
 
-        let scattered: Ray = Ray::new(rec.point, reflected + self.fuzz * random_in_unit_sphere());
-        let atteuation: Color = self.albedo;
-        if scattered.dir().dot(&rec.normal) > 0. {
-            Some((atteuation, scattered))
-        } else {
-            None
+/// Index of refraction of a dielectric, possibly varying with the wavelength
+#[derive(Debug, Clone, Copy)]
+pub enum Ior {
+    Constant(f64),
+    /// Cauchy's equation: n = a + b / λ², with λ in micrometers
+    Cauchy {
+        a: f64,
+        b: f64,
+    },
+    /// Sellmeier's equation: n² = 1 + Σ b λ² / (λ² - c), with λ in micrometers
+    Sellmeier {
+        b: [f64; 3],
+        c: [f64; 3],
+    },
+}
+
+impl Ior {
+    /// Borosilicate crown glass, the usual optical glass
+    pub const BK7: Ior = Ior::Sellmeier {
+        b: [1.03961212, 0.231792344, 1.01046945],
+        c: [0.00600069867, 0.0200179144, 103.560653],
+    };
+    /// Dense flint glass, which disperses light much more than BK7
+    pub const SF11: Ior = Ior::Sellmeier {
+        b: [1.73759695, 0.313747346, 1.89878101],
+        c: [0.013188707, 0.0623068142, 155.23629],
+    };
+
+    /// Index of refraction at a wavelength in nanometers.
+    /// Without wavelength it's the one of the yellow helium line, at 587.6 nm.
+    pub fn at(&self, wavelength: Option<f64>) -> f64 {
+        let lambda = wavelength.unwrap_or(587.6) / 1000.;
+        match self {
+            Ior::Constant(n) => *n,
+            Ior::Cauchy { a, b } => a + b / (lambda * lambda),
+            Ior::Sellmeier { b, c } => {
+                let lambda2 = lambda * lambda;
+                let sum: f64 = (0..3).map(|i| b[i] * lambda2 / (lambda2 - c[i])).sum();
+                (1. + sum).sqrt()
+            }
+        }
+    }
+    /// The kind of equation, then its coefficients
+    fn parameters(&self) -> Vec<f64> {
+        match self {
+            Ior::Constant(n) => vec![0., *n],
+            Ior::Cauchy { a, b } => vec![1., *a, *b],
+            Ior::Sellmeier { b, c } => [2.].iter().chain(b).chain(c).cloned().collect(),
         }
     }
+    /// True if the index of refraction depends on the wavelength
+    pub fn is_dispersive(&self) -> bool {
+        !matches!(self, Ior::Constant(_))
+    }
 }
 
 pub struct Dielectric {
-    ir: f64, // Index of refraction
+    ir: Ior,           // Index of refraction
+    absorption: Color, // Absorption coefficient per unit of distance
 }
 
 impl Dielectric {
     pub fn new(ir: f64) -> Self {
-        Self { ir }
+        Self::dispersive(Ior::Constant(ir))
+    }
+    /// Glass whose index of refraction depends on the wavelength, which splits
+    /// white light into its colors when rendering spectrally
+    pub fn dispersive(ir: Ior) -> Self {
+        Self {
+            ir,
+            absorption: Color::zero(),
+        }
+    }
+    /// Colored glass that absorbs light following the Beer–Lambert law.
+    /// # Arguments
+    /// * `ir` - The index of refraction
+    /// * `absorption` - How much of each channel is absorbed per unit of distance inside the medium
+    pub fn with_absorption(ir: f64, absorption: Color) -> Self {
+        Self {
+            ir: Ior::Constant(ir),
+            absorption,
+        }
+    }
+    /// Beer–Lambert transmittance for a ray that travelled `distance` inside the medium
+    fn transmittance_over(&self, distance: f64) -> Color {
+        (-distance * self.absorption).exp()
     }
     ///Shlick's approximation for reflectance
     fn reflectance(cosine: f64, ref_idx: f64) -> f64 {
@@ -82,27 +334,186 @@ impl Dielectric {
     }
 }
 impl Material for Dielectric {
-    fn scatter(&self, r_in: &Ray, rec: &HitRecord) -> Option<(Color, Ray)> {
-        let attenuation = Color::one();
-        let refraction_ratio = if rec.front_face {
-            1. / self.ir
-        } else {
-            self.ir
-        };
-
-        let unit_direction: Vec3 = r_in.dir().unit_vector();
+    fn name(&self) -> &'static str {
+        "dielectric"
+    }
+    fn fingerprint(&self) -> Vec<f64> {
+        let mut parameters = self.ir.parameters();
+        parameters.extend_from_slice(&[
+            self.absorption.x(),
+            self.absorption.y(),
+            self.absorption.z(),
+        ]);
+        parameters
+    }
+    fn eval(&self, _wo: &Vec3, _wi: &Vec3, _rec: &HitRecord) -> Color {
+        Color::zero()
+    }
+    fn sample(&self, wo: &Vec3, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        let ir = self.ir.at(rec.wavelength);
+        let refraction_ratio = if rec.front_face { 1. / ir } else { ir };
 
-        let cos_theta: f64 = -unit_direction.dot(&rec.normal).min(1.);
+        let cos_theta: f64 = wo.z().min(1.);
         let sin_theta: f64 = (1. - cos_theta * cos_theta).sqrt();
         let cannot_refract: bool = refraction_ratio * sin_theta > 1.;
-        let direction: Vec3 = if cannot_refract
-            || Dielectric::reflectance(cos_theta, refraction_ratio) > rand::random::<f64>()
-        {
-            unit_direction.reflect(&rec.normal)
+        let reflect_prob = if cannot_refract {
+            1.
         } else {
-            unit_direction.refract(&rec.normal, refraction_ratio)
+            Dielectric::reflectance(cos_theta, refraction_ratio)
         };
-        let scattered: Ray = Ray::new(rec.point, direction);
-        Some((attenuation, scattered))
+        if reflect_prob > sampler.next_1d() {
+            let wi = reflect_local(wo);
+            Some(BsdfSample {
+                wi,
+                f: Color::one() * reflect_prob / wi.z().abs(),
+                pdf: reflect_prob,
+                delta: true,
+                specular: true,
+            })
+        } else {
+            let wi = (-*wo).refract(&Vec3::new(0., 0., 1.), refraction_ratio);
+            Some(BsdfSample {
+                wi,
+                f: Color::one() * (1. - reflect_prob) / wi.z().abs(),
+                pdf: 1. - reflect_prob,
+                delta: true,
+                specular: true,
+            })
+        }
+    }
+    fn pdf(&self, _wo: &Vec3, _wi: &Vec3, _rec: &HitRecord) -> f64 {
+        0.
+    }
+    fn is_delta(&self) -> bool {
+        true
+    }
+    fn is_dispersive(&self) -> bool {
+        self.ir.is_dispersive()
+    }
+    fn transmittance(&self, r_in: &Ray, rec: &HitRecord) -> Color {
+        // Hitting the back face means the ray travelled inside the medium
+        // since its last hit (the entry point or an internal reflection).
+        if rec.front_face {
+            Color::one()
+        } else {
+            self.transmittance_over(rec.t * r_in.dir().norm())
+        }
+    }
+}
+
+/// A material that only emits light, from its front face
+#[derive(Debug)]
+pub struct DiffuseLight {
+    emit: Color,
+}
+impl DiffuseLight {
+    pub fn new(emit: Color) -> Self {
+        Self { emit }
+    }
+}
+impl Material for DiffuseLight {
+    fn name(&self) -> &'static str {
+        "diffuse_light"
+    }
+    fn fingerprint(&self) -> Vec<f64> {
+        vec![self.emit.x(), self.emit.y(), self.emit.z()]
+    }
+    fn eval(&self, _wo: &Vec3, _wi: &Vec3, _rec: &HitRecord) -> Color {
+        Color::zero()
+    }
+    fn sample(
+        &self,
+        _wo: &Vec3,
+        _rec: &HitRecord,
+        _sampler: &mut dyn Sampler,
+    ) -> Option<BsdfSample> {
+        None
+    }
+    fn pdf(&self, _wo: &Vec3, _wi: &Vec3, _rec: &HitRecord) -> f64 {
+        0.
+    }
+    fn emitted(&self, rec: &HitRecord) -> Color {
+        if rec.front_face {
+            self.emit
+        } else {
+            Color::zero()
+        }
+    }
+    fn is_emissive(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Seeded;
+    use crate::vec3::Point3;
+    use std::sync::Arc;
+
+    fn hit_record(material: Arc<dyn Material>, t: f64, front_face: bool) -> HitRecord {
+        HitRecord {
+            point: Point3::zero(),
+            normal: Vec3::new(0., 0., 1.),
+            material,
+            t,
+            u: 0.,
+            v: 0.,
+            front_face,
+            wavelength: None,
+        }
+    }
+
+    #[test]
+    fn dielectric_absorbs_inside() {
+        let glass = Arc::new(Dielectric::with_absorption(1.5, Color::new(0., 0.5, 1.)));
+        let r_in = Ray::new(Point3::new(0., 0., 2.), Vec3::new(0., 0., -1.));
+
+        // Entering the glass doesn't attenuate
+        let rec = hit_record(glass.clone(), 2., true);
+        let (attenuation, _) = glass.scatter(&r_in, &rec, &mut Seeded::new(0)).unwrap();
+        assert!((attenuation - Color::one()).near_zero());
+
+        // Leaving the glass after travelling 2 units
+        let rec = hit_record(glass.clone(), 2., false);
+        let (attenuation, _) = glass.scatter(&r_in, &rec, &mut Seeded::new(0)).unwrap();
+        assert!((attenuation - Color::new(1., (-1f64).exp(), (-2f64).exp())).near_zero());
+    }
+
+    #[test]
+    fn dielectric_clear_by_default() {
+        let glass = Arc::new(Dielectric::new(1.5));
+        let r_in = Ray::new(Point3::new(0., 0., 2.), Vec3::new(0., 0., -1.));
+        let rec = hit_record(glass.clone(), 10., false);
+        let (attenuation, _) = glass.scatter(&r_in, &rec, &mut Seeded::new(0)).unwrap();
+        assert!((attenuation - Color::one()).near_zero());
+    }
+
+    #[test]
+    fn sellmeier_ior() {
+        // The tabulated index of BK7 at the helium d line
+        assert!((Ior::BK7.at(None) - 1.5168).abs() < 1e-4);
+        assert!((Ior::BK7.at(Some(587.6)) - 1.5168).abs() < 1e-4);
+        // Blue light is bent more than red light
+        assert!(Ior::SF11.at(Some(450.)) > Ior::SF11.at(Some(650.)));
+        assert!(Ior::SF11.is_dispersive());
+        assert_eq!(Ior::Constant(1.5).at(Some(450.)), 1.5);
+        assert!(!Ior::Constant(1.5).is_dispersive());
+    }
+
+    #[test]
+    fn metal_fuzz_pdf_integrates_to_one() {
+        // Integrate the density over the whole sphere of directions
+        let metal = Metal::new(Color::one(), 0.7);
+        let wo = Vec3::new(0.4, 0., 0.9).unit_vector();
+        let n = 200000;
+        let mut total = 0.;
+        let mut sampler = Seeded::new(0);
+        for _ in 0..n {
+            let wi = random_in_unit_sphere(&mut sampler).unit_vector();
+            total += metal.fuzz_pdf(&wo, &wi) * 4. * PI;
+        }
+        let integral = total / n as f64;
+        assert!((integral - 1.).abs() < 0.05, "integral was {}", integral);
     }
 }
@@ -0,0 +1,347 @@
+use crate::color::luminance;
+use crate::film::{film_coordinates, film_pixel};
+use crate::integrator::{Integrator, PathTracer, Splat};
+use crate::ray::Ray;
+use crate::sampler::{hash, Sampler};
+use crate::scene::Scene;
+use crate::vec3::Color;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+
+/// A coordinate of the primary sample vector
+#[derive(Debug, Clone, Copy, Default)]
+struct PrimarySample {
+    value: f64,
+    /// Iteration of the last mutation
+    last_modification: u64,
+    /// State before the current mutation, to restore it on rejection
+    value_backup: f64,
+    modify_backup: u64,
+}
+
+/// Sampler of Primary Sample Space Metropolis light transport (Kelemen et al. 2002).
+/// The random numbers of a path are the coordinates of a point in [0, 1)^n,
+/// which is mutated from one iteration to the next. Coordinates are mutated lazily,
+/// when they are used, so paths can have any length.
+#[derive(Debug, Clone)]
+pub struct MltSampler {
+    rng: StdRng,
+    x: Vec<PrimarySample>,
+    sigma: f64,
+    large_step_probability: f64,
+    current_iteration: u64,
+    large_step: bool,
+    last_large_step_iteration: u64,
+    sample_index: usize,
+}
+
+impl MltSampler {
+    /// Creates a sampler whose first iteration is a large step, drawn from the seed
+    pub fn new(seed: u64, sigma: f64, large_step_probability: f64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            x: Vec::new(),
+            sigma,
+            large_step_probability,
+            current_iteration: 0,
+            large_step: true,
+            last_large_step_iteration: 0,
+            sample_index: 0,
+        }
+    }
+
+    /// Proposes a new point: a large step that draws every coordinate again
+    /// or a small step that perturbs them
+    pub fn start_iteration(&mut self) {
+        self.current_iteration += 1;
+        self.large_step = self.rng.gen::<f64>() < self.large_step_probability;
+        self.sample_index = 0;
+    }
+    /// Keeps the proposed point
+    pub fn accept(&mut self) {
+        if self.large_step {
+            self.last_large_step_iteration = self.current_iteration;
+        }
+    }
+    /// Goes back to the point before the proposal
+    pub fn reject(&mut self) {
+        for xi in self.x.iter_mut() {
+            if xi.last_modification == self.current_iteration {
+                xi.value = xi.value_backup;
+                xi.last_modification = xi.modify_backup;
+            }
+        }
+        self.current_iteration -= 1;
+    }
+
+    /// Brings a coordinate up to date with the current iteration
+    fn ensure_ready(&mut self, index: usize) {
+        // New coordinates start from a uniform value, as if drawn at the last large step
+        while index >= self.x.len() {
+            let value = self.rng.gen();
+            self.x.push(PrimarySample {
+                value,
+                last_modification: self.last_large_step_iteration,
+                ..PrimarySample::default()
+            });
+        }
+        let xi = &mut self.x[index];
+        // Coordinates unused since the last large step start from a fresh value
+        if xi.last_modification < self.last_large_step_iteration {
+            xi.value = self.rng.gen();
+            xi.last_modification = self.last_large_step_iteration;
+        }
+
+        xi.value_backup = xi.value;
+        xi.modify_backup = xi.last_modification;
+        if self.large_step {
+            xi.value = self.rng.gen();
+        } else {
+            // Small steps missed while the coordinate was unused add up
+            let small_steps = (self.current_iteration - xi.last_modification) as f64;
+            let sigma = self.sigma * small_steps.sqrt();
+            xi.value += normal(&mut self.rng) * sigma;
+            xi.value -= xi.value.floor();
+        }
+        xi.last_modification = self.current_iteration;
+    }
+}
+
+impl Sampler for MltSampler {
+    fn next_1d(&mut self) -> f64 {
+        let index = self.sample_index;
+        self.sample_index += 1;
+        self.ensure_ready(index);
+        self.x[index].value
+    }
+}
+
+/// Standard normal random number, with the Box-Muller transform
+fn normal(rng: &mut StdRng) -> f64 {
+    let u1: f64 = 1. - rng.gen::<f64>();
+    let u2: f64 = rng.gen();
+    (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+}
+
+/// Primary Sample Space Metropolis light transport.
+/// Paths are generated by a path tracer whose random numbers, film position included,
+/// all come from an `MltSampler`. Markov chains mutate them, spending more samples
+/// on the paths that bring more light, which finds light through small openings
+/// and caustics seen in mirrors much more often than independent samples.
+/// The chains start from paths picked among `bootstrap` independent ones, which also
+/// give the normalization of the image.
+#[derive(Debug, Clone, Copy)]
+pub struct Mlt {
+    pub path: PathTracer,
+    /// Number of independent paths used to start the chains and normalize the image
+    pub bootstrap: usize,
+    /// Number of Markov chains
+    pub chains: usize,
+    /// Standard deviation of the small step mutations
+    pub sigma: f64,
+    /// Probability of a mutation being a large step
+    pub large_step_probability: f64,
+    /// Seed of the bootstrap paths and of the chains
+    pub seed: u64,
+}
+
+/// Number of images that the chains are split between. It doesn't depend on the number
+/// of threads, so that the sums of the samples, and the render, don't either.
+const IMAGES: usize = 16;
+
+impl Mlt {
+    /// Sampler of a bootstrap path, from which the chains starting at that path replay it
+    fn bootstrap_sampler(&self, index: usize) -> MltSampler {
+        let seed = hash(&[self.seed, index as u64]);
+        MltSampler::new(seed, self.sigma, self.large_step_probability)
+    }
+
+    /// Traces the path given by the primary samples, through a film of `size` pixels.
+    /// Returns its color and the film coordinates it goes through.
+    fn l(
+        &self,
+        scene: &Scene,
+        size: (usize, usize),
+        sampler: &mut MltSampler,
+    ) -> (Color, f64, f64) {
+        let (u, v) = sampler.next_2d();
+        let point = (u * size.0 as f64, v * size.1 as f64);
+        let (s, t) = film_coordinates(point, size);
+        let r = scene.camera.get_ray(s, t, sampler);
+        let color = self.path.li(&r, scene, sampler, &mut Vec::new());
+        if color.x().is_finite() && color.y().is_finite() && color.z().is_finite() {
+            (color, s, t)
+        } else {
+            (Color::zero(), s, t)
+        }
+    }
+
+    /// Runs a chain, adding its samples to `image`
+    #[allow(clippy::too_many_arguments)]
+    fn run_chain(
+        &self,
+        scene: &Scene,
+        chain: usize,
+        weights: &[f64],
+        mutations: usize,
+        width: usize,
+        height: usize,
+        image: &mut [Color],
+    ) {
+        // Pick the starting path among the bootstrap ones, proportionally to their luminance
+        let mut rng = StdRng::seed_from_u64(hash(&[self.seed, u64::MAX - chain as u64]));
+        let total: f64 = weights.iter().sum();
+        let mut target = rng.gen::<f64>() * total;
+        let mut start = weights.len() - 1;
+        for (index, weight) in weights.iter().enumerate() {
+            if target < *weight {
+                start = index;
+                break;
+            }
+            target -= weight;
+        }
+
+        // Replaying the bootstrap path puts the sampler in its state
+        let mut sampler = self.bootstrap_sampler(start);
+        let (mut current, mut s, mut t) = self.l(scene, (width, height), &mut sampler);
+        let splat = |color: Color, s: f64, t: f64, image: &mut [Color]| {
+            image[film_pixel((s, t), (width, height))] += color;
+        };
+        for _ in 0..mutations {
+            sampler.start_iteration();
+            let (proposed, proposed_s, proposed_t) = self.l(scene, (width, height), &mut sampler);
+            let current_luminance = luminance(&current);
+            let proposed_luminance = luminance(&proposed);
+            let accept = if current_luminance > 0. {
+                (proposed_luminance / current_luminance).min(1.)
+            } else {
+                1.
+            };
+
+            // Both states contribute, weighted by their probability of being the next one
+            if accept > 0. && proposed_luminance > 0. {
+                splat(
+                    proposed * (accept / proposed_luminance),
+                    proposed_s,
+                    proposed_t,
+                    image,
+                );
+            }
+            if accept < 1. {
+                splat(current * ((1. - accept) / current_luminance), s, t, image);
+            }
+
+            if rng.gen::<f64>() < accept {
+                current = proposed;
+                s = proposed_s;
+                t = proposed_t;
+                sampler.accept();
+            } else {
+                sampler.reject();
+            }
+        }
+    }
+}
+
+impl Integrator for Mlt {
+    /// Falls back to the path tracer for single rays
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        splats: &mut Vec<Splat>,
+    ) -> Color {
+        self.path.li(r, scene, sampler, splats)
+    }
+    fn renders_image(&self) -> bool {
+        true
+    }
+
+    fn render(
+        &self,
+        scene: &Scene,
+        width: usize,
+        height: usize,
+        samples_per_pixel: u32,
+    ) -> Option<Vec<Color>> {
+        // The luminance of independent paths. Each one is seeded by its index
+        // so that the chains can start from it.
+        let weights: Vec<f64> = (0..self.bootstrap)
+            .into_par_iter()
+            .map(|index| {
+                let mut sampler = self.bootstrap_sampler(index);
+                luminance(&self.l(scene, (width, height), &mut sampler).0).max(0.)
+            })
+            .collect();
+        let b = weights.iter().sum::<f64>() / self.bootstrap as f64;
+        let pixels = width * height;
+        if b <= 0. {
+            return Some(vec![Color::zero(); pixels]);
+        }
+
+        let total_mutations = pixels * samples_per_pixel as usize;
+        let chains = self.chains.min(total_mutations).max(1);
+        // Each image gets its share of the chains. They are added in order at the end.
+        let images = IMAGES.min(chains);
+        let images: Vec<Vec<Color>> = (0..images)
+            .into_par_iter()
+            .map(|first| {
+                let mut image = vec![Color::zero(); pixels];
+                for chain in (first..chains).step_by(images) {
+                    // Spread the mutations that don't divide evenly over the first chains
+                    let mutations =
+                        total_mutations / chains + (chain < total_mutations % chains) as usize;
+                    self.run_chain(scene, chain, &weights, mutations, width, height, &mut image);
+                }
+                image
+            })
+            .collect();
+        let mut image = vec![Color::zero(); pixels];
+        for other in images {
+            for (a, b) in image.iter_mut().zip(other) {
+                *a += b;
+            }
+        }
+        // The sample of a chain has an expected value of b over the whole image.
+        // With as many mutations as samples, scaling by b gives the sums of the samples of
+        // the pixels.
+        Some(image.into_iter().map(|color| color * b).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mlt_sampler_restores_rejected_samples() {
+        let mut sampler = MltSampler::new(7, 0.01, 0.3);
+        let first: Vec<f64> = (0..5).map(|_| sampler.next_1d()).collect();
+        assert!(first.iter().all(|u| (0. ..1.).contains(u)));
+
+        // Replaying the same seed gives the same path
+        let mut replay = MltSampler::new(7, 0.01, 0.3);
+        let again: Vec<f64> = (0..5).map(|_| replay.next_1d()).collect();
+        assert_eq!(first, again);
+
+        sampler.start_iteration();
+        let proposed: Vec<f64> = (0..5).map(|_| sampler.next_1d()).collect();
+        assert_ne!(first, proposed);
+        sampler.reject();
+        assert_eq!(sampler.x.iter().map(|x| x.value).collect::<Vec<_>>(), first);
+    }
+
+    #[test]
+    fn mlt_small_steps_stay_close() {
+        let mut sampler = MltSampler::new(3, 0.01, 0.);
+        let first = sampler.next_1d();
+        sampler.accept();
+        sampler.start_iteration();
+        let next = sampler.next_1d();
+        let distance = (next - first).abs();
+        // Wrapping around [0, 1) can bring the value to the other end
+        assert!(distance.min(1. - distance) < 0.1);
+    }
+}
@@ -0,0 +1,53 @@
+use crate::vec3::Vec3;
+
+/// An orthonormal basis.
+/// Used to move directions between world space and a local shading frame
+/// where `w` (the z axis) is the surface normal.
+#[derive(Debug, Clone, Copy)]
+pub struct Onb {
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+}
+
+impl Onb {
+    /// Builds a basis around the unit vector `n`.
+    /// Uses the branchless construction from Duff et al. 2017
+    pub fn build_from_w(n: &Vec3) -> Self {
+        let sign = 1f64.copysign(n.z());
+        let a = -1. / (sign + n.z());
+        let b = n.x() * n.y() * a;
+        let u = Vec3::new(1. + sign * n.x() * n.x() * a, sign * b, -sign * n.x());
+        let v = Vec3::new(b, sign + n.y() * n.y() * a, -n.y());
+        Self { u, v, w: *n }
+    }
+    /// Transforms a direction given in local coordinates to world space
+    pub fn local(&self, a: &Vec3) -> Vec3 {
+        a.x() * self.u + a.y() * self.v + a.z() * self.w
+    }
+    /// Transforms a world space direction to local coordinates
+    pub fn to_local(self, a: &Vec3) -> Vec3 {
+        Vec3::new(a.dot(&self.u), a.dot(&self.v), a.dot(&self.w))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn onb_orthonormal() {
+        for n in [
+            Vec3::new(0., 0., 1.),
+            Vec3::new(0., 0., -1.),
+            Vec3::new(1., 2., 3.).unit_vector(),
+        ] {
+            let onb = Onb::build_from_w(&n);
+            assert!(onb.u.dot(&onb.v).abs() < 1e-12);
+            assert!(onb.u.dot(&onb.w).abs() < 1e-12);
+            assert!((onb.u.norm() - 1.).abs() < 1e-12);
+            assert!((onb.v.norm() - 1.).abs() < 1e-12);
+            let a = Vec3::new(0.3, -0.2, 0.9);
+            assert!((onb.to_local(&onb.local(&a)) - a).near_zero());
+        }
+    }
+}
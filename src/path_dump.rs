@@ -0,0 +1,208 @@
+use crate::hittable::HitRecord;
+use crate::integrator::Integrator;
+use crate::scene::Scene;
+use crate::settings::Settings;
+use crate::vec3::{Color, Point3, Vec3};
+use std::cell::RefCell;
+use std::fmt::Write;
+
+/// A hit of a traced path
+#[derive(Debug, Clone)]
+pub struct Bounce {
+    pub point: Point3,
+    pub normal: Vec3,
+    pub material: &'static str,
+    pub emitted: Color,
+    /// Weight of the scattered ray: the BSDF times the cosine over the density
+    pub attenuation: Color,
+    /// Direction of the scattered ray, or None if the path stopped at the hit
+    pub direction: Option<Vec3>,
+    /// Density of the scattered direction
+    pub pdf: f64,
+}
+
+impl Bounce {
+    /// The bounce at a hit, with the direction, weight and density of the scattered ray
+    /// if the material scattered it
+    pub fn new(rec: &HitRecord, emitted: Color, scattered: Option<(Vec3, Color, f64)>) -> Self {
+        let (direction, attenuation, pdf) = match scattered {
+            Some((direction, attenuation, pdf)) => (Some(direction), attenuation, pdf),
+            None => (None, Color::zero(), 0.),
+        };
+        Self {
+            point: rec.point,
+            normal: rec.normal,
+            material: rec.material.name(),
+            emitted,
+            attenuation,
+            direction,
+            pdf,
+        }
+    }
+}
+
+/// A sample of the pixel, with the bounces of its path
+#[derive(Debug, Clone, Default)]
+pub struct Path {
+    pub color: Color,
+    pub bounces: Vec<Bounce>,
+    /// The background seen by the path when it left the scene
+    pub background: Option<Color>,
+}
+
+thread_local! {
+    /// The path being traced on this thread, when it is recorded
+    static RECORDER: RefCell<Option<Path>> = const { RefCell::new(None) };
+}
+
+/// Records a bounce of the path being traced, if it is recorded.
+/// Integrators call it at each hit; the bounce is only built when needed.
+pub fn record<F: FnOnce() -> Bounce>(bounce: F) {
+    RECORDER.with(|recorder| {
+        if let Some(path) = recorder.borrow_mut().as_mut() {
+            path.bounces.push(bounce());
+        }
+    });
+}
+
+/// Records that the path being traced left the scene and saw the background
+pub fn record_background(color: Color) {
+    RECORDER.with(|recorder| {
+        if let Some(path) = recorder.borrow_mut().as_mut() {
+            path.background = Some(color);
+        }
+    });
+}
+
+/// Takes the samples of the pixel `(i, j)`, counted from the bottom left corner like in
+/// `shoot_ray`, and returns their paths. Only the integrators that call `record` fill
+/// in the bounces.
+pub fn trace_pixel(
+    scene: &Scene,
+    integrator: &dyn Integrator,
+    settings: &Settings,
+    (i, j): (usize, usize),
+) -> Vec<Path> {
+    let pixel = (j * crate::IMAGE_WIDTH as usize + i) as u64;
+    let samples = settings.samples.max(1);
+    (0..samples)
+        .map(|index| {
+            let mut sampler = settings
+                .sampler
+                .pixel_sampler(settings.seed, pixel, index, samples);
+            RECORDER.with(|recorder| *recorder.borrow_mut() = Some(Path::default()));
+            let (du, dv) = sampler.next_2d();
+            let color = crate::shoot_ray(
+                (i as f64 + du, j as f64 + dv),
+                scene,
+                integrator,
+                sampler.as_mut(),
+                &mut Vec::new(),
+            );
+            let path = RECORDER.with(|recorder| recorder.borrow_mut().take());
+            Path {
+                color,
+                ..path.unwrap_or_default()
+            }
+        })
+        .collect()
+}
+
+/// JSON number, with the values that JSON can't represent, like the NaN of a broken
+/// path, written as strings
+fn number(x: f64) -> String {
+    if x.is_finite() {
+        format!("{}", x)
+    } else {
+        format!("\"{}\"", x)
+    }
+}
+fn vector(v: Vec3) -> String {
+    format!("[{}, {}, {}]", number(v.x()), number(v.y()), number(v.z()))
+}
+
+/// Writes the paths of the pixel `(x, y)`, counted from the top left corner, as JSON
+pub fn to_json((x, y): (usize, usize), paths: &[Path]) -> String {
+    let mut json = String::new();
+    writeln!(json, "{{\n  \"pixel\": [{}, {}],\n  \"paths\": [", x, y).unwrap();
+    for (index, path) in paths.iter().enumerate() {
+        writeln!(json, "    {{").unwrap();
+        writeln!(json, "      \"sample\": {},", index).unwrap();
+        writeln!(json, "      \"color\": {},", vector(path.color)).unwrap();
+        writeln!(json, "      \"bounces\": [").unwrap();
+        for (depth, bounce) in path.bounces.iter().enumerate() {
+            let direction = bounce.direction.map_or(String::from("null"), vector);
+            writeln!(
+                json,
+                "        {{\"point\": {}, \"normal\": {}, \"material\": \"{}\", \"emitted\": {}, \
+                 \"attenuation\": {}, \"direction\": {}, \"pdf\": {}}}{}",
+                vector(bounce.point),
+                vector(bounce.normal),
+                bounce.material,
+                vector(bounce.emitted),
+                vector(bounce.attenuation),
+                direction,
+                number(bounce.pdf),
+                if depth + 1 < path.bounces.len() {
+                    ","
+                } else {
+                    ""
+                }
+            )
+            .unwrap();
+        }
+        writeln!(json, "      ],").unwrap();
+        let background = path.background.map_or(String::from("null"), vector);
+        writeln!(json, "      \"background\": {}", background).unwrap();
+        let separator = if index + 1 < paths.len() { "," } else { "" };
+        writeln!(json, "    }}{}", separator).unwrap();
+    }
+    writeln!(json, "  ]\n}}").unwrap();
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::integrator::PathTracer;
+    use crate::scene;
+    use crate::settings::DirectLighting;
+
+    #[test]
+    fn pixel_paths() {
+        let scene = scene::simple_scene(crate::ASPECT_RATIO as f64);
+        let path_tracer = PathTracer {
+            max_depth: 4,
+            rr_depth: 10,
+            direct_lighting: DirectLighting::Mis,
+        };
+        let settings = Settings {
+            samples: 3,
+            ..Settings::default()
+        };
+        // The middle of the image sees the sphere in the middle of the simple scene
+        let middle = (
+            crate::IMAGE_WIDTH as usize / 2,
+            crate::IMAGE_HEIGHT as usize / 2,
+        );
+        let paths = trace_pixel(&scene, &path_tracer, &settings, middle);
+        assert_eq!(paths.len(), 3);
+        for path in &paths {
+            assert!(!path.bounces.is_empty() && path.bounces.len() <= 4);
+            let first = &path.bounces[0];
+            assert!((first.normal.norm() - 1.).abs() < 1e-9);
+            if let Some(direction) = first.direction {
+                assert!(direction.dot(&first.normal) > -1e-9 || first.material == "dielectric");
+            }
+        }
+        // Nothing is recorded outside of `trace_pixel`
+        record(|| unreachable!());
+
+        let json = to_json((1, 2), &paths[..1]);
+        assert!(json.starts_with("{\n  \"pixel\": [1, 2],"));
+        assert!(json.contains("\"material\": \""));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+        assert_eq!(json.matches('[').count(), json.matches(']').count());
+        assert_eq!(number(f64::NAN), "\"NaN\"");
+    }
+}
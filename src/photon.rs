@@ -0,0 +1,550 @@
+use crate::hittable::{HitRecord, Hittable};
+use crate::integrator::{sample_lights, Integrator, Splat};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::{hash, Sampler, Seeded};
+use crate::scene::Scene;
+use crate::vec3::{random_cosine_direction, Color, Point3, Vec3};
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::f64::consts::PI;
+
+/// A packet of light that landed on a non-specular surface
+#[derive(Debug, Clone, Copy)]
+pub struct Photon {
+    pub point: Point3,
+    /// Direction the photon came from
+    pub wi: Vec3,
+    pub power: Color,
+}
+
+/// Photons stored in a balanced kd-tree.
+/// The tree is implicit: the node of a range of photons is the one in its middle,
+/// with the photons before it on one side of its splitting plane and the photons
+/// after it on the other side.
+#[derive(Debug, Default)]
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    /// Axis of the splitting plane of each node
+    axes: Vec<usize>,
+}
+
+/// A photon found by a nearest neighbours query, ordered by distance
+struct Neighbour {
+    distance_squared: f64,
+    index: usize,
+}
+
+impl PartialEq for Neighbour {
+    fn eq(&self, other: &Self) -> bool {
+        self.distance_squared == other.distance_squared
+    }
+}
+impl Eq for Neighbour {}
+impl PartialOrd for Neighbour {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Neighbour {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance_squared.total_cmp(&other.distance_squared)
+    }
+}
+
+impl PhotonMap {
+    /// Builds the kd-tree of the photons
+    pub fn new(mut photons: Vec<Photon>) -> Self {
+        let mut axes = vec![0; photons.len()];
+        build(&mut photons, &mut axes);
+        Self { photons, axes }
+    }
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    /// Returns the photons closer than `radius` to `point`
+    pub fn within(&self, point: &Point3, radius: f64) -> Vec<&Photon> {
+        let mut found = Vec::new();
+        self.within_range(0, self.photons.len(), point, radius * radius, &mut found);
+        found
+    }
+    fn within_range<'a>(
+        &'a self,
+        lo: usize,
+        hi: usize,
+        point: &Point3,
+        radius_squared: f64,
+        found: &mut Vec<&'a Photon>,
+    ) {
+        if lo >= hi {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let photon = &self.photons[mid];
+        let delta = point[self.axes[mid]] - photon.point[self.axes[mid]];
+        if delta <= 0. || delta * delta < radius_squared {
+            self.within_range(lo, mid, point, radius_squared, found);
+        }
+        if delta >= 0. || delta * delta < radius_squared {
+            self.within_range(mid + 1, hi, point, radius_squared, found);
+        }
+        if (photon.point - *point).norm_squared() < radius_squared {
+            found.push(photon);
+        }
+    }
+
+    /// Returns the (at most) `k` photons closest to `point` among the ones closer than
+    /// `max_radius`, with the squared radius of the smallest sphere around `point`
+    /// that contains them all, or `max_radius` squared if less than `k` were found
+    pub fn nearest(&self, point: &Point3, k: usize, max_radius: f64) -> (Vec<&Photon>, f64) {
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+        let mut radius_squared = max_radius * max_radius;
+        self.nearest_range(
+            0,
+            self.photons.len(),
+            point,
+            k,
+            &mut heap,
+            &mut radius_squared,
+        );
+        let photons = heap
+            .into_iter()
+            .map(|neighbour| &self.photons[neighbour.index])
+            .collect();
+        (photons, radius_squared)
+    }
+    fn nearest_range(
+        &self,
+        lo: usize,
+        hi: usize,
+        point: &Point3,
+        k: usize,
+        heap: &mut BinaryHeap<Neighbour>,
+        radius_squared: &mut f64,
+    ) {
+        if lo >= hi || k == 0 {
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        let photon = &self.photons[mid];
+        let delta = point[self.axes[mid]] - photon.point[self.axes[mid]];
+        let (near, far) = if delta <= 0. {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.nearest_range(near.0, near.1, point, k, heap, radius_squared);
+
+        let distance_squared = (photon.point - *point).norm_squared();
+        if distance_squared < *radius_squared {
+            heap.push(Neighbour {
+                distance_squared,
+                index: mid,
+            });
+            if heap.len() > k {
+                heap.pop();
+            }
+            if heap.len() == k {
+                *radius_squared = heap.peek().unwrap().distance_squared;
+            }
+        }
+
+        // The other side can only have closer photons if the splitting plane is close enough
+        if delta * delta < *radius_squared {
+            self.nearest_range(far.0, far.1, point, k, heap, radius_squared);
+        }
+    }
+}
+
+/// Reorders the photons into a kd-tree, splitting along the axis where they spread the most
+fn build(photons: &mut [Photon], axes: &mut [usize]) {
+    if photons.len() <= 1 {
+        return;
+    }
+    let mut min = photons[0].point;
+    let mut max = photons[0].point;
+    for photon in photons.iter() {
+        let p = photon.point;
+        min = Point3::new(min.x().min(p.x()), min.y().min(p.y()), min.z().min(p.z()));
+        max = Point3::new(max.x().max(p.x()), max.y().max(p.y()), max.z().max(p.z()));
+    }
+    let extent = max - min;
+    let axis = if extent.x() > extent.y() && extent.x() > extent.z() {
+        0
+    } else if extent.y() > extent.z() {
+        1
+    } else {
+        2
+    };
+
+    let mid = photons.len() / 2;
+    photons.select_nth_unstable_by(mid, |a, b| a.point[axis].total_cmp(&b.point[axis]));
+    axes[mid] = axis;
+    let (left, right) = photons.split_at_mut(mid);
+    let (left_axes, right_axes) = axes.split_at_mut(mid);
+    build(left, left_axes);
+    build(&mut right[1..], &mut right_axes[1..]);
+}
+
+/// The photons shot from the lights, in two maps.
+/// Caustic photons only bounced on specular (delta) surfaces before landing,
+/// the global map has all the photons, caustic ones included.
+/// Photons are only stored after their first bounce: direct lighting is computed
+/// with light sampling instead.
+#[derive(Debug, Default)]
+pub struct PhotonMaps {
+    pub caustics: PhotonMap,
+    pub global: PhotonMap,
+}
+
+impl PhotonMaps {
+    /// Shoots `count` photons from the lights of the scene and stores where they land.
+    /// Each photon draws its random numbers from the seed and its index.
+    pub fn trace(scene: &Scene, count: usize, max_depth: u32, seed: u64) -> Self {
+        if scene.lights.is_empty() {
+            return Self::default();
+        }
+        let photons: Vec<(Photon, bool)> = (0..count)
+            .into_par_iter()
+            .flat_map(|index| {
+                let mut sampler = Seeded::from_keys(&[seed, index as u64]);
+                trace_photon(scene, count, max_depth, &mut sampler)
+            })
+            .collect();
+        let caustics = photons
+            .iter()
+            .filter(|(_, caustic)| *caustic)
+            .map(|(photon, _)| *photon)
+            .collect();
+        let global = photons.into_iter().map(|(photon, _)| photon).collect();
+        Self {
+            caustics: PhotonMap::new(caustics),
+            global: PhotonMap::new(global),
+        }
+    }
+}
+
+/// Traces a single photon path, out of `count`.
+/// Returns the photons it stored, with true for the caustic ones.
+fn trace_photon(
+    scene: &Scene,
+    count: usize,
+    max_depth: u32,
+    sampler: &mut dyn Sampler,
+) -> Vec<(Photon, bool)> {
+    let mut photons = Vec::new();
+    let (rec, pdf_pos) = match scene.lights.sample_surface(sampler) {
+        Some(sample) => sample,
+        None => return photons,
+    };
+    let local = random_cosine_direction(sampler);
+    if pdf_pos <= 0. || local.z() <= 0. {
+        return photons;
+    }
+    // Emitted radiance over the density of the position and the cosine weighted direction
+    let mut power = rec.material.emitted(&rec) * (PI / (pdf_pos * count as f64));
+    let mut ray = Ray::new(rec.point, Onb::build_from_w(&rec.normal).local(&local));
+    let mut specular = true;
+
+    for depth in 0..max_depth {
+        let rec = match scene.world.hit(&ray, 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => break,
+        };
+        power *= rec.material.transmittance(&ray, &rec);
+        let wi = -ray.dir().unit_vector();
+        if !rec.material.is_delta() {
+            if depth > 0 {
+                let photon = Photon {
+                    point: rec.point,
+                    wi,
+                    power,
+                };
+                photons.push((photon, specular));
+            }
+            specular = false;
+        }
+
+        let onb = Onb::build_from_w(&rec.normal);
+        let sample = match rec.material.sample(&onb.to_local(&wi), &rec, sampler) {
+            Some(sample) if sample.pdf > 0. => sample,
+            _ => break,
+        };
+        // Russian roulette that keeps the power of the surviving photons about the same
+        let weight = sample.weight();
+        let survival = weight.x().max(weight.y()).max(weight.z()).min(1.);
+        if sampler.next_1d() >= survival {
+            break;
+        }
+        power *= weight / survival;
+        ray = Ray::new(rec.point, onb.local(&sample.wi));
+    }
+    photons
+}
+
+/// Follows a camera ray through specular (delta) surfaces up to a first other hit.
+/// There, the direct light is computed with light sampling and caustics are
+/// estimated from the caustic map, then a final gather bounce follows the BSDF,
+/// through specular surfaces again, to a second hit where the light is the direct
+/// light plus the estimate from the global map.
+/// `gather` returns the density estimate of a photon map at a hit.
+fn trace_camera_ray(
+    r: &Ray,
+    scene: &Scene,
+    max_depth: u32,
+    maps: &PhotonMaps,
+    sampler: &mut dyn Sampler,
+    gather: impl Fn(&PhotonMap, &HitRecord, &Onb, &Vec3) -> Color,
+) -> Color {
+    let mut ray = *r;
+    let mut throughput = Color::one();
+    let mut color = Color::zero();
+    let mut final_gather = false;
+    for _ in 0..max_depth {
+        let rec = match scene.world.hit(&ray, 0.001, f64::INFINITY) {
+            Some(rec) => rec,
+            None => {
+                // The background doesn't shoot photons, so it's never in the maps
+                color += throughput * scene.background.color(&ray);
+                break;
+            }
+        };
+        throughput *= rec.material.transmittance(&ray, &rec);
+        // After the first non-specular hit, emitters are accounted for by light
+        // sampling and by the caustic map
+        if !final_gather {
+            color += throughput * rec.material.emitted(&rec);
+        }
+
+        let onb = Onb::build_from_w(&rec.normal);
+        let wo = onb.to_local(&-ray.dir().unit_vector());
+        if !rec.material.is_delta() {
+            if !scene.lights.is_empty() {
+                color += throughput * sample_lights(&rec, &onb, &wo, scene, sampler, false);
+            }
+            if final_gather {
+                color += throughput * gather(&maps.global, &rec, &onb, &wo);
+                break;
+            }
+            color += throughput * gather(&maps.caustics, &rec, &onb, &wo);
+            final_gather = true;
+        }
+
+        let sample = match rec.material.sample(&wo, &rec, sampler) {
+            Some(sample) if sample.pdf > 0. => sample,
+            _ => break,
+        };
+        throughput *= sample.weight();
+        ray = Ray::new(rec.point, onb.local(&sample.wi));
+    }
+    color
+}
+
+/// Density estimate of the light reflected towards `wo` by the photons in a disc
+/// of squared radius `radius_squared` around a hit
+fn estimate_radiance<'a>(
+    photons: impl Iterator<Item = &'a Photon>,
+    radius_squared: f64,
+    rec: &HitRecord,
+    onb: &Onb,
+    wo: &Vec3,
+) -> Color {
+    if radius_squared <= 0. {
+        return Color::zero();
+    }
+    let flux: Color = photons
+        .map(|photon| photon.power * rec.material.eval(wo, &onb.to_local(&photon.wi), rec))
+        .sum();
+    flux / (PI * radius_squared)
+}
+
+/// Two pass photon mapper (Jensen 1996).
+/// Photons are shot from the lights and stored where they land on non-specular
+/// surfaces. Caustics are estimated directly from the nearest caustic photons,
+/// the rest of the indirect light from the nearest photons of the global map,
+/// after a final gather bounce.
+#[derive(Debug)]
+pub struct PhotonMapper {
+    /// Number of photons shot from the lights
+    pub photons: usize,
+    /// Number of photons used by each density estimate
+    pub nearest: usize,
+    /// Largest distance at which photons are looked for
+    pub max_radius: f64,
+    pub max_depth: u32,
+    /// Seed of the photons
+    pub seed: u64,
+    pub maps: PhotonMaps,
+}
+
+impl Integrator for PhotonMapper {
+    fn preprocess(&mut self, scene: &Scene, _pass: u32) {
+        self.maps = PhotonMaps::trace(scene, self.photons, self.max_depth, self.seed);
+    }
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        _splats: &mut Vec<Splat>,
+    ) -> Color {
+        trace_camera_ray(
+            r,
+            scene,
+            self.max_depth,
+            &self.maps,
+            sampler,
+            |map, rec, onb, wo| {
+                if map.is_empty() {
+                    return Color::zero();
+                }
+                let (photons, radius_squared) =
+                    map.nearest(&rec.point, self.nearest, self.max_radius);
+                estimate_radiance(photons.into_iter(), radius_squared, rec, onb, wo)
+            },
+        )
+    }
+}
+
+/// Progressive photon mapper, in the probabilistic formulation of Knaus and Zwicker (2011).
+/// Each pass shoots a new set of photons and gathers them within a radius that
+/// shrinks from pass to pass, so that averaging the passes converges to the right image
+/// while the memory use stays the one of a single pass.
+#[derive(Debug)]
+pub struct Sppm {
+    /// Number of photons shot from the lights at each pass
+    pub photons: usize,
+    /// Number of passes
+    pub passes: u32,
+    /// Gather radius of the first pass
+    pub initial_radius: f64,
+    /// Fraction of the photons kept from one pass to the next, between 0 and 1
+    pub alpha: f64,
+    pub max_depth: u32,
+    /// Seed of the photons, which are different at each pass
+    pub seed: u64,
+    /// Gather radius of the current pass
+    pub radius: f64,
+    pub maps: PhotonMaps,
+}
+
+impl Sppm {
+    /// Gather radius of a pass: r(i+1)² = r(i)² (i + alpha) / (i + 1)
+    pub fn radius(&self, pass: u32) -> f64 {
+        let mut radius_squared = self.initial_radius * self.initial_radius;
+        for i in 0..pass {
+            radius_squared *= (i as f64 + self.alpha) / (i as f64 + 1.);
+        }
+        radius_squared.sqrt()
+    }
+}
+
+impl Integrator for Sppm {
+    fn passes(&self) -> u32 {
+        self.passes
+    }
+    fn preprocess(&mut self, scene: &Scene, pass: u32) {
+        self.radius = self.radius(pass);
+        let seed = hash(&[self.seed, pass as u64]);
+        self.maps = PhotonMaps::trace(scene, self.photons, self.max_depth, seed);
+    }
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        _splats: &mut Vec<Splat>,
+    ) -> Color {
+        trace_camera_ray(
+            r,
+            scene,
+            self.max_depth,
+            &self.maps,
+            sampler,
+            |map, rec, onb, wo| {
+                let photons = map.within(&rec.point, self.radius);
+                estimate_radiance(photons.into_iter(), self.radius * self.radius, rec, onb, wo)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene;
+
+    fn random_photons(count: usize) -> Vec<Photon> {
+        let mut sampler = Seeded::new(0);
+        (0..count)
+            .map(|_| Photon {
+                point: Point3::random_interval(-1., 1., &mut sampler),
+                wi: Vec3::new(0., 1., 0.),
+                power: Color::one(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn photon_map_queries_match_brute_force() {
+        let photons = random_photons(1000);
+        let map = PhotonMap::new(photons.clone());
+        assert_eq!(map.within(&Point3::zero(), 2.).len(), 1000);
+        let point = Point3::new(0.1, -0.2, 0.3);
+
+        let mut distances: Vec<f64> = photons
+            .iter()
+            .map(|photon| (photon.point - point).norm_squared())
+            .collect();
+        distances.sort_by(|a, b| a.total_cmp(b));
+
+        let within = map.within(&point, 0.3);
+        let expected = distances.iter().filter(|d| **d < 0.09).count();
+        assert_eq!(within.len(), expected);
+
+        let (nearest, radius_squared) = map.nearest(&point, 10, 2.);
+        assert_eq!(nearest.len(), 10);
+        assert_eq!(radius_squared, distances[9]);
+        for photon in nearest {
+            assert!((photon.point - point).norm_squared() <= distances[9]);
+        }
+
+        // Fewer photons than asked for within the radius
+        let (nearest, radius_squared) = map.nearest(&point, 10, 1e-3);
+        assert!(nearest.is_empty());
+        assert_eq!(radius_squared, 1e-6);
+    }
+
+    #[test]
+    fn sppm_radius_shrinks() {
+        let sppm = Sppm {
+            photons: 0,
+            passes: 4,
+            initial_radius: 0.5,
+            alpha: 2. / 3.,
+            max_depth: 5,
+            seed: 0,
+            radius: 0.5,
+            maps: PhotonMaps::default(),
+        };
+        assert_eq!(sppm.radius(0), 0.5);
+        assert!((sppm.radius(1) - 0.5 * (2f64 / 3.).sqrt()).abs() < 1e-12);
+        assert!(sppm.radius(3) < sppm.radius(2));
+    }
+
+    #[test]
+    fn photons_are_shot_from_the_lights() {
+        let scene = scene::lights_scene(1.);
+        let maps = PhotonMaps::trace(&scene, 2000, 5, 0);
+        assert!(!maps.global.is_empty());
+        // The glass sphere focuses some of the light
+        assert!(!maps.caustics.is_empty());
+        let everywhere = maps.global.within(&Point3::zero(), 1e3);
+        assert!(everywhere.iter().all(|photon| photon.power.x() >= 0.));
+        // Without lights there is nothing to shoot
+        let maps = PhotonMaps::trace(&scene::by_name("random", 1., 0).unwrap(), 10, 5, 0);
+        assert!(maps.global.is_empty() && maps.caustics.is_empty());
+    }
+}
@@ -0,0 +1,501 @@
+use crate::color::luminance;
+use crate::hittable::HitRecord;
+use crate::material::{BsdfSample, Material};
+use crate::sampler::Sampler;
+use crate::utils::clamp;
+use crate::vec3::{random_cosine_direction, Color, Vec3};
+use std::f64::consts::PI;
+
+/// A principled "uber" material based on the Disney BSDF (Burley 2012, 2015).
+/// It blends a diffuse base with sheen, a GGX specular layer, a clearcoat
+/// and rough dielectric transmission, all driven by artist friendly parameters in [0, 1].
+#[derive(Debug, Clone, Copy)]
+pub struct Principled {
+    pub base_color: Color,
+    /// 0 is a dielectric, 1 is a metal
+    pub metallic: f64,
+    pub roughness: f64,
+    /// Strength of the dielectric specular reflection (0.5 is ~4% reflectance)
+    pub specular: f64,
+    /// How much the dielectric specular is tinted towards the base color
+    pub specular_tint: f64,
+    /// Extra grazing reflection, useful for cloth
+    pub sheen: f64,
+    pub sheen_tint: f64,
+    /// Strength of a second, always white, specular lobe
+    pub clearcoat: f64,
+    /// 0 is a satin clearcoat, 1 is a glossy one
+    pub clearcoat_gloss: f64,
+    /// 0 is opaque, 1 is fully transmissive (glass like)
+    pub transmission: f64,
+    /// Index of refraction used by the transmission lobe
+    pub ior: f64,
+}
+
+impl Default for Principled {
+    fn default() -> Self {
+        Self {
+            base_color: Color::new(0.8, 0.8, 0.8),
+            metallic: 0.,
+            roughness: 0.5,
+            specular: 0.5,
+            specular_tint: 0.,
+            sheen: 0.,
+            sheen_tint: 0.5,
+            clearcoat: 0.,
+            clearcoat_gloss: 1.,
+            transmission: 0.,
+            ior: 1.5,
+        }
+    }
+}
+
+/// The lobes the BSDF is made of, in the order they are picked when sampling
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Lobe {
+    Diffuse,
+    Specular,
+    Clearcoat,
+    Transmission,
+}
+
+impl Principled {
+    /// Creates a principled material with the default parameters and the given base color
+    pub fn new(base_color: Color) -> Self {
+        Self {
+            base_color,
+            ..Self::default()
+        }
+    }
+
+    /// GGX roughness, clamped so that the lobes never become perfectly specular
+    fn alpha(&self) -> f64 {
+        (self.roughness * self.roughness).max(1e-3)
+    }
+    /// Weight of the diffuse (and sheen) lobe
+    fn diffuse_weight(&self) -> f64 {
+        (1. - self.metallic) * (1. - self.transmission)
+    }
+    /// Weight of the transmission lobe
+    fn transmission_weight(&self) -> f64 {
+        (1. - self.metallic) * self.transmission
+    }
+    /// Color of the base tint, the base color normalized by its luminance
+    fn tint(&self) -> Color {
+        let lum = luminance(&self.base_color);
+        if lum > 0. {
+            self.base_color / lum
+        } else {
+            Color::one()
+        }
+    }
+    /// Specular reflectance at normal incidence
+    fn specular_f0(&self) -> Color {
+        let dielectric =
+            0.08 * self.specular * lerp_color(Color::one(), self.tint(), self.specular_tint);
+        lerp_color(dielectric, self.base_color, self.metallic)
+    }
+
+    /// Probabilities of sampling each lobe given the outgoing direction
+    fn lobe_probabilities(&self, wo: &Vec3) -> [(Lobe, f64); 4] {
+        let diffuse = self.diffuse_weight();
+        let specular =
+            (1. - self.transmission_weight()) * luminance(&schlick(self.specular_f0(), wo.z()));
+        let clearcoat = 0.25 * self.clearcoat;
+        let transmission = self.transmission_weight();
+        let total = diffuse + specular + clearcoat + transmission;
+        if total <= 0. {
+            return [
+                (Lobe::Diffuse, 0.),
+                (Lobe::Specular, 0.),
+                (Lobe::Clearcoat, 0.),
+                (Lobe::Transmission, 0.),
+            ];
+        }
+        [
+            (Lobe::Diffuse, diffuse / total),
+            (Lobe::Specular, specular / total),
+            (Lobe::Clearcoat, clearcoat / total),
+            (Lobe::Transmission, transmission / total),
+        ]
+    }
+
+    /// Evaluates the BSDF for the local directions `wo` and `wi`.
+    /// `eta` is the ratio of the index of refraction on the far side of the surface over the near one.
+    fn bsdf(&self, wo: &Vec3, wi: &Vec3, eta: f64) -> Color {
+        if wo.z() <= 0. {
+            return Color::zero();
+        }
+        if wi.z() <= 0. {
+            return self.transmission_weight() * self.eval_refraction(wo, wi, eta);
+        }
+        let h = (*wo + *wi).unit_vector();
+        let mut f = Color::zero();
+
+        let diffuse = self.diffuse_weight();
+        if diffuse > 0. {
+            f += diffuse * self.eval_diffuse(wo, wi, &h);
+        }
+        let specular = 1. - self.transmission_weight();
+        if specular > 0. {
+            let alpha = self.alpha();
+            let fresnel = schlick(self.specular_f0(), wi.dot(&h));
+            let g = smith_g1(wo, alpha) * smith_g1(wi, alpha);
+            f += specular * fresnel * ggx_d(&h, alpha) * g / (4. * wo.z() * wi.z());
+        }
+        if self.clearcoat > 0. {
+            let alpha = lerp(0.1, 0.001, self.clearcoat_gloss);
+            let fresnel = schlick_scalar(0.04, wi.dot(&h));
+            let g = smith_g1(wo, 0.25) * smith_g1(wi, 0.25);
+            f += Color::one() * (0.25 * self.clearcoat * fresnel * gtr1_d(&h, alpha) * g)
+                / (4. * wo.z() * wi.z());
+        }
+        let transmission = self.transmission_weight();
+        if transmission > 0. {
+            let alpha = self.alpha();
+            let fresnel = fresnel_dielectric(wo.dot(&h), eta);
+            let g = smith_g1(wo, alpha) * smith_g1(wi, alpha);
+            f += Color::one()
+                * (transmission * fresnel * ggx_d(&h, alpha) * g / (4. * wo.z() * wi.z()));
+        }
+        f
+    }
+    /// Burley diffuse with retro-reflection plus the sheen lobe
+    fn eval_diffuse(&self, wo: &Vec3, wi: &Vec3, h: &Vec3) -> Color {
+        let cos_d = wi.dot(h);
+        let fl = schlick_weight(wi.z());
+        let fv = schlick_weight(wo.z());
+        let fd90 = 0.5 + 2. * self.roughness * cos_d * cos_d;
+        let diffuse = self.base_color / PI * (1. + (fd90 - 1.) * fl) * (1. + (fd90 - 1.) * fv);
+        let sheen = self.sheen
+            * lerp_color(Color::one(), self.tint(), self.sheen_tint)
+            * schlick_weight(cos_d);
+        diffuse + sheen
+    }
+    /// Rough dielectric refraction from Walter et al. 2007 for `wi` below the surface
+    fn eval_refraction(&self, wo: &Vec3, wi: &Vec3, eta: f64) -> Color {
+        let mut h = (*wo + eta * *wi).unit_vector();
+        if h.z() < 0. {
+            h = -h;
+        }
+        let wo_h = wo.dot(&h);
+        let wi_h = wi.dot(&h);
+        // Both directions must be on the correct side of the microfacet
+        if wo_h * wi_h >= 0. {
+            return Color::zero();
+        }
+        let alpha = self.alpha();
+        let fresnel = fresnel_dielectric(wo_h, eta);
+        let g = smith_g1(wo, alpha) * smith_g1(wi, alpha);
+        let denom = wo_h + eta * wi_h;
+        let value = (1. - fresnel) * ggx_d(&h, alpha) * g * (wi_h * wo_h).abs()
+            / ((wi.z() * wo.z()).abs() * denom * denom);
+        self.base_color * value
+    }
+
+    /// Probability density of sampling `wi` given `wo` (solid angle measure)
+    fn bsdf_pdf(&self, wo: &Vec3, wi: &Vec3, eta: f64) -> f64 {
+        if wo.z() <= 0. {
+            return 0.;
+        }
+        let alpha = self.alpha();
+        let mut pdf = 0.;
+        for (lobe, p) in self.lobe_probabilities(wo).iter() {
+            if *p <= 0. {
+                continue;
+            }
+            let lobe_pdf = match lobe {
+                Lobe::Diffuse => {
+                    if wi.z() > 0. {
+                        wi.z() / PI
+                    } else {
+                        0.
+                    }
+                }
+                Lobe::Specular | Lobe::Clearcoat => {
+                    if wi.z() <= 0. {
+                        0.
+                    } else {
+                        let h = (*wo + *wi).unit_vector();
+                        let d = if *lobe == Lobe::Specular {
+                            ggx_d(&h, alpha)
+                        } else {
+                            gtr1_d(&h, lerp(0.1, 0.001, self.clearcoat_gloss))
+                        };
+                        d * h.z() / (4. * wo.dot(&h))
+                    }
+                }
+                Lobe::Transmission => {
+                    if wi.z() > 0. {
+                        let h = (*wo + *wi).unit_vector();
+                        let fresnel = fresnel_dielectric(wo.dot(&h), eta);
+                        fresnel * ggx_d(&h, alpha) * h.z() / (4. * wo.dot(&h))
+                    } else {
+                        let mut h = (*wo + eta * *wi).unit_vector();
+                        if h.z() < 0. {
+                            h = -h;
+                        }
+                        let wo_h = wo.dot(&h);
+                        let wi_h = wi.dot(&h);
+                        if wo_h * wi_h >= 0. {
+                            0.
+                        } else {
+                            let fresnel = fresnel_dielectric(wo_h, eta);
+                            let denom = wo_h + eta * wi_h;
+                            let jacobian = eta * eta * wi_h.abs() / (denom * denom);
+                            (1. - fresnel) * ggx_d(&h, alpha) * h.z() * jacobian
+                        }
+                    }
+                }
+            };
+            pdf += p * lobe_pdf;
+        }
+        pdf
+    }
+
+    /// Picks a lobe and importance samples it, returning the local incoming direction and
+    /// the lobe
+    fn sample_direction(
+        &self,
+        wo: &Vec3,
+        eta: f64,
+        sampler: &mut dyn Sampler,
+    ) -> Option<(Vec3, Lobe)> {
+        let probabilities = self.lobe_probabilities(wo);
+        let mut u = sampler.next_1d();
+        let mut lobe = None;
+        for (l, p) in probabilities.iter() {
+            if *p > 0. && u < *p {
+                lobe = Some(*l);
+                break;
+            }
+            u -= p;
+        }
+        // Rounding errors might leave u slightly above the last probability
+        let lobe = lobe.or_else(|| {
+            probabilities
+                .iter()
+                .rev()
+                .find(|(_, p)| *p > 0.)
+                .map(|(l, _)| *l)
+        })?;
+
+        let wi = match lobe {
+            Lobe::Diffuse => random_cosine_direction(sampler),
+            Lobe::Specular => reflect(wo, &sample_ggx(self.alpha(), sampler))?,
+            Lobe::Clearcoat => reflect(
+                wo,
+                &sample_gtr1(lerp(0.1, 0.001, self.clearcoat_gloss), sampler),
+            )?,
+            Lobe::Transmission => {
+                let h = sample_ggx(self.alpha(), sampler);
+                if wo.dot(&h) <= 0. {
+                    return None;
+                }
+                let fresnel = fresnel_dielectric(wo.dot(&h), eta);
+                if sampler.next_1d() < fresnel {
+                    reflect(wo, &h)?
+                } else {
+                    // `refract` expects the incident direction pointing towards the surface
+                    let cos_i = wo.dot(&h);
+                    let sin2_t = (1. - cos_i * cos_i) / (eta * eta);
+                    if sin2_t >= 1. {
+                        return None;
+                    }
+                    (-*wo).refract(&h, 1. / eta)
+                }
+            }
+        };
+        Some((wi, lobe))
+    }
+}
+
+impl Material for Principled {
+    fn name(&self) -> &'static str {
+        "principled"
+    }
+    fn fingerprint(&self) -> Vec<f64> {
+        let mut parameters = vec![
+            self.base_color.x(),
+            self.base_color.y(),
+            self.base_color.z(),
+        ];
+        parameters.extend_from_slice(&[
+            self.metallic,
+            self.roughness,
+            self.specular,
+            self.specular_tint,
+            self.sheen,
+            self.sheen_tint,
+            self.clearcoat,
+            self.clearcoat_gloss,
+            self.transmission,
+            self.ior,
+        ]);
+        parameters
+    }
+    fn albedo(&self, _rec: &HitRecord) -> Color {
+        self.base_color
+    }
+    fn is_specular(&self) -> bool {
+        self.metallic + self.transmission > 0.5
+    }
+    fn eval(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> Color {
+        self.bsdf(wo, wi, relative_ior(self.ior, rec))
+    }
+    fn sample(&self, wo: &Vec3, rec: &HitRecord, sampler: &mut dyn Sampler) -> Option<BsdfSample> {
+        let eta = relative_ior(self.ior, rec);
+        let (wi, lobe) = self.sample_direction(wo, eta, sampler)?;
+        Some(BsdfSample {
+            wi,
+            f: self.bsdf(wo, &wi, eta),
+            pdf: self.bsdf_pdf(wo, &wi, eta),
+            delta: false,
+            specular: lobe != Lobe::Diffuse,
+        })
+    }
+    fn pdf(&self, wo: &Vec3, wi: &Vec3, rec: &HitRecord) -> f64 {
+        self.bsdf_pdf(wo, wi, relative_ior(self.ior, rec))
+    }
+}
+
+/// Index of refraction on the far side of the surface over the one on the near side
+fn relative_ior(ior: f64, rec: &HitRecord) -> f64 {
+    if rec.front_face {
+        ior
+    } else {
+        1. / ior
+    }
+}
+fn lerp(a: f64, b: f64, t: f64) -> f64 {
+    a + (b - a) * t
+}
+fn lerp_color(a: Color, b: Color, t: f64) -> Color {
+    a + (b - a) * t
+}
+/// (1 - cos)^5
+fn schlick_weight(cos: f64) -> f64 {
+    clamp(1. - cos, 0., 1.).powi(5)
+}
+fn schlick(f0: Color, cos: f64) -> Color {
+    f0 + (Color::one() - f0) * schlick_weight(cos)
+}
+fn schlick_scalar(f0: f64, cos: f64) -> f64 {
+    f0 + (1. - f0) * schlick_weight(cos)
+}
+/// Unpolarized Fresnel reflectance of a dielectric interface.
+/// `eta` is the relative index of refraction of the far side
+fn fresnel_dielectric(cos_i: f64, eta: f64) -> f64 {
+    let cos_i = clamp(cos_i, -1., 1.);
+    let sin2_t = (1. - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1. {
+        return 1.;
+    }
+    let cos_t = (1. - sin2_t).sqrt();
+    let cos_i = cos_i.abs();
+    let r_parl = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    let r_perp = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    0.5 * (r_parl * r_parl + r_perp * r_perp)
+}
+/// GGX (Trowbridge-Reitz) normal distribution
+fn ggx_d(h: &Vec3, alpha: f64) -> f64 {
+    let a2 = alpha * alpha;
+    let cos2 = h.z() * h.z();
+    let t = 1. + (a2 - 1.) * cos2;
+    a2 / (PI * t * t)
+}
+/// Berry (GTR1) normal distribution used by the clearcoat
+fn gtr1_d(h: &Vec3, alpha: f64) -> f64 {
+    if alpha >= 1. {
+        return 1. / PI;
+    }
+    let a2 = alpha * alpha;
+    let cos2 = h.z() * h.z();
+    (a2 - 1.) / (PI * a2.ln() * (1. + (a2 - 1.) * cos2))
+}
+/// Smith masking term for the GGX distribution
+fn smith_g1(w: &Vec3, alpha: f64) -> f64 {
+    let cos2 = w.z() * w.z();
+    if cos2 <= 0. {
+        return 0.;
+    }
+    let tan2 = (1. - cos2).max(0.) / cos2;
+    2. / (1. + (1. + alpha * alpha * tan2).sqrt())
+}
+/// Samples a microfacet normal proportionally to D(h) * cos(h)
+fn sample_ggx(alpha: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    let (u1, u2) = sampler.next_2d();
+    let tan2 = alpha * alpha * u1 / (1. - u1);
+    let cos = 1. / (1. + tan2).sqrt();
+    spherical_direction(cos, 2. * PI * u2)
+}
+/// Samples a microfacet normal of the GTR1 distribution proportionally to D(h) * cos(h)
+fn sample_gtr1(alpha: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    let (u1, u2) = sampler.next_2d();
+    let a2 = alpha * alpha;
+    let cos = ((1. - a2.powf(1. - u1)) / (1. - a2)).max(0.).sqrt();
+    spherical_direction(cos, 2. * PI * u2)
+}
+/// Mirrors `wo` about the microfacet normal `h`, None if `h` faces away from `wo`
+fn reflect(wo: &Vec3, h: &Vec3) -> Option<Vec3> {
+    if wo.dot(h) <= 0. {
+        return None;
+    }
+    Some((-*wo).reflect(h))
+}
+fn spherical_direction(cos_theta: f64, phi: f64) -> Vec3 {
+    let sin_theta = (1. - cos_theta * cos_theta).max(0.).sqrt();
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::Seeded;
+
+    #[test]
+    fn principled_sample_matches_pdf() {
+        let material = Principled {
+            metallic: 0.3,
+            roughness: 0.4,
+            clearcoat: 0.5,
+            transmission: 0.5,
+            ..Principled::new(Color::new(0.8, 0.3, 0.2))
+        };
+        let wo = Vec3::new(0.3, 0.1, 0.8).unit_vector();
+        let mut sampler = Seeded::new(0);
+        for _ in 0..1000 {
+            if let Some((wi, _)) = material.sample_direction(&wo, 1.5, &mut sampler) {
+                let pdf = material.bsdf_pdf(&wo, &wi, 1.5);
+                let f = material.bsdf(&wo, &wi, 1.5);
+                assert!(pdf >= 0. && pdf.is_finite());
+                // Every direction we can sample has a non zero density
+                if f.x() > 0. {
+                    assert!(pdf > 0.);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn principled_white_furnace() {
+        // A white diffuse-only material should reflect on average (almost) all the light
+        let material = Principled {
+            specular: 0.,
+            roughness: 0.5,
+            ..Principled::new(Color::one())
+        };
+        let wo = Vec3::new(0., 0., 1.);
+        let n = 20000;
+        let mut total = 0.;
+        let mut sampler = Seeded::new(0);
+        for _ in 0..n {
+            if let Some((wi, _)) = material.sample_direction(&wo, 1.5, &mut sampler) {
+                let pdf = material.bsdf_pdf(&wo, &wi, 1.5);
+                total += material.bsdf(&wo, &wi, 1.5).x() * wi.z() / pdf;
+            }
+        }
+        let albedo = total / n as f64;
+        assert!(albedo > 0.8 && albedo < 1.2, "albedo was {}", albedo);
+    }
+}
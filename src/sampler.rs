@@ -0,0 +1,421 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// A source of the random numbers used by the sampling decisions of the renderer:
+/// positions on the lens, BSDF lobes and directions, lights, Russian roulette...
+/// Drawing them all from a sampler lets an integrator control them, like
+/// Metropolis light transport does by mutating them.
+pub trait Sampler {
+    /// Returns the next number, uniform in [0, 1)
+    fn next_1d(&mut self) -> f64;
+    /// Returns the next two numbers, uniform in [0, 1)².
+    /// Low discrepancy samplers spread the pairs well over the square, so
+    /// 2d decisions (pixel position, lens, direction) should ask for them together.
+    fn next_2d(&mut self) -> (f64, f64) {
+        let u1 = self.next_1d();
+        (u1, self.next_1d())
+    }
+}
+
+/// Hashes keys into a seed, with the finalizer of SplitMix64.
+/// Neighbouring keys give unrelated seeds.
+pub fn hash(keys: &[u64]) -> u64 {
+    let mut h = 0x9e37_79b9_7f4a_7c15u64;
+    for key in keys {
+        h = (h ^ key).wrapping_add(0x9e37_79b9_7f4a_7c15);
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+    }
+    h
+}
+
+/// Independent uniform numbers from a seeded random number generator.
+/// Giving each sample its own sampler, seeded from the render seed and what the sample
+/// is (pixel, sample index, photon...), makes renders reproducible whatever the number
+/// of threads and the order in which the samples are taken.
+#[derive(Debug, Clone)]
+pub struct Seeded {
+    rng: StdRng,
+}
+
+impl Seeded {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+    /// Sampler seeded from the hash of the keys
+    pub fn from_keys(keys: &[u64]) -> Self {
+        Self::new(hash(keys))
+    }
+}
+
+impl Sampler for Seeded {
+    fn next_1d(&mut self) -> f64 {
+        self.rng.gen()
+    }
+}
+
+/// Uniform number in [0, 1) from a hash
+fn hashed_uniform(keys: &[u64]) -> f64 {
+    (hash(keys) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The samplers that a render can use for the samples of the pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+/// Names of the samplers, as given on the command line
+pub const SAMPLER_NAMES: [&str; 4] = ["independent", "stratified", "halton", "sobol"];
+
+impl SamplerKind {
+    /// Name of the sampler, as given on the command line
+    pub fn name(self) -> &'static str {
+        match self {
+            SamplerKind::Independent => "independent",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+        }
+    }
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "independent" => Ok(SamplerKind::Independent),
+            "stratified" => Ok(SamplerKind::Stratified),
+            "halton" => Ok(SamplerKind::Halton),
+            "sobol" => Ok(SamplerKind::Sobol),
+            _ => Err(format!(
+                "Unknown sampler `{}`, expected one of {:?}",
+                s, SAMPLER_NAMES
+            )),
+        }
+    }
+
+    /// Sampler of the sample with index `sample` out of `samples_per_pixel` of a pixel
+    pub fn pixel_sampler(
+        self,
+        seed: u64,
+        pixel: u64,
+        sample: u32,
+        samples_per_pixel: u32,
+    ) -> Box<dyn Sampler> {
+        match self {
+            SamplerKind::Independent => Box::new(Seeded::from_keys(&[seed, pixel, sample as u64])),
+            SamplerKind::Stratified => Box::new(Stratified {
+                seed: hash(&[seed, pixel]),
+                sample,
+                samples: samples_per_pixel.max(1),
+                dimension: 0,
+            }),
+            SamplerKind::Halton => Box::new(Halton {
+                seed: hash(&[seed, pixel]),
+                sample,
+                dimension: 0,
+            }),
+            SamplerKind::Sobol => Box::new(Sobol {
+                seed: hash(&[seed, pixel]),
+                sample,
+                dimension: 0,
+            }),
+        }
+    }
+}
+
+/// Element `i` of a random permutation of [0, l), given by the seed `p`.
+/// Kensler's hashing from "Correlated multi-jittered sampling" (2013).
+fn permutation_element(mut i: u32, l: u32, p: u32) -> u32 {
+    let mut w = l - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= p;
+        i = i.wrapping_mul(0xe170_893d);
+        i ^= p >> 16;
+        i ^= (i & w) >> 4;
+        i ^= p >> 8;
+        i = i.wrapping_mul(0x0929_eb3f);
+        i ^= p >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | p >> 27);
+        i = i.wrapping_mul(0x6935_fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dc_b303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e50_1cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860_a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < l {
+            break;
+        }
+    }
+    ((i as u64 + p as u64) % l as u64) as u32
+}
+
+/// Jittered stratified sampling. Each dimension is split in as many strata as there are
+/// samples in the pixel, and each sample gets a random point in its own stratum.
+/// The strata are shuffled differently for each dimension, so that dimensions don't
+/// correlate.
+#[derive(Debug, Clone)]
+pub struct Stratified {
+    seed: u64,
+    sample: u32,
+    samples: u32,
+    dimension: u64,
+}
+
+impl Stratified {
+    /// Shuffled stratum of the sample in the current dimension, and the hash of the dimension
+    fn stratum(&mut self) -> (u32, u64) {
+        let h = hash(&[self.seed, self.dimension]);
+        self.dimension += 1;
+        let stratum = permutation_element(self.sample % self.samples, self.samples, h as u32);
+        (stratum, h)
+    }
+}
+
+impl Sampler for Stratified {
+    fn next_1d(&mut self) -> f64 {
+        let (stratum, h) = self.stratum();
+        let jitter = hashed_uniform(&[h, self.sample as u64]);
+        (stratum as f64 + jitter) / self.samples as f64
+    }
+    /// Stratifies the square in a grid with a cell per sample when the number of samples
+    /// is a square. Otherwise x and y are stratified separately, each in as many strata as
+    /// samples and shuffled differently (Latin hypercube), so that no part of the square
+    /// is left out.
+    fn next_2d(&mut self) -> (f64, f64) {
+        let (stratum, h) = self.stratum();
+        let jx = hashed_uniform(&[h, self.sample as u64, 0]);
+        let jy = hashed_uniform(&[h, self.sample as u64, 1]);
+        let n = (self.samples as f64).sqrt().round() as u32;
+        if n * n == self.samples {
+            return (
+                ((stratum % n) as f64 + jx) / n as f64,
+                ((stratum / n) as f64 + jy) / n as f64,
+            );
+        }
+        let y_stratum =
+            permutation_element(self.sample % self.samples, self.samples, (h >> 32) as u32);
+        (
+            (stratum as f64 + jx) / self.samples as f64,
+            (y_stratum as f64 + jy) / self.samples as f64,
+        )
+    }
+}
+
+/// Bases of the dimensions of the Halton sequence
+const PRIMES: [u64; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+/// Digits of `index` in the base, mirrored around the radix point
+fn radical_inverse(base: u64, mut index: u64) -> f64 {
+    let inverse_base = 1. / base as f64;
+    let mut reversed = 0;
+    let mut inverse_base_power = 1.;
+    while index > 0 {
+        reversed = reversed * base + index % base;
+        inverse_base_power *= inverse_base;
+        index /= base;
+    }
+    (reversed as f64 * inverse_base_power).min(1. - f64::EPSILON)
+}
+
+/// The Halton sequence, the radical inverse of the sample index in a different prime
+/// base for each dimension. Every pixel walks the same sequence, shifted by a random
+/// offset (Cranley-Patterson rotation) so that pixels don't show the same pattern.
+/// Dimensions past the last prime are independent random numbers.
+#[derive(Debug, Clone)]
+pub struct Halton {
+    seed: u64,
+    sample: u32,
+    dimension: usize,
+}
+
+impl Sampler for Halton {
+    fn next_1d(&mut self) -> f64 {
+        let dimension = self.dimension;
+        self.dimension += 1;
+        let shift = hashed_uniform(&[self.seed, dimension as u64]);
+        if dimension >= PRIMES.len() {
+            return hashed_uniform(&[self.seed, dimension as u64, self.sample as u64]);
+        }
+        let u = radical_inverse(PRIMES[dimension], self.sample as u64) + shift;
+        u - u.floor()
+    }
+}
+
+/// First two dimensions of the Sobol sequence, as 32 bit fractions
+fn sobol(index: u32, dimension: usize) -> u32 {
+    if dimension == 0 {
+        return index.reverse_bits();
+    }
+    // The direction numbers of the second dimension
+    let mut v = 1u32 << 31;
+    let mut x = 0;
+    for bit in 0..32 {
+        if (index >> bit) & 1 == 1 {
+            x ^= v;
+        }
+        v ^= v >> 1;
+    }
+    x
+}
+
+/// Owen scrambling of a 32 bit fraction, with the hash of Laine and Karras
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50_b47c);
+    x ^= x.wrapping_mul(0xb82f_1e52);
+    x ^= x.wrapping_mul(0xc7af_e638);
+    x ^= x.wrapping_mul(0x8d22_f6e6);
+    x.reverse_bits()
+}
+
+/// Owen-scrambled Sobol points, as in Burley's "Practical hash-based Owen scrambling" (2020).
+/// Each 1d or 2d request takes the first dimensions of the Sobol sequence, with its own
+/// shuffling of the sample order and its own scrambling, so any number of dimensions is
+/// well stratified on its own and uncorrelated to the others.
+#[derive(Debug, Clone)]
+pub struct Sobol {
+    seed: u64,
+    sample: u32,
+    dimension: u64,
+}
+
+impl Sobol {
+    /// Seed of the current dimension, and the shuffled index of the sample in it
+    fn shuffled_index(&mut self) -> (u64, u32) {
+        let h = hash(&[self.seed, self.dimension]);
+        self.dimension += 1;
+        (h, nested_uniform_scramble(self.sample, h as u32))
+    }
+}
+
+/// Converts a 32 bit fraction to [0, 1)
+fn fraction(x: u32) -> f64 {
+    x as f64 / (1u64 << 32) as f64
+}
+
+impl Sampler for Sobol {
+    fn next_1d(&mut self) -> f64 {
+        let (h, index) = self.shuffled_index();
+        fraction(nested_uniform_scramble(sobol(index, 0), (h >> 32) as u32))
+    }
+    fn next_2d(&mut self) -> (f64, f64) {
+        let (h, index) = self.shuffled_index();
+        let x = nested_uniform_scramble(sobol(index, 0), (h >> 32) as u32);
+        let y = nested_uniform_scramble(sobol(index, 1), hash(&[h]) as u32);
+        (fraction(x), fraction(y))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_samplers_are_reproducible() {
+        let mut a = Seeded::from_keys(&[0, 12, 3]);
+        let mut b = Seeded::from_keys(&[0, 12, 3]);
+        let mut c = Seeded::from_keys(&[0, 12, 4]);
+        let a: Vec<f64> = (0..8).map(|_| a.next_1d()).collect();
+        let b: Vec<f64> = (0..8).map(|_| b.next_1d()).collect();
+        let c: Vec<f64> = (0..8).map(|_| c.next_1d()).collect();
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert!(a.iter().all(|u| (0. ..1.).contains(u)));
+    }
+
+    #[test]
+    fn permutations_are_permutations() {
+        for l in [1, 5, 16, 100].iter() {
+            let mut elements: Vec<u32> =
+                (0..*l).map(|i| permutation_element(i, *l, 1234)).collect();
+            elements.sort_unstable();
+            assert_eq!(elements, (0..*l).collect::<Vec<_>>());
+        }
+    }
+
+    /// Root mean square error of estimating the area of the quarter disk with
+    /// `samples` points, over many pixels
+    fn quarter_disk_error(kind: SamplerKind, samples: u32) -> f64 {
+        let pixels = 200;
+        let mut squared_error = 0.;
+        for pixel in 0..pixels {
+            let mut inside = 0;
+            for sample in 0..samples {
+                let mut sampler = kind.pixel_sampler(1, pixel, sample, samples);
+                // Skip a dimension to check more than the first one
+                sampler.next_1d();
+                let (x, y) = sampler.next_2d();
+                assert!((0. ..1.).contains(&x) && (0. ..1.).contains(&y));
+                if x * x + y * y < 1. {
+                    inside += 1;
+                }
+            }
+            let estimate = inside as f64 / samples as f64;
+            squared_error += (estimate - std::f64::consts::FRAC_PI_4).powi(2);
+        }
+        (squared_error / pixels as f64).sqrt()
+    }
+
+    #[test]
+    fn low_discrepancy_samplers_converge_faster() {
+        let independent = quarter_disk_error(SamplerKind::Independent, 64);
+        for kind in [
+            SamplerKind::Stratified,
+            SamplerKind::Halton,
+            SamplerKind::Sobol,
+        ]
+        .iter()
+        {
+            let error = quarter_disk_error(*kind, 64);
+            assert!(
+                error < independent / 2.,
+                "{:?}: {} vs {}",
+                kind,
+                error,
+                independent
+            );
+        }
+    }
+
+    #[test]
+    fn stratified_covers_the_square() {
+        // With a number of samples that isn't a square, no part of the square may be
+        // left out, or the mean of the pixels would shift away from it
+        for samples in [3, 5].iter() {
+            let mut quadrants = [0; 4];
+            let (mut x_sum, mut y_sum) = (0., 0.);
+            let pixels = 2000;
+            for pixel in 0..pixels {
+                for sample in 0..*samples {
+                    let mut sampler =
+                        SamplerKind::Stratified.pixel_sampler(1, pixel, sample, *samples);
+                    let (x, y) = sampler.next_2d();
+                    quadrants[(x >= 0.5) as usize + 2 * (y >= 0.5) as usize] += 1;
+                    x_sum += x;
+                    y_sum += y;
+                }
+            }
+            let n = (pixels * *samples as u64) as f64;
+            assert!(quadrants.iter().all(|count| *count > 0), "{:?}", quadrants);
+            assert!((x_sum / n - 0.5).abs() < 0.01, "{}", x_sum / n);
+            assert!((y_sum / n - 0.5).abs() < 0.01, "{}", y_sum / n);
+        }
+    }
+}
@@ -0,0 +1,372 @@
+use crate::camera::Camera;
+use crate::hittable::HittableList;
+use crate::material::{Dielectric, DiffuseLight, Ior, Lambertian, Material, Metal};
+use crate::principled::Principled;
+use crate::ray::Ray;
+use crate::sampler::{Sampler, Seeded};
+use crate::settings::Settings;
+use crate::sphere::Sphere;
+use crate::vec3::{Color, Point3, Vec3};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// What a ray sees when it doesn't hit anything
+#[derive(Debug, Clone, Copy)]
+pub enum Background {
+    /// White to blue vertical gradient
+    Sky,
+    Solid(Color),
+}
+
+impl Background {
+    pub fn color(&self, r: &Ray) -> Color {
+        match self {
+            Background::Sky => {
+                let unit_direction: Vec3 = r.dir().unit_vector();
+                let t = 0.5 * (unit_direction.y() + 1.);
+                (1.0 - t) * Color::one() + t * Color::new(0.5, 0.7, 1.)
+            }
+            Background::Solid(color) => *color,
+        }
+    }
+}
+
+/// Everything needed to render an image: the objects, the lights among them,
+/// the background and the camera
+pub struct Scene {
+    pub world: HittableList,
+    /// The emissive objects of `world`, used for light sampling
+    pub lights: HittableList,
+    /// Index of each material of `world`, by its address, for the material ids of the AOVs.
+    /// The materials are numbered in the order of the objects.
+    material_indices: HashMap<usize, usize>,
+    pub background: Background,
+    pub camera: Camera,
+}
+
+impl Scene {
+    /// Creates a scene and collects the emissive objects of `world` as its lights
+    pub fn new(world: HittableList, background: Background, camera: Camera) -> Self {
+        let lights = world.lights();
+        let material_indices = world
+            .materials()
+            .iter()
+            .enumerate()
+            .map(|(index, material)| (address(material), index))
+            .collect();
+        Self {
+            world,
+            lights,
+            material_indices,
+            background,
+            camera,
+        }
+    }
+    /// Index of a material of the world
+    pub fn material_index(&self, material: &Arc<dyn Material>) -> Option<usize> {
+        self.material_indices.get(&address(material)).cloned()
+    }
+}
+
+/// Address of a shared material, that tells it apart from the other materials
+fn address(material: &Arc<dyn Material>) -> usize {
+    Arc::as_ptr(material) as *const () as usize
+}
+
+/// Names of the scenes that `by_name` knows about
+pub const SCENE_NAMES: [&str; 5] = ["random", "simple", "lights", "mis", "dispersion"];
+
+/// Builds the scene with the given name. Random scenes are generated from the seed.
+pub fn by_name(name: &str, aspect_ratio: f64, seed: u64) -> Option<Scene> {
+    match name {
+        "random" => Some(random_scene(aspect_ratio, &mut Seeded::new(seed))),
+        "simple" => Some(simple_scene(aspect_ratio)),
+        "lights" => Some(lights_scene(aspect_ratio)),
+        "mis" => Some(mis_scene(aspect_ratio)),
+        "dispersion" => Some(dispersion_scene(aspect_ratio)),
+        _ => None,
+    }
+}
+
+/// The scene of the settings, seen through the camera they ask for
+pub fn from_settings(settings: &Settings, aspect_ratio: f64) -> Option<Scene> {
+    let mut scene = by_name(&settings.scene, aspect_ratio, settings.seed)?;
+    if let Some(view_width) = settings.orthographic {
+        scene.camera = scene.camera.to_orthographic(view_width);
+    }
+    Some(scene)
+}
+
+/// The cover of "Ray tracing in one weekend": lots of small random spheres
+pub fn random_scene(aspect_ratio: f64, sampler: &mut dyn Sampler) -> Scene {
+    let mut world = HittableList::new();
+    let ground_material = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    let ground_sphere = Sphere::new(Point3::new(0., -1000., 0.), 1000., ground_material);
+    world.add(Box::new(ground_sphere));
+
+    for a in -11..11 {
+        for b in -11..11 {
+            let choose_mat = sampler.next_1d();
+            let center: Point3 = Point3::new(
+                a as f64 + 0.9 * sampler.next_1d(),
+                0.2,
+                b as f64 + sampler.next_1d(),
+            );
+            if (center - Point3::new(4., 0.2, 0.)).norm() > 0.9 {
+                let sphere_material: Arc<dyn Material>;
+                if choose_mat < 0.8 {
+                    // Diffuse
+                    let albedo = Color::random(sampler) * Color::random(sampler);
+                    sphere_material = Arc::new(Lambertian::new(albedo));
+                } else if choose_mat < 0.95 {
+                    // Metal
+                    let albedo = Color::random_interval(0.5, 1., sampler);
+                    let fuzz = sampler.next_1d() / 2.;
+                    sphere_material = Arc::new(Metal::new(albedo, fuzz));
+                } else {
+                    // Glass
+                    let ir = 1.5;
+                    sphere_material = Arc::new(Dielectric::new(ir));
+                }
+                let world_sphere = Sphere::new(center, 0.2, sphere_material);
+                world.add(Box::new(world_sphere));
+            }
+        }
+    }
+    let material1 = Arc::new(Dielectric::new(1.5));
+    let sphere1 = Sphere::new(Point3::new(0., 1., 0.), 1., material1);
+    let material2 = Arc::new(Lambertian::new(Color::new(0.4, 0.2, 0.1)));
+    let sphere2 = Sphere::new(Point3::new(-4., 1., 0.), 1., material2);
+    let material3 = Arc::new(Metal::new(Color::new(0.7, 0.6, 0.5), 0.0));
+    let sphere3 = Sphere::new(Point3::new(0., 1., 0.), 1., material3);
+    world.add(Box::new(sphere1));
+    world.add(Box::new(sphere2));
+    world.add(Box::new(sphere3));
+
+    let origin = Point3::new(11., 2., 7.);
+    let lookat = Point3::new(0., 0., 0.);
+    let vup = Vec3::new(0., 1., 0.);
+    let vfov = 20.;
+    let dist_to_focus = 10.;
+    let aperture = 0.1;
+    let cam = Camera::new(
+        aspect_ratio,
+        vfov,
+        aperture,
+        dist_to_focus,
+        origin,
+        lookat,
+        vup,
+    );
+    Scene::new(world, Background::Sky, cam)
+}
+
+/// Three spheres on a big ground sphere
+pub fn simple_scene(aspect_ratio: f64) -> Scene {
+    let material_ground = Arc::new(Lambertian::new(Color::new(0.8, 0.8, 0.0)));
+    let material_center = Arc::new(Lambertian::new(Color::new(0.0, 0.0, 0.25)));
+    let material_left = Arc::new(Lambertian::new(Color::new(0.0, 0.0, 0.5)));
+    let material_right = Arc::new(Lambertian::new(Color::new(0.0, 0.0, 1.)));
+
+    let mut world = HittableList::new();
+    world.add(Box::new(Sphere::new(
+        Point3::new(0., -100.5, -1.),
+        100.,
+        material_ground,
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0., 0., -1.),
+        0.5,
+        material_center,
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(-1., 0., -1.),
+        0.5,
+        material_left,
+    )));
+    world.add(Box::new(Sphere::new(
+        Point3::new(1., 0., -1.),
+        0.5,
+        material_right,
+    )));
+
+    let cam = Camera::new(
+        aspect_ratio,
+        90.,
+        0.,
+        1.,
+        Point3::zero(),
+        Point3::new(0., 0., -1.),
+        Vec3::new(0., 1., 0.),
+    );
+    Scene::new(world, Background::Sky, cam)
+}
+
+/// A dark scene lit by a small spherical light, showing off the materials
+pub fn lights_scene(aspect_ratio: f64) -> Scene {
+    let mut world = HittableList::new();
+    let ground = Arc::new(Lambertian::new(Color::new(0.5, 0.5, 0.5)));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0., -1000., 0.),
+        1000.,
+        ground,
+    )));
+
+    let glass = Arc::new(Dielectric::with_absorption(1.5, Color::new(0.1, 0.6, 1.2)));
+    world.add(Box::new(Sphere::new(Point3::new(-2.2, 1., 0.), 1., glass)));
+    let plastic = Arc::new(Principled {
+        roughness: 0.3,
+        clearcoat: 1.,
+        ..Principled::new(Color::new(0.8, 0.1, 0.1))
+    });
+    world.add(Box::new(Sphere::new(Point3::new(0., 1., 0.), 1., plastic)));
+    let metal = Arc::new(Metal::new(Color::new(0.8, 0.7, 0.5), 0.2));
+    world.add(Box::new(Sphere::new(Point3::new(2.2, 1., 0.), 1., metal)));
+
+    let light = Arc::new(DiffuseLight::new(Color::new(40., 36., 30.)));
+    world.add(Box::new(Sphere::new(Point3::new(1., 4., 2.), 0.3, light)));
+
+    let cam = Camera::new(
+        aspect_ratio,
+        35.,
+        0.,
+        10.,
+        Point3::new(0., 3., 9.),
+        Point3::new(0., 0.8, 0.),
+        Vec3::new(0., 1., 0.),
+    );
+    Scene::new(world, Background::Solid(Color::new(0.01, 0.01, 0.02)), cam)
+}
+
+/// A version of Veach's multiple importance sampling test scene.
+/// Four glossy spheres, from sharp to rough, reflect four lights of the same power,
+/// from small to large. Rendering it with `--direct-lighting bsdf`, `lights` and `mis`
+/// shows that BSDF sampling is noisy for small lights on rough surfaces, light sampling
+/// is noisy for large lights on sharp surfaces and MIS handles both.
+pub fn mis_scene(aspect_ratio: f64) -> Scene {
+    let mut world = HittableList::new();
+    let ground = Arc::new(Lambertian::new(Color::new(0.4, 0.4, 0.4)));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0., -1000., 0.),
+        1000.,
+        ground,
+    )));
+
+    for (i, roughness) in [0.05, 0.15, 0.3, 0.5].iter().enumerate() {
+        let material = Arc::new(Principled {
+            metallic: 1.,
+            roughness: *roughness,
+            ..Principled::new(Color::new(0.8, 0.8, 0.8))
+        });
+        let x = -3.3 + 2.2 * i as f64;
+        world.add(Box::new(Sphere::new(Point3::new(x, 1., 0.), 1., material)));
+    }
+
+    let colors = [
+        Color::new(1., 0.3, 0.3),
+        Color::new(1., 1., 0.3),
+        Color::new(0.3, 1., 0.3),
+        Color::new(0.3, 0.3, 1.),
+    ];
+    for (i, radius) in [0.05, 0.2, 0.5, 1.].iter().enumerate() {
+        // Same power for every light
+        let emit = colors[i] * (2. / (radius * radius));
+        let light = Arc::new(DiffuseLight::new(emit));
+        let x = -4.5 + 3. * i as f64;
+        world.add(Box::new(Sphere::new(
+            Point3::new(x, 6., 6.),
+            *radius,
+            light,
+        )));
+    }
+
+    let cam = Camera::new(
+        aspect_ratio,
+        40.,
+        0.,
+        10.,
+        Point3::new(0., 2., 10.),
+        Point3::new(0., 1., 0.),
+        Vec3::new(0., 1., 0.),
+    );
+    Scene::new(world, Background::Solid(Color::zero()), cam)
+}
+
+/// Glass spheres under a small light, whose caustics are split into colors
+/// by the spectral integrator
+pub fn dispersion_scene(aspect_ratio: f64) -> Scene {
+    let mut world = HittableList::new();
+    let ground = Arc::new(Lambertian::new(Color::new(0.8, 0.8, 0.8)));
+    world.add(Box::new(Sphere::new(
+        Point3::new(0., -1000., 0.),
+        1000.,
+        ground,
+    )));
+
+    let flint = Arc::new(Dielectric::dispersive(Ior::SF11));
+    world.add(Box::new(Sphere::new(Point3::new(-2.2, 1., 0.), 1., flint)));
+    let crown = Arc::new(Dielectric::dispersive(Ior::BK7));
+    world.add(Box::new(Sphere::new(Point3::new(0., 1., 0.), 1., crown)));
+    // Fused silica, with Cauchy's approximation
+    let silica = Arc::new(Dielectric::dispersive(Ior::Cauchy {
+        a: 1.4580,
+        b: 0.00354,
+    }));
+    world.add(Box::new(Sphere::new(Point3::new(2.2, 1., 0.), 1., silica)));
+
+    let light = Arc::new(DiffuseLight::new(Color::new(200., 200., 200.)));
+    world.add(Box::new(Sphere::new(Point3::new(0., 8., -3.), 0.3, light)));
+
+    let cam = Camera::new(
+        aspect_ratio,
+        40.,
+        0.,
+        10.,
+        Point3::new(0., 4., 8.),
+        Point3::new(0., 0.5, 0.),
+        Vec3::new(0., 1., 0.),
+    );
+    Scene::new(world, Background::Solid(Color::zero()), cam)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::Hittable;
+    #[test]
+    fn scenes_by_name() {
+        for name in SCENE_NAMES.iter() {
+            assert!(by_name(name, 1., 0).is_some());
+        }
+        assert!(by_name("nope", 1., 0).is_none());
+        assert_eq!(by_name("lights", 1., 0).unwrap().lights.len(), 1);
+        assert!(by_name("random", 1., 0).unwrap().lights.is_empty());
+    }
+
+    #[test]
+    fn material_indices() {
+        let scene = simple_scene(1.);
+        for (index, material) in scene.world.materials().iter().enumerate() {
+            assert_eq!(scene.material_index(material), Some(index));
+        }
+        let unknown: Arc<dyn Material> = Arc::new(Lambertian::new(Color::one()));
+        assert_eq!(scene.material_index(&unknown), None);
+    }
+
+    #[test]
+    fn random_scene_depends_on_the_seed() {
+        // Distances to the first hit along rays grazing the small spheres
+        let distances = |seed| -> Vec<Option<f64>> {
+            let scene = by_name("random", 1., seed).unwrap();
+            (0..40)
+                .map(|i| {
+                    let origin = Point3::new(-11. + 0.55 * i as f64, 0.2, 12.);
+                    let r = Ray::new(origin, Vec3::new(0., 0., -1.));
+                    scene.world.hit(&r, 0.001, f64::INFINITY).map(|rec| rec.t)
+                })
+                .collect()
+        };
+        assert_eq!(distances(7), distances(7));
+        assert_ne!(distances(7), distances(8));
+    }
+}
@@ -0,0 +1,604 @@
+use crate::aov::Aov;
+use crate::filter::FilterKind;
+use crate::sampler::SamplerKind;
+use crate::tiles::TileOrder;
+use crate::tonemap::ToneMapper;
+
+/// How the light reaching a hit directly from the emitters is estimated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DirectLighting {
+    /// Only by following the directions sampled from the BSDFs
+    Bsdf,
+    /// Only by sampling directions towards the lights
+    Lights,
+    /// Both, combined with multiple importance sampling
+    Mis,
+}
+
+impl DirectLighting {
+    fn name(self) -> &'static str {
+        match self {
+            DirectLighting::Bsdf => "bsdf",
+            DirectLighting::Lights => "lights",
+            DirectLighting::Mis => "mis",
+        }
+    }
+    fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "bsdf" => Ok(DirectLighting::Bsdf),
+            "lights" => Ok(DirectLighting::Lights),
+            "mis" => Ok(DirectLighting::Mis),
+            _ => Err(format!(
+                "Unknown direct lighting `{}`, expected bsdf, lights or mis",
+                s
+            )),
+        }
+    }
+}
+
+/// Render settings, read from the command line
+#[derive(Debug, Clone)]
+pub struct Settings {
+    /// Name of the scene to render
+    pub scene: String,
+    /// Width of the view, in the units of the scene, of an orthographic camera that
+    /// replaces the perspective one of the scene from the same spot
+    pub orthographic: Option<f64>,
+    /// Name of the rendering algorithm
+    pub integrator: String,
+    pub direct_lighting: DirectLighting,
+    /// Maximum number of bounces of a path
+    pub max_depth: u32,
+    /// Number of bounces after which paths are terminated with Russian roulette
+    pub rr_depth: u32,
+    /// Number of photons shot by the photon mappers, per pass
+    pub photons: usize,
+    /// Gather radius of the photon mappers
+    pub photon_radius: f64,
+    /// Number of passes of the progressive photon mapper
+    pub passes: u32,
+    /// Number of samples per pixel to stop at
+    pub samples: u32,
+    /// Wall-clock time in seconds after which the render stops, at the end of a pass
+    pub time_budget: Option<f64>,
+    /// Path where the image so far is written during the render
+    pub progress: Option<String>,
+    /// Seconds between two writes of the image so far
+    pub progress_interval: f64,
+    /// Width and height of the tiles rendered in parallel, in pixels
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    /// Seed of the random numbers. Renders with the same settings and seed are identical.
+    pub seed: u64,
+    /// Sampler of the samples of the pixels
+    pub sampler: SamplerKind,
+    /// Instead of rendering, measure the noise of the samplers up to this many samples per pixel
+    pub benchmark: Option<u32>,
+    /// With adaptive sampling, pixels stop being sampled when their relative error
+    /// falls below this threshold
+    pub adaptive: Option<f64>,
+    /// Number of samples that every pixel gets with adaptive sampling, before its error
+    /// is estimated
+    pub min_samples: u32,
+    /// Path of the image of the number of samples of the pixels
+    pub heatmap: Option<String>,
+    /// Path where the state of the render is saved, to resume it if it is interrupted
+    pub checkpoint: Option<String>,
+    /// Seconds between two checkpoints
+    pub checkpoint_interval: f64,
+    /// Continue the render from the checkpoint
+    pub resume: bool,
+    /// Address to listen on for workers, which render the tiles of the image
+    pub coordinator: Option<String>,
+    /// Address of the coordinator to render tiles for
+    pub worker: Option<String>,
+    /// Seconds that the coordinator waits without hearing from a worker before giving up
+    /// on it, as it may have lost power or its network, and giving its tile to another one.
+    /// Workers send heartbeats while they render, so slow tiles don't time out.
+    pub worker_timeout: f64,
+    /// Only render this rectangle of the image: left, top, width and height in pixels
+    pub crop: Option<(usize, usize, usize, usize)>,
+    /// Instead of rendering, trace the paths of the pixel at this column and row from
+    /// the top left corner
+    pub debug_pixel: Option<(usize, usize)>,
+    /// Path of the JSON file of the traced paths, printed if not given
+    pub path_dump: Option<String>,
+    /// Reconstruction filter of the film
+    pub filter: FilterKind,
+    /// Radius of the filter in pixels, or the default radius of the filter
+    pub filter_radius: Option<f64>,
+    /// Exposure compensation of the image, in stops
+    pub exposure: f64,
+    /// Color temperature in Kelvin that is balanced to white
+    pub white_balance: Option<f64>,
+    pub tone_mapper: ToneMapper,
+    /// White point of the extended Reinhard tone mapping
+    pub white_point: f64,
+    /// Luminance above which pixels bloom and glare
+    pub bright_threshold: f64,
+    /// Share of the light of the bright pixels that blooms around them
+    pub bloom: Option<f64>,
+    /// Radius of the bloom in pixels
+    pub bloom_radius: f64,
+    /// Share of the light of the bright pixels diffracted into a starburst
+    pub glare: Option<f64>,
+    /// Number of blades of the aperture, which shape the starburst
+    pub glare_blades: u32,
+    /// Length of the streaks of the starburst in pixels
+    pub glare_length: f64,
+    /// Tangent of the angle of incidence in the corners, for the cos^4 vignetting
+    pub vignetting: Option<f64>,
+    /// Distance in pixels between the red and blue images in the corners
+    pub chromatic_aberration: Option<f64>,
+    /// Denoise the image with the albedo, normals and depth of the first hits
+    pub denoise: bool,
+    /// Path where the image is written before it is denoised
+    pub noisy: Option<String>,
+    /// Passes rendered along with the image
+    pub aovs: Vec<Aov>,
+    /// Path of the AOVs: a multi-layer EXR if it ends with `.exr`, otherwise the prefix
+    /// of a PFM image per AOV
+    pub aov_output: Option<String>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            scene: String::from("random"),
+            orthographic: None,
+            integrator: String::from("path"),
+            direct_lighting: DirectLighting::Mis,
+            max_depth: 50,
+            rr_depth: 3,
+            photons: 100_000,
+            photon_radius: 0.1,
+            passes: 16,
+            samples: 100,
+            time_budget: None,
+            progress: None,
+            progress_interval: 10.,
+            tile_size: 16,
+            tile_order: TileOrder::Spiral,
+            seed: 0,
+            sampler: SamplerKind::Sobol,
+            benchmark: None,
+            adaptive: None,
+            min_samples: 16,
+            heatmap: None,
+            checkpoint: None,
+            checkpoint_interval: 300.,
+            resume: false,
+            coordinator: None,
+            worker: None,
+            worker_timeout: 600.,
+            crop: None,
+            debug_pixel: None,
+            path_dump: None,
+            filter: FilterKind::Box,
+            filter_radius: None,
+            exposure: 0.,
+            white_balance: None,
+            tone_mapper: ToneMapper::Clamp,
+            white_point: 4.,
+            bright_threshold: 1.,
+            bloom: None,
+            bloom_radius: 8.,
+            glare: None,
+            glare_blades: 6,
+            glare_length: 40.,
+            vignetting: None,
+            chromatic_aberration: None,
+            denoise: false,
+            noisy: None,
+            aovs: Vec::new(),
+            aov_output: None,
+        }
+    }
+}
+
+impl Settings {
+    /// Parses settings from command line arguments (without the program name).
+    /// Arguments that are not given keep their default value.
+    pub fn from_args<I: Iterator<Item = String>>(mut args: I) -> Result<Self, String> {
+        let mut settings = Self::default();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--scene" => settings.scene = value(&arg, args.next())?,
+                "--orthographic" => settings.orthographic = Some(number(&arg, args.next())?),
+                "--integrator" => settings.integrator = value(&arg, args.next())?,
+                "--direct-lighting" => {
+                    settings.direct_lighting = DirectLighting::parse(&value(&arg, args.next())?)?
+                }
+                "--max-depth" => settings.max_depth = number(&arg, args.next())?,
+                "--rr-depth" => settings.rr_depth = number(&arg, args.next())?,
+                "--photons" => settings.photons = number(&arg, args.next())?,
+                "--photon-radius" => settings.photon_radius = number(&arg, args.next())?,
+                "--passes" => settings.passes = number(&arg, args.next())?,
+                "--samples" => settings.samples = number(&arg, args.next())?,
+                "--time-budget" => settings.time_budget = Some(number(&arg, args.next())?),
+                "--progress" => settings.progress = Some(value(&arg, args.next())?),
+                "--progress-interval" => settings.progress_interval = number(&arg, args.next())?,
+                "--tile-size" => settings.tile_size = number(&arg, args.next())?,
+                "--tile-order" => {
+                    settings.tile_order = TileOrder::parse(&value(&arg, args.next())?)?
+                }
+                "--seed" => settings.seed = number(&arg, args.next())?,
+                "--sampler" => settings.sampler = SamplerKind::parse(&value(&arg, args.next())?)?,
+                "--benchmark" => settings.benchmark = Some(number(&arg, args.next())?),
+                "--adaptive" => settings.adaptive = Some(number(&arg, args.next())?),
+                "--min-samples" => settings.min_samples = number(&arg, args.next())?,
+                "--heatmap" => settings.heatmap = Some(value(&arg, args.next())?),
+                "--checkpoint" => settings.checkpoint = Some(value(&arg, args.next())?),
+                "--checkpoint-interval" => {
+                    settings.checkpoint_interval = number(&arg, args.next())?
+                }
+                "--resume" => settings.resume = true,
+                "--coordinator" => settings.coordinator = Some(value(&arg, args.next())?),
+                "--worker" => settings.worker = Some(value(&arg, args.next())?),
+                "--worker-timeout" => settings.worker_timeout = number(&arg, args.next())?,
+                "--crop" => {
+                    let crop = numbers(&arg, args.next(), 4)?;
+                    settings.crop = Some((crop[0], crop[1], crop[2], crop[3]));
+                }
+                "--debug-pixel" => {
+                    let pixel = numbers(&arg, args.next(), 2)?;
+                    settings.debug_pixel = Some((pixel[0], pixel[1]));
+                }
+                "--path-dump" => settings.path_dump = Some(value(&arg, args.next())?),
+                "--filter" => settings.filter = FilterKind::parse(&value(&arg, args.next())?)?,
+                "--filter-radius" => settings.filter_radius = Some(number(&arg, args.next())?),
+                "--exposure" => settings.exposure = number(&arg, args.next())?,
+                "--white-balance" => settings.white_balance = Some(number(&arg, args.next())?),
+                "--tone-map" => {
+                    settings.tone_mapper = ToneMapper::parse(&value(&arg, args.next())?)?
+                }
+                "--white-point" => settings.white_point = number(&arg, args.next())?,
+                "--bright-threshold" => settings.bright_threshold = number(&arg, args.next())?,
+                "--bloom" => settings.bloom = Some(number(&arg, args.next())?),
+                "--bloom-radius" => settings.bloom_radius = number(&arg, args.next())?,
+                "--glare" => settings.glare = Some(number(&arg, args.next())?),
+                "--glare-blades" => settings.glare_blades = number(&arg, args.next())?,
+                "--glare-length" => settings.glare_length = number(&arg, args.next())?,
+                "--vignetting" => settings.vignetting = Some(number(&arg, args.next())?),
+                "--chromatic-aberration" => {
+                    settings.chromatic_aberration = Some(number(&arg, args.next())?)
+                }
+                "--denoise" => settings.denoise = true,
+                "--noisy" => settings.noisy = Some(value(&arg, args.next())?),
+                "--aovs" => settings.aovs = Aov::parse_list(&value(&arg, args.next())?)?,
+                "--aov-output" => settings.aov_output = Some(value(&arg, args.next())?),
+                _ => return Err(format!("Unknown argument `{}`", arg)),
+            }
+        }
+        Ok(settings)
+    }
+
+    /// The first of the given flags that a coordinator can't honour: its workers take
+    /// every sample of their tiles and only send back the samples
+    pub fn unsupported_by_coordinator(&self) -> Option<&'static str> {
+        [
+            ("--aovs", !self.aovs.is_empty()),
+            ("--adaptive", self.adaptive.is_some()),
+            ("--time-budget", self.time_budget.is_some()),
+            ("--progress", self.progress.is_some()),
+            ("--checkpoint", self.checkpoint.is_some()),
+            ("--resume", self.resume),
+        ]
+        .iter()
+        .find(|(_, given)| *given)
+        .map(|(flag, _)| *flag)
+    }
+
+    /// The first of the given flags that integrators which render the whole image at once
+    /// can't honour: they take every sample of the pixels in one go, without a film
+    pub fn unsupported_by_whole_image(&self) -> Option<&'static str> {
+        [
+            ("--adaptive", self.adaptive.is_some()),
+            ("--time-budget", self.time_budget.is_some()),
+            ("--progress", self.progress.is_some()),
+            ("--checkpoint", self.checkpoint.is_some()),
+            ("--resume", self.resume),
+        ]
+        .iter()
+        .find(|(_, given)| *given)
+        .map(|(flag, _)| *flag)
+    }
+
+    /// Command line arguments that give the settings which change the rendered image,
+    /// sent by the coordinator to its workers
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![
+            ("--scene", self.scene.clone()),
+            ("--integrator", self.integrator.clone()),
+            ("--direct-lighting", self.direct_lighting.name().to_string()),
+            ("--max-depth", self.max_depth.to_string()),
+            ("--rr-depth", self.rr_depth.to_string()),
+            ("--photons", self.photons.to_string()),
+            ("--photon-radius", self.photon_radius.to_string()),
+            ("--passes", self.passes.to_string()),
+            ("--samples", self.samples.to_string()),
+            ("--tile-size", self.tile_size.to_string()),
+            ("--seed", self.seed.to_string()),
+            ("--sampler", self.sampler.name().to_string()),
+            ("--filter", self.filter.name().to_string()),
+        ];
+        if let Some(radius) = self.filter_radius {
+            args.push(("--filter-radius", radius.to_string()));
+        }
+        if let Some(width) = self.orthographic {
+            args.push(("--orthographic", width.to_string()));
+        }
+        args.iter()
+            .flat_map(|(flag, value)| vec![flag.to_string(), value.clone()])
+            .collect()
+    }
+}
+
+/// Returns the value that follows a flag
+fn value(flag: &str, value: Option<String>) -> Result<String, String> {
+    value.ok_or_else(|| format!("Missing value for `{}`", flag))
+}
+/// Parses the number that follows a flag
+fn number<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = self::value(flag, value)?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value `{}` for `{}`", value, flag))
+}
+/// Parses the `count` comma separated numbers that follow a flag
+fn numbers<T: std::str::FromStr>(
+    flag: &str,
+    value: Option<String>,
+    count: usize,
+) -> Result<Vec<T>, String> {
+    let value = self::value(flag, value)?;
+    let numbers: Vec<T> = value
+        .split(',')
+        .map(|x| x.trim().parse())
+        .collect::<Result<_, _>>()
+        .map_err(|_| format!("Invalid value `{}` for `{}`", value, flag))?;
+    if numbers.len() != count {
+        return Err(format!(
+            "Expected {} comma separated numbers for `{}`, got `{}`",
+            count, flag, value
+        ));
+    }
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Settings, String> {
+        Settings::from_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn settings_from_args() {
+        assert_eq!(parse(&[]).unwrap().scene, "random");
+        assert_eq!(parse(&["--scene", "lights"]).unwrap().scene, "lights");
+        assert!(parse(&["--scene"]).is_err());
+        assert_eq!(
+            parse(&["--orthographic", "4.5"]).unwrap().orthographic,
+            Some(4.5)
+        );
+        assert_eq!(parse(&["--integrator", "ao"]).unwrap().integrator, "ao");
+        assert_eq!(
+            parse(&["--direct-lighting", "bsdf"])
+                .unwrap()
+                .direct_lighting,
+            DirectLighting::Bsdf
+        );
+        assert!(parse(&["--direct-lighting", "all"]).is_err());
+        assert_eq!(parse(&["--max-depth", "200"]).unwrap().max_depth, 200);
+        assert!(parse(&["--max-depth", "-1"]).is_err());
+        assert_eq!(parse(&["--photons", "5000"]).unwrap().photons, 5000);
+        assert_eq!(
+            parse(&["--photon-radius", "0.5"]).unwrap().photon_radius,
+            0.5
+        );
+        assert_eq!(parse(&["--passes", "4"]).unwrap().passes, 4);
+        assert_eq!(parse(&["--seed", "42"]).unwrap().seed, 42);
+        assert_eq!(parse(&["--samples", "16"]).unwrap().samples, 16);
+        assert_eq!(parse(&["--tile-size", "32"]).unwrap().tile_size, 32);
+        assert_eq!(
+            parse(&["--tile-order", "hilbert"]).unwrap().tile_order,
+            TileOrder::Hilbert
+        );
+        assert!(parse(&["--tile-order", "random"]).is_err());
+        assert_eq!(
+            parse(&["--time-budget", "2.5"]).unwrap().time_budget,
+            Some(2.5)
+        );
+        assert_eq!(
+            parse(&["--progress", "out.ppm"])
+                .unwrap()
+                .progress
+                .as_deref(),
+            Some("out.ppm")
+        );
+        assert_eq!(
+            parse(&["--progress-interval", "1"])
+                .unwrap()
+                .progress_interval,
+            1.
+        );
+        assert_eq!(
+            parse(&["--sampler", "halton"]).unwrap().sampler,
+            SamplerKind::Halton
+        );
+        assert!(parse(&["--sampler", "sobel"]).is_err());
+        assert_eq!(parse(&["--benchmark", "64"]).unwrap().benchmark, Some(64));
+        assert_eq!(parse(&["--adaptive", "0.05"]).unwrap().adaptive, Some(0.05));
+        assert_eq!(parse(&["--min-samples", "8"]).unwrap().min_samples, 8);
+        assert_eq!(
+            parse(&["--heatmap", "spp.ppm"]).unwrap().heatmap.as_deref(),
+            Some("spp.ppm")
+        );
+        assert_eq!(
+            parse(&["--checkpoint", "render.ckpt"])
+                .unwrap()
+                .checkpoint
+                .as_deref(),
+            Some("render.ckpt")
+        );
+        assert_eq!(
+            parse(&["--checkpoint-interval", "60"])
+                .unwrap()
+                .checkpoint_interval,
+            60.
+        );
+        assert!(parse(&["--resume"]).unwrap().resume);
+        assert!(!parse(&[]).unwrap().resume);
+        assert_eq!(
+            parse(&["--coordinator", "0.0.0.0:7878"])
+                .unwrap()
+                .coordinator
+                .as_deref(),
+            Some("0.0.0.0:7878")
+        );
+        assert_eq!(
+            parse(&["--worker", "render1:7878"])
+                .unwrap()
+                .worker
+                .as_deref(),
+            Some("render1:7878")
+        );
+        assert_eq!(
+            parse(&["--worker-timeout", "30"]).unwrap().worker_timeout,
+            30.
+        );
+        assert_eq!(
+            parse(&["--crop", "10,20,64,32"]).unwrap().crop,
+            Some((10, 20, 64, 32))
+        );
+        assert!(parse(&["--crop", "10,20,64"]).is_err());
+        assert!(parse(&["--crop", "10,20,64,-1"]).is_err());
+        assert_eq!(
+            parse(&["--debug-pixel", "100, 200"]).unwrap().debug_pixel,
+            Some((100, 200))
+        );
+        assert_eq!(
+            parse(&["--path-dump", "paths.json"])
+                .unwrap()
+                .path_dump
+                .as_deref(),
+            Some("paths.json")
+        );
+        assert_eq!(
+            parse(&["--filter", "lanczos"]).unwrap().filter,
+            FilterKind::Lanczos
+        );
+        assert!(parse(&["--filter", "sinc"]).is_err());
+        assert_eq!(
+            parse(&["--filter-radius", "3"]).unwrap().filter_radius,
+            Some(3.)
+        );
+        assert_eq!(parse(&["--exposure", "-1.5"]).unwrap().exposure, -1.5);
+        assert_eq!(
+            parse(&["--white-balance", "3200"]).unwrap().white_balance,
+            Some(3200.)
+        );
+        assert_eq!(
+            parse(&["--tone-map", "agx"]).unwrap().tone_mapper,
+            ToneMapper::Agx
+        );
+        assert!(parse(&["--tone-map", "hable"]).is_err());
+        assert_eq!(parse(&["--white-point", "8"]).unwrap().white_point, 8.);
+        let lens = parse(&[
+            "--bloom",
+            "0.1",
+            "--glare",
+            "0.05",
+            "--glare-blades",
+            "7",
+            "--vignetting",
+            "0.8",
+        ])
+        .unwrap();
+        assert_eq!(lens.bloom, Some(0.1));
+        assert_eq!((lens.glare, lens.glare_blades), (Some(0.05), 7));
+        assert_eq!(lens.vignetting, Some(0.8));
+        assert_eq!(lens.chromatic_aberration, None);
+        assert_eq!(
+            parse(&["--chromatic-aberration", "2"])
+                .unwrap()
+                .chromatic_aberration,
+            Some(2.)
+        );
+        assert_eq!(parse(&["--bloom-radius", "3"]).unwrap().bloom_radius, 3.);
+        assert!(parse(&["--glare-blades", "six"]).is_err());
+        assert!(parse(&["--denoise"]).unwrap().denoise);
+        assert_eq!(
+            parse(&["--noisy", "noisy.ppm"]).unwrap().noisy.as_deref(),
+            Some("noisy.ppm")
+        );
+        assert_eq!(
+            parse(&["--aovs", "normal,object-id"]).unwrap().aovs,
+            vec![Aov::Normal, Aov::ObjectId]
+        );
+        assert!(parse(&["--aovs", "beauty"]).is_err());
+        assert_eq!(
+            parse(&["--aov-output", "passes.exr"])
+                .unwrap()
+                .aov_output
+                .as_deref(),
+            Some("passes.exr")
+        );
+        assert!(parse(&["--nope"]).is_err());
+    }
+
+    #[test]
+    fn settings_to_args() {
+        let settings = parse(&[
+            "--scene",
+            "lights",
+            "--integrator",
+            "sppm",
+            "--direct-lighting",
+            "lights",
+            "--photon-radius",
+            "0.25",
+            "--seed",
+            "7",
+            "--sampler",
+            "halton",
+            "--filter",
+            "mitchell",
+            "--filter-radius",
+            "1.5",
+            "--orthographic",
+            "3",
+            "--time-budget",
+            "10",
+        ])
+        .unwrap();
+        let parsed = Settings::from_args(settings.to_args().into_iter()).unwrap();
+        assert_eq!(parsed.scene, "lights");
+        assert_eq!(parsed.integrator, "sppm");
+        assert_eq!(parsed.direct_lighting, DirectLighting::Lights);
+        assert_eq!(parsed.photon_radius, 0.25);
+        assert_eq!(parsed.seed, 7);
+        assert_eq!(parsed.orthographic, Some(3.));
+        assert_eq!(parsed.sampler, SamplerKind::Halton);
+        assert_eq!(parsed.filter, FilterKind::Mitchell);
+        assert_eq!(parsed.filter_radius, Some(1.5));
+        // Only the settings that change the image are given, the ones that the workers
+        // can't honour are refused
+        assert_eq!(parsed.time_budget, None);
+        assert_eq!(settings.unsupported_by_coordinator(), Some("--time-budget"));
+        assert_eq!(parsed.unsupported_by_coordinator(), None);
+        let adaptive = parse(&["--adaptive", "0.01", "--min-samples", "8"]).unwrap();
+        assert_eq!(adaptive.unsupported_by_coordinator(), Some("--adaptive"));
+    }
+
+    #[test]
+    fn settings_unsupported_by_whole_image() {
+        assert_eq!(parse(&[]).unwrap().unsupported_by_whole_image(), None);
+        let resume = parse(&["--checkpoint", "render.ckpt", "--resume"]).unwrap();
+        assert_eq!(resume.unsupported_by_whole_image(), Some("--checkpoint"));
+        let progress = parse(&["--progress", "60", "--samples", "8"]).unwrap();
+        assert_eq!(progress.unsupported_by_whole_image(), Some("--progress"));
+    }
+}
@@ -0,0 +1,401 @@
+use crate::hittable::Hittable;
+use crate::integrator::{sample_lights, Integrator, Splat};
+use crate::onb::Onb;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::scene::Scene;
+use crate::utils::power_heuristic;
+use crate::vec3::Color;
+use std::ops::{AddAssign, Mul, MulAssign};
+
+/// Shortest wavelength rendered, in nanometers
+pub const LAMBDA_MIN: f64 = 360.;
+/// Longest wavelength rendered, in nanometers
+pub const LAMBDA_MAX: f64 = 830.;
+/// Number of wavelengths carried by a path
+pub const SAMPLES: usize = 4;
+
+/// Integral of the CIE color matching functions over the rendered wavelengths
+const CIE_X_INTEGRAL: f64 = 106.7658;
+const CIE_Y_INTEGRAL: f64 = 106.9221;
+const CIE_Z_INTEGRAL: f64 = 106.8750;
+
+/// Piecewise gaussian with a different width on each side of its peak
+fn gaussian(x: f64, mu: f64, sigma_left: f64, sigma_right: f64) -> f64 {
+    let sigma = if x < mu { sigma_left } else { sigma_right };
+    let t = (x - mu) / sigma;
+    (-0.5 * t * t).exp()
+}
+
+/// The CIE 1931 color matching functions at a wavelength in nanometers,
+/// with the multi-lobe fit of Wyman, Sloan and Shirley (2013)
+pub fn cie_xyz(lambda: f64) -> (f64, f64, f64) {
+    let x = 1.056 * gaussian(lambda, 599.8, 37.9, 31.0)
+        + 0.362 * gaussian(lambda, 442.0, 16.0, 26.7)
+        - 0.065 * gaussian(lambda, 501.1, 20.4, 26.2);
+    let y =
+        0.821 * gaussian(lambda, 568.8, 46.9, 40.5) + 0.286 * gaussian(lambda, 530.9, 16.3, 31.1);
+    let z =
+        1.217 * gaussian(lambda, 437.0, 11.8, 36.0) + 0.681 * gaussian(lambda, 459.0, 26.0, 13.8);
+    (x, y, z)
+}
+
+/// Linear sRGB of a CIE XYZ color
+fn xyz_to_rgb(x: f64, y: f64, z: f64) -> Color {
+    Color::new(
+        3.2404542 * x - 1.5371385 * y - 0.4985314 * z,
+        -0.9692660 * x + 1.8760108 * y + 0.0415560 * z,
+        0.0556434 * x - 0.2040259 * y + 1.0572252 * z,
+    )
+}
+
+/// Linear sRGB of the light of a black body at a temperature in Kelvin, with a
+/// luminance of 1
+pub fn blackbody_rgb(temperature: f64) -> Color {
+    const STEPS: usize = 94;
+    let (mut x, mut y, mut z) = (0., 0., 0.);
+    for step in 0..STEPS {
+        let lambda = LAMBDA_MIN + (step as f64 + 0.5) * (LAMBDA_MAX - LAMBDA_MIN) / STEPS as f64;
+        // Planck's law, up to a constant factor
+        let meters = lambda * 1e-9;
+        let radiance = 1. / (meters.powi(5) * ((0.014387769 / (meters * temperature)).exp() - 1.));
+        let (cx, cy, cz) = cie_xyz(lambda);
+        x += cx * radiance;
+        y += cy * radiance;
+        z += cz * radiance;
+    }
+    xyz_to_rgb(x / y, 1., z / y)
+}
+
+// Spectra of Smits (1999) "An RGB-to-spectrum conversion for reflectances",
+// in 10 bins between 380 and 720 nm
+const SMITS_WHITE: [f64; 10] = [1., 1., 0.9999, 0.9993, 0.9992, 0.9998, 1., 1., 1., 1.];
+const SMITS_CYAN: [f64; 10] = [
+    0.9710, 0.9426, 1.0007, 1.0007, 1.0007, 1.0007, 0.1564, 0., 0., 0.,
+];
+const SMITS_MAGENTA: [f64; 10] = [1., 1., 0.9685, 0.2229, 0., 0.0458, 0.8369, 1., 1., 0.9959];
+const SMITS_YELLOW: [f64; 10] = [
+    0.0001, 0., 0.1088, 0.6651, 1., 1., 0.9996, 0.9586, 0.9685, 0.9840,
+];
+const SMITS_RED: [f64; 10] = [
+    0.1012, 0.0515, 0., 0., 0., 0., 0.8325, 1.0149, 1.0149, 1.0149,
+];
+const SMITS_GREEN: [f64; 10] = [0., 0., 0.0273, 0.7937, 1., 0.9418, 0.1719, 0., 0., 0.0025];
+const SMITS_BLUE: [f64; 10] = [
+    1., 1., 0.8916, 0.3323, 0., 0., 0.0003, 0.0369, 0.0483, 0.0496,
+];
+
+/// Value of an RGB color upsampled to a spectrum, at a wavelength in nanometers.
+/// The color is split, as in Smits' method, into white plus a secondary color
+/// plus a primary color, whose smooth spectra are added.
+pub fn rgb_to_spectrum(rgb: &Color, lambda: f64) -> f64 {
+    let bin = (((lambda - 380.) / 34.).max(0.) as usize).min(9);
+    let (r, g, b) = (rgb.x(), rgb.y(), rgb.z());
+    let value = if r <= g && r <= b {
+        r * SMITS_WHITE[bin]
+            + if g <= b {
+                (g - r) * SMITS_CYAN[bin] + (b - g) * SMITS_BLUE[bin]
+            } else {
+                (b - r) * SMITS_CYAN[bin] + (g - b) * SMITS_GREEN[bin]
+            }
+    } else if g <= r && g <= b {
+        g * SMITS_WHITE[bin]
+            + if r <= b {
+                (r - g) * SMITS_MAGENTA[bin] + (b - r) * SMITS_BLUE[bin]
+            } else {
+                (b - g) * SMITS_MAGENTA[bin] + (r - b) * SMITS_RED[bin]
+            }
+    } else {
+        b * SMITS_WHITE[bin]
+            + if r <= g {
+                (r - b) * SMITS_YELLOW[bin] + (g - r) * SMITS_GREEN[bin]
+            } else {
+                (g - b) * SMITS_YELLOW[bin] + (r - g) * SMITS_RED[bin]
+            }
+    };
+    value.max(0.)
+}
+
+/// Values of a spectrum at the wavelengths carried by a path
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledSpectrum(pub [f64; SAMPLES]);
+
+impl SampledSpectrum {
+    pub fn constant(value: f64) -> Self {
+        Self([value; SAMPLES])
+    }
+    /// Upsamples an RGB color at the given wavelengths
+    pub fn from_rgb(rgb: &Color, lambdas: &SampledWavelengths) -> Self {
+        let mut values = [0.; SAMPLES];
+        for (value, lambda) in values.iter_mut().zip(lambdas.lambda.iter()) {
+            *value = rgb_to_spectrum(rgb, *lambda);
+        }
+        Self(values)
+    }
+    /// The largest value
+    pub fn max(&self) -> f64 {
+        self.0.iter().cloned().fold(f64::NEG_INFINITY, f64::max)
+    }
+}
+
+impl AddAssign for SampledSpectrum {
+    fn add_assign(&mut self, other: SampledSpectrum) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a += b;
+        }
+    }
+}
+impl Mul for SampledSpectrum {
+    type Output = SampledSpectrum;
+    fn mul(mut self, other: SampledSpectrum) -> Self {
+        self *= other;
+        self
+    }
+}
+impl MulAssign for SampledSpectrum {
+    fn mul_assign(&mut self, other: SampledSpectrum) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a *= b;
+        }
+    }
+}
+impl Mul<f64> for SampledSpectrum {
+    type Output = SampledSpectrum;
+    fn mul(mut self, scalar: f64) -> Self {
+        self *= scalar;
+        self
+    }
+}
+impl MulAssign<f64> for SampledSpectrum {
+    fn mul_assign(&mut self, scalar: f64) {
+        for a in self.0.iter_mut() {
+            *a *= scalar;
+        }
+    }
+}
+
+/// Wavelengths carried by a path, in nanometers, with their densities.
+/// The first one is the hero wavelength, the others are evenly spaced after it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SampledWavelengths {
+    pub lambda: [f64; SAMPLES],
+    pub pdf: [f64; SAMPLES],
+}
+
+impl SampledWavelengths {
+    /// Hero wavelength sampling: one uniform wavelength, rotated over the range for the others
+    pub fn sample_uniform(u: f64) -> Self {
+        let range = LAMBDA_MAX - LAMBDA_MIN;
+        let mut lambda = [0.; SAMPLES];
+        for (i, l) in lambda.iter_mut().enumerate() {
+            let offset = u + i as f64 / SAMPLES as f64;
+            *l = LAMBDA_MIN + (offset - offset.floor()) * range;
+        }
+        Self {
+            lambda,
+            pdf: [1. / range; SAMPLES],
+        }
+    }
+    /// The wavelength that the path follows after a dispersive hit
+    pub fn hero(&self) -> f64 {
+        self.lambda[0]
+    }
+    /// True if only the hero wavelength is still carried
+    pub fn secondary_terminated(&self) -> bool {
+        self.pdf[1..].iter().all(|pdf| *pdf == 0.)
+    }
+    /// Drops the secondary wavelengths, after a hit that sends each wavelength
+    /// in its own direction. The hero wavelength then stands for all of them.
+    pub fn terminate_secondary(&mut self) {
+        if self.secondary_terminated() {
+            return;
+        }
+        for pdf in self.pdf[1..].iter_mut() {
+            *pdf = 0.;
+        }
+        self.pdf[0] /= SAMPLES as f64;
+    }
+
+    /// Converts the radiance at these wavelengths to linear sRGB.
+    /// The film is white balanced so that a constant spectrum gives a gray.
+    pub fn to_rgb(self, radiance: &SampledSpectrum) -> Color {
+        let (mut x, mut y, mut z) = (0., 0., 0.);
+        for i in 0..SAMPLES {
+            if self.pdf[i] == 0. {
+                continue;
+            }
+            let (cx, cy, cz) = cie_xyz(self.lambda[i]);
+            let weight = radiance.0[i] / self.pdf[i];
+            x += cx * weight;
+            y += cy * weight;
+            z += cz * weight;
+        }
+        let scale = 1. / (SAMPLES as f64 * CIE_Y_INTEGRAL);
+        let rgb = xyz_to_rgb(x * scale, y * scale, z * scale);
+        let white = xyz_to_rgb(
+            CIE_X_INTEGRAL / CIE_Y_INTEGRAL,
+            1.,
+            CIE_Z_INTEGRAL / CIE_Y_INTEGRAL,
+        );
+        Color::new(
+            rgb.x() / white.x(),
+            rgb.y() / white.y(),
+            rgb.z() / white.z(),
+        )
+    }
+}
+
+/// Spectral path tracer.
+/// Each path carries a few wavelengths, picked with hero wavelength sampling, instead of
+/// RGB. The RGB colors of the scene are upsampled to spectra, and the radiance is
+/// converted to sRGB at the film. Materials whose index of refraction depends on the
+/// wavelength split white light into its colors, as with a prism.
+/// Direct light is sampled and weighted against BSDF sampling with MIS.
+#[derive(Debug, Clone, Copy)]
+pub struct SpectralPathTracer {
+    pub max_depth: u32,
+    pub rr_depth: u32,
+}
+
+impl Integrator for SpectralPathTracer {
+    fn li(
+        &self,
+        r: &Ray,
+        scene: &Scene,
+        sampler: &mut dyn Sampler,
+        _splats: &mut Vec<Splat>,
+    ) -> Color {
+        let mut lambdas = SampledWavelengths::sample_uniform(sampler.next_1d());
+        let mut ray = *r;
+        let mut throughput = SampledSpectrum::constant(1.);
+        let mut radiance = SampledSpectrum::constant(0.);
+        // The density with which the previous hit sampled the ray, or None for camera rays
+        // and rays sampled from delta lobes
+        let mut bsdf_pdf: Option<f64> = None;
+
+        for depth in 0..self.max_depth {
+            let mut rec = match scene.world.hit(&ray, 0.001, f64::INFINITY) {
+                Some(rec) => rec,
+                None => {
+                    let background = scene.background.color(&ray);
+                    radiance += throughput * SampledSpectrum::from_rgb(&background, &lambdas);
+                    break;
+                }
+            };
+            rec.wavelength = Some(lambdas.hero());
+            let transmittance = rec.material.transmittance(&ray, &rec);
+            throughput *= SampledSpectrum::from_rgb(&transmittance, &lambdas);
+
+            let emitted = rec.material.emitted(&rec);
+            if !emitted.near_zero() {
+                let weight = match bsdf_pdf {
+                    None => 1.,
+                    Some(bsdf_pdf) => {
+                        let light_pdf = scene.lights.pdf_value(&ray.orig(), &ray.dir());
+                        power_heuristic(bsdf_pdf, light_pdf)
+                    }
+                };
+                radiance += throughput * SampledSpectrum::from_rgb(&(emitted * weight), &lambdas);
+            }
+
+            if rec.material.is_dispersive() {
+                lambdas.terminate_secondary();
+            }
+            let onb = Onb::build_from_w(&rec.normal);
+            let wo = onb.to_local(&-ray.dir().unit_vector());
+            let sample_direct = !rec.material.is_delta() && !scene.lights.is_empty();
+            if sample_direct {
+                let direct = sample_lights(&rec, &onb, &wo, scene, sampler, true);
+                radiance += throughput * SampledSpectrum::from_rgb(&direct, &lambdas);
+            }
+
+            let sample = match rec.material.sample(&wo, &rec, sampler) {
+                Some(sample) if sample.pdf > 0. => sample,
+                _ => break,
+            };
+            throughput *= SampledSpectrum::from_rgb(&sample.weight(), &lambdas);
+            ray = Ray::new(rec.point, onb.local(&sample.wi));
+            bsdf_pdf = if sample_direct && !sample.delta {
+                Some(sample.pdf)
+            } else {
+                None
+            };
+
+            // Russian roulette on the brightest wavelength
+            if depth + 1 >= self.rr_depth {
+                let survival = throughput.max().min(1.);
+                if sampler.next_1d() >= survival {
+                    break;
+                }
+                throughput *= 1. / survival;
+            }
+        }
+        lambdas.to_rgb(&radiance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blackbody_colors() {
+        // Candle light is orange, daylight near white and a clear sky blue
+        let warm = blackbody_rgb(2000.);
+        let daylight = blackbody_rgb(6500.);
+        let cold = blackbody_rgb(12000.);
+        assert!(warm.x() > warm.y() && warm.y() > warm.z());
+        assert!(cold.z() > cold.y() && cold.y() > cold.x());
+        for channel in [daylight.x(), daylight.y(), daylight.z()].iter() {
+            assert!((channel - 1.).abs() < 0.1, "{:?}", daylight);
+        }
+    }
+
+    #[test]
+    fn smits_white_is_flat() {
+        for i in 0..50 {
+            let lambda = LAMBDA_MIN + (LAMBDA_MAX - LAMBDA_MIN) * i as f64 / 49.;
+            assert!((rgb_to_spectrum(&Color::one(), lambda) - 1.).abs() < 1e-3);
+        }
+    }
+
+    /// Average color of a constant spectrum, over evenly spread hero wavelengths
+    fn average_white(terminate_secondary: bool) -> Color {
+        let n = 10000;
+        let mut rgb = Color::zero();
+        for i in 0..n {
+            let mut lambdas = SampledWavelengths::sample_uniform((i as f64 + 0.5) / n as f64);
+            if terminate_secondary {
+                lambdas.terminate_secondary();
+            }
+            rgb += lambdas.to_rgb(&SampledSpectrum::constant(1.));
+        }
+        rgb / n as f64
+    }
+
+    #[test]
+    fn constant_spectrum_is_white() {
+        let rgb = average_white(false);
+        assert!((rgb - Color::one()).norm() < 1e-3, "white was {:?}", rgb);
+    }
+
+    #[test]
+    fn terminating_secondary_wavelengths_keeps_the_hero() {
+        let mut lambdas = SampledWavelengths::sample_uniform(0.9);
+        assert!(lambdas
+            .lambda
+            .iter()
+            .all(|l| (LAMBDA_MIN..LAMBDA_MAX).contains(l)));
+        assert!(!lambdas.secondary_terminated());
+        lambdas.terminate_secondary();
+        assert!(lambdas.secondary_terminated());
+        assert_eq!(
+            lambdas.pdf[0],
+            1. / (SAMPLES as f64 * (LAMBDA_MAX - LAMBDA_MIN))
+        );
+
+        // The hero alone stands for the dropped wavelengths
+        let rgb = average_white(true);
+        assert!((rgb - Color::one()).norm() < 1e-3, "white was {:?}", rgb);
+    }
+}
@@ -1,7 +1,10 @@
+use crate::checkpoint::push_part;
 use crate::hittable::{HitRecord, Hittable};
 use crate::material::Material;
+use crate::onb::Onb;
 use crate::ray::Ray;
-use crate::vec3::{Point3, Vec3};
+use crate::sampler::Sampler;
+use crate::vec3::{random_to_sphere, random_unit_in_unit_sphere, Point3, Vec3};
 use std::rc::Rc;
 use std::sync::Arc;
 #[derive(Debug)]
@@ -24,6 +27,20 @@ impl Sphere {
             material,
         }
     }
+    /// Surface area of the sphere
+    pub fn area(&self) -> f64 {
+        4. * std::f64::consts::PI * self.radius * self.radius
+    }
+    /// Returns the (u, v) coordinates of a point on the unit sphere.
+    /// u is the angle around the y axis from x = -1, v the angle from y = -1 to y = 1
+    fn get_sphere_uv(p: &Point3) -> (f64, f64) {
+        let theta = (-p.y()).acos();
+        let phi = (-p.z()).atan2(p.x()) + std::f64::consts::PI;
+        (
+            phi / (2. * std::f64::consts::PI),
+            theta / std::f64::consts::PI,
+        )
+    }
 }
 
 impl Hittable for Sphere {
@@ -50,25 +67,90 @@ impl Hittable for Sphere {
             }
         }
 
-
         let mut rec = HitRecord {
             t: root,
             point: r.at(root),
             normal: Vec3::zero(),
+            u: 0.,
+            v: 0.,
             front_face: false,
             material: Arc::clone(&self.material),
+            wavelength: None,
         };
         let outward_normal = (rec.point - self.center) / self.radius;
         rec.set_face_normal(r, &outward_normal);
+        let (u, v) = Sphere::get_sphere_uv(&outward_normal);
+        rec.u = u;
+        rec.v = v;
 
         Some(rec)
     }
+    fn is_emissive(&self) -> bool {
+        self.material.is_emissive()
+    }
+    fn fingerprint(&self, words: &mut Vec<u64>) {
+        let c = self.center;
+        push_part(words, "sphere", &[c.x(), c.y(), c.z(), self.radius]);
+        push_part(words, self.material.name(), &self.material.fingerprint());
+    }
+    /// Directions are sampled uniformly in the cone subtended by the sphere
+    fn pdf_value(&self, origin: &Point3, dir: &Vec3) -> f64 {
+        if self
+            .hit(&Ray::new(*origin, *dir), 0.001, f64::INFINITY)
+            .is_none()
+        {
+            return 0.;
+        }
+        let distance_squared = (self.center - *origin).norm_squared();
+        let radius_squared = self.radius * self.radius;
+        if distance_squared <= radius_squared {
+            return 0.;
+        }
+        let cos_theta_max = (1. - radius_squared / distance_squared).sqrt();
+        let solid_angle = 2. * std::f64::consts::PI * (1. - cos_theta_max);
+        1. / solid_angle
+    }
+    fn random(&self, origin: &Point3, sampler: &mut dyn Sampler) -> Vec3 {
+        let direction = self.center - *origin;
+        let onb = Onb::build_from_w(&direction.unit_vector());
+        onb.local(&random_to_sphere(
+            self.radius,
+            direction.norm_squared(),
+            sampler,
+        ))
+    }
+    fn sample_surface(&self, sampler: &mut dyn Sampler) -> Option<(HitRecord, f64)> {
+        let normal = random_unit_in_unit_sphere(sampler);
+        let (u, v) = Sphere::get_sphere_uv(&normal);
+        let rec = HitRecord {
+            point: self.center + self.radius * normal,
+            normal,
+            material: Arc::clone(&self.material),
+            t: 0.,
+            u,
+            v,
+            front_face: true,
+            wavelength: None,
+        };
+        Some((rec, 1. / self.area()))
+    }
+    fn surface_pdf(&self, point: &Point3) -> f64 {
+        let distance = (*point - self.center).norm();
+        if (distance - self.radius).abs() > 1e-6 * self.radius.max(1.) {
+            return 0.;
+        }
+        1. / self.area()
+    }
+    fn material(&self) -> Option<&Arc<dyn Material>> {
+        Some(&self.material)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::material::Lambertian;
+    use crate::sampler::Seeded;
     use crate::vec3::Color;
     #[test]
     fn sphere_hit() {
@@ -80,4 +162,23 @@ mod tests {
         let r_dir = Point3::one();
         let _r = Ray::new(r_orig, r_dir);
     }
+    #[test]
+    fn sphere_random_direction_hits() {
+        let sphere = Sphere::new(
+            Point3::new(0., 5., 0.),
+            1.,
+            Arc::new(Lambertian::new(Color::zero())),
+        );
+        let origin = Point3::zero();
+        let mut sampler = Seeded::new(0);
+        for _ in 0..100 {
+            let dir = sphere.random(&origin, &mut sampler);
+            assert!(sphere
+                .hit(&Ray::new(origin, dir), 0.001, f64::INFINITY)
+                .is_some());
+            assert!(sphere.pdf_value(&origin, &dir) > 0.);
+        }
+        let away = Vec3::new(0., -1., 0.);
+        assert_eq!(sphere.pdf_value(&origin, &away), 0.);
+    }
 }
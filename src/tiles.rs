@@ -0,0 +1,208 @@
+use std::ops::Range;
+
+/// Order in which the tiles of the image are rendered
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TileOrder {
+    /// Rows of tiles from the top, left to right
+    Scanline,
+    /// Rings of tiles around the center, outwards, so the middle of the image shows first
+    Spiral,
+    /// Along a Hilbert curve, which keeps consecutive tiles close to each other
+    Hilbert,
+}
+
+/// Names of the tile orders, as given on the command line
+pub const TILE_ORDER_NAMES: [&str; 3] = ["scanline", "spiral", "hilbert"];
+
+impl TileOrder {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "scanline" => Ok(TileOrder::Scanline),
+            "spiral" => Ok(TileOrder::Spiral),
+            "hilbert" => Ok(TileOrder::Hilbert),
+            _ => Err(format!(
+                "Unknown tile order `{}`, expected one of {:?}",
+                s, TILE_ORDER_NAMES
+            )),
+        }
+    }
+}
+
+/// A rectangle of pixels of the image, `j` counting rows from the bottom
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tile {
+    pub i: Range<usize>,
+    pub j: Range<usize>,
+}
+
+impl Tile {
+    /// The rectangle `width` by `height` with its top left corner at `(left, top)`, in
+    /// pixels from the top left corner of the image. Returns None if it is empty or
+    /// doesn't fit in the image.
+    pub fn crop(
+        (left, top, width, height): (usize, usize, usize, usize),
+        image_width: usize,
+        image_height: usize,
+    ) -> Option<Tile> {
+        if width == 0 || height == 0 || left + width > image_width || top + height > image_height {
+            return None;
+        }
+        Some(Tile {
+            i: left..left + width,
+            j: image_height - top - height..image_height - top,
+        })
+    }
+    /// Indices of the pixels of the tile in an image of the given width, rows from the top
+    pub fn pixels(&self, width: usize) -> impl Iterator<Item = usize> + '_ {
+        self.j
+            .clone()
+            .rev()
+            .flat_map(move |j| self.i.clone().map(move |i| j * width + i))
+    }
+    /// The values of an image of the given width inside the tile, row by row from the
+    /// bottom like the image
+    pub fn extract<T: Copy>(&self, image: &[T], width: usize) -> Vec<T> {
+        self.j
+            .clone()
+            .flat_map(|j| self.i.clone().map(move |i| image[j * width + i]))
+            .collect()
+    }
+}
+
+/// Distance along the Hilbert curve filling a `n` by `n` square (`n` a power of 2)
+/// of the cell `(x, y)`
+fn hilbert_distance(n: usize, mut x: usize, mut y: usize) -> usize {
+    let mut d = 0;
+    let mut s = n / 2;
+    while s > 0 {
+        let rx = (x & s > 0) as usize;
+        let ry = (y & s > 0) as usize;
+        d += s * s * ((3 * rx) ^ ry);
+        // Rotate the quadrant so the curve inside it has the right orientation
+        if ry == 0 {
+            if rx == 1 {
+                x = s - 1 - (x & (s - 1));
+                y = s - 1 - (y & (s - 1));
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    d
+}
+
+/// Splits a `width` by `height` image into tiles of `size` by `size` pixels, smaller on
+/// the right and top edges, in the given order
+pub fn tiles(width: usize, height: usize, size: usize, order: TileOrder) -> Vec<Tile> {
+    let size = size.max(1);
+    let columns = width.div_ceil(size);
+    let rows = height.div_ceil(size);
+    // Tile coordinates, with rows from the top
+    let mut cells: Vec<(usize, usize)> = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .collect();
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => {
+            let center_x = (columns as f64 - 1.) / 2.;
+            let center_y = (rows as f64 - 1.) / 2.;
+            let key = |&(column, row): &(usize, usize)| {
+                let dx = column as f64 - center_x;
+                let dy = row as f64 - center_y;
+                let ring = dx.abs().max(dy.abs()).round();
+                (ring, dy.atan2(dx))
+            };
+            cells.sort_by(|a, b| {
+                let (a, b) = (key(a), key(b));
+                a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+            });
+        }
+        TileOrder::Hilbert => {
+            let n = columns.max(rows).next_power_of_two();
+            cells.sort_by_key(|&(column, row)| hilbert_distance(n, column, row));
+        }
+    }
+    cells
+        .into_iter()
+        .map(|(column, row)| {
+            let top = height - row * size;
+            Tile {
+                i: column * size..((column + 1) * size).min(width),
+                j: top.saturating_sub(size)..top,
+            }
+        })
+        .collect()
+}
+
+/// Splits a region of the image into tiles, like `tiles`
+pub fn tiles_in(region: &Tile, size: usize, order: TileOrder) -> Vec<Tile> {
+    tiles(region.i.len(), region.j.len(), size, order)
+        .into_iter()
+        .map(|tile| Tile {
+            i: region.i.start + tile.i.start..region.i.start + tile.i.end,
+            j: region.j.start + tile.j.start..region.j.start + tile.j.end,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_the_image_once() {
+        for order in [TileOrder::Scanline, TileOrder::Spiral, TileOrder::Hilbert].iter() {
+            let tiles = tiles(37, 21, 8, *order);
+            assert_eq!(tiles.len(), 5 * 3);
+            let mut pixels: Vec<usize> = tiles.iter().flat_map(|tile| tile.pixels(37)).collect();
+            pixels.sort_unstable();
+            assert_eq!(pixels, (0..37 * 21).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn cropped_region() {
+        assert_eq!(Tile::crop((0, 0, 0, 4), 10, 8), None);
+        assert_eq!(Tile::crop((8, 0, 3, 4), 10, 8), None);
+        // Rows are counted from the top for the crop, from the bottom for the tile
+        let region = Tile::crop((2, 1, 3, 2), 10, 8).unwrap();
+        assert_eq!(region, Tile { i: 2..5, j: 5..7 });
+        let image: Vec<usize> = (0..80).collect();
+        assert_eq!(region.extract(&image, 10), vec![52, 53, 54, 62, 63, 64]);
+        let tiles = tiles_in(&region, 2, TileOrder::Scanline);
+        let mut pixels: Vec<usize> = tiles.iter().flat_map(|tile| tile.pixels(10)).collect();
+        pixels.sort_unstable();
+        assert_eq!(pixels, region.extract(&image, 10));
+    }
+
+    #[test]
+    fn tile_orders() {
+        // Scanline starts at the top left corner
+        let scanline = tiles(64, 64, 16, TileOrder::Scanline);
+        assert_eq!(
+            scanline[0],
+            Tile {
+                i: 0..16,
+                j: 48..64
+            }
+        );
+        // Spiral starts in the middle and ends on the border
+        let spiral = tiles(80, 80, 16, TileOrder::Spiral);
+        assert_eq!(
+            spiral[0],
+            Tile {
+                i: 32..48,
+                j: 32..48
+            }
+        );
+        let last = spiral.last().unwrap();
+        assert!([0, 64].contains(&last.i.start) || [0, 64].contains(&last.j.start));
+        // Consecutive tiles of the Hilbert curve are neighbours
+        let hilbert = tiles(64, 64, 16, TileOrder::Hilbert);
+        for pair in hilbert.windows(2) {
+            let di = (pair[0].i.start as i64 - pair[1].i.start as i64).abs();
+            let dj = (pair[0].j.start as i64 - pair[1].j.start as i64).abs();
+            assert_eq!(di + dj, 16);
+        }
+    }
+}
@@ -0,0 +1,279 @@
+use crate::color::luminance;
+use crate::lens::LensEffects;
+use crate::settings::Settings;
+use crate::spectrum::blackbody_rgb;
+use crate::tiles::Tile;
+use crate::utils::clamp;
+use crate::vec3::Color;
+
+/// Operator that compresses the linear colors of the image into the displayable range
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToneMapper {
+    /// Colors above 1 are clipped
+    Clamp,
+    /// c / (1 + c): everything fits, but highlights are dull
+    Reinhard,
+    /// Reinhard that maps the white point to 1, keeping more contrast
+    ExtendedReinhard,
+    /// Fit of the ACES filmic curve, with its tone of the colors
+    Aces,
+    /// The AgX curve, that desaturates bright colors towards white like film
+    Agx,
+}
+
+/// Names of the tone mapping operators, as given on the command line
+pub const TONE_MAPPER_NAMES: [&str; 5] = ["clamp", "reinhard", "reinhard-extended", "aces", "agx"];
+
+impl ToneMapper {
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "clamp" => Ok(ToneMapper::Clamp),
+            "reinhard" => Ok(ToneMapper::Reinhard),
+            "reinhard-extended" => Ok(ToneMapper::ExtendedReinhard),
+            "aces" => Ok(ToneMapper::Aces),
+            "agx" => Ok(ToneMapper::Agx),
+            _ => Err(format!(
+                "Unknown tone mapping `{}`, expected one of {:?}",
+                s, TONE_MAPPER_NAMES
+            )),
+        }
+    }
+}
+
+/// Applies a 3x3 matrix, given by rows, to a color
+fn transform(m: &[[f64; 3]; 3], c: Color) -> Color {
+    Color::new(
+        m[0][0] * c.x() + m[0][1] * c.y() + m[0][2] * c.z(),
+        m[1][0] * c.x() + m[1][1] * c.y() + m[1][2] * c.z(),
+        m[2][0] * c.x() + m[2][1] * c.y() + m[2][2] * c.z(),
+    )
+}
+fn map_channels<F: Fn(f64) -> f64>(c: Color, f: F) -> Color {
+    Color::new(f(c.x()), f(c.y()), f(c.z()))
+}
+
+/// The ACES reference rendering and output transforms, fitted by Stephen Hill
+fn aces(c: Color) -> Color {
+    const INPUT: [[f64; 3]; 3] = [
+        [0.59719, 0.35458, 0.04823],
+        [0.07600, 0.90834, 0.01566],
+        [0.02840, 0.13383, 0.83777],
+    ];
+    const OUTPUT: [[f64; 3]; 3] = [
+        [1.60475, -0.53108, -0.07367],
+        [-0.10208, 1.10813, -0.00605],
+        [-0.00327, -0.07276, 1.07602],
+    ];
+    let c = map_channels(transform(&INPUT, c), |v| {
+        (v * (v + 0.0245786) - 0.000090537) / (v * (0.983729 * v + 0.4329510) + 0.238081)
+    });
+    transform(&OUTPUT, c)
+}
+
+/// AgX of Troy Sobotka, with the polynomial fit of its default contrast curve
+fn agx(c: Color) -> Color {
+    const INSET: [[f64; 3]; 3] = [
+        [0.842479062253094, 0.0784335999999992, 0.0792237451477643],
+        [0.0423282422610123, 0.878468636469772, 0.0791661274605434],
+        [0.0423756549057051, 0.0784336, 0.879142973793104],
+    ];
+    const OUTSET: [[f64; 3]; 3] = [
+        [1.19687900512017, -0.0980208811401368, -0.0990297440797205],
+        [-0.0528968517574562, 1.15190312990417, -0.0989611768448433],
+        [-0.0529716355144438, -0.0980434501171241, 1.15107367264116],
+    ];
+    // Range of exposures around middle gray that the curve covers
+    const MIN_EV: f64 = -12.47393;
+    const MAX_EV: f64 = 4.026069;
+    let c = map_channels(transform(&INSET, c), |v| {
+        let x = (clamp(v.max(1e-10).log2(), MIN_EV, MAX_EV) - MIN_EV) / (MAX_EV - MIN_EV);
+        let x2 = x * x;
+        let x4 = x2 * x2;
+        15.5 * x4 * x2 - 40.14 * x4 * x + 31.96 * x4 - 6.868 * x2 * x + 0.4298 * x2 + 0.1191 * x
+            - 0.00232
+    });
+    // The curve gives display encoded values, back to linear
+    map_channels(transform(&OUTSET, c), |v| v.max(0.).powf(2.2))
+}
+
+/// Post processing of the linear image before it is displayed: the effects of the lens,
+/// exposure, white balance and tone mapping
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PostProcess {
+    /// Exposure compensation in stops: the image is scaled by 2^exposure
+    pub exposure: f64,
+    /// Color temperature in Kelvin of the light that should appear white
+    pub white_balance: Option<f64>,
+    pub tone_mapper: ToneMapper,
+    /// Smallest value that the extended Reinhard operator maps to white
+    pub white_point: f64,
+    pub lens: LensEffects,
+}
+
+/// Temperature in Kelvin of the white of sRGB, where the white balance does nothing
+const NEUTRAL_TEMPERATURE: f64 = 6504.;
+
+impl PostProcess {
+    pub fn from_settings(settings: &Settings) -> Self {
+        Self {
+            exposure: settings.exposure,
+            white_balance: settings.white_balance,
+            tone_mapper: settings.tone_mapper,
+            white_point: settings.white_point,
+            lens: LensEffects::from_settings(settings),
+        }
+    }
+
+    /// Maps a linear color of the image to a linear color in [0, 1] for the display
+    fn apply(&self, color: Color, white_balance: Color) -> Color {
+        let c = color * 2f64.powf(self.exposure);
+        let c = Color::new(
+            c.x() * white_balance.x(),
+            c.y() * white_balance.y(),
+            c.z() * white_balance.z(),
+        );
+        // Colors out of the sRGB gamut, as spectral rendering can give, may be negative
+        let c = map_channels(c, |v| v.max(0.));
+        let c = match self.tone_mapper {
+            ToneMapper::Clamp => c,
+            ToneMapper::Reinhard => map_channels(c, |v| v / (1. + v)),
+            ToneMapper::ExtendedReinhard => {
+                let white = self.white_point * self.white_point;
+                map_channels(c, |v| v * (1. + v / white) / (1. + v))
+            }
+            ToneMapper::Aces => aces(c),
+            ToneMapper::Agx => agx(c),
+        };
+        map_channels(c, |v| clamp(v, 0., 1.))
+    }
+
+    /// Scale of the channels that makes a light of the white balance temperature white,
+    /// keeping its luminance
+    fn white_balance_scale(&self) -> Color {
+        match self.white_balance {
+            Some(temperature) => {
+                let neutral = blackbody_rgb(NEUTRAL_TEMPERATURE);
+                let light = blackbody_rgb(temperature);
+                let scale = Color::new(
+                    neutral.x() / light.x(),
+                    neutral.y() / light.y(),
+                    neutral.z() / light.z(),
+                );
+                scale / luminance(&scale)
+            }
+            None => Color::one(),
+        }
+    }
+
+    /// Post processes an image, row by row from the bottom
+    pub fn image(&self, image: &[Color], width: usize, height: usize) -> Vec<Color> {
+        self.map_colors(&self.lens.apply(image, width, height))
+    }
+    /// Post processes the image of a region of a frame of the given size, with the
+    /// effects of the lens centered on the frame
+    pub fn region_image(
+        &self,
+        image: &[Color],
+        region: &Tile,
+        frame: (usize, usize),
+    ) -> Vec<Color> {
+        self.map_colors(&self.lens.apply_in(image, region, frame))
+    }
+    fn map_colors(&self, image: &[Color]) -> Vec<Color> {
+        let white_balance = self.white_balance_scale();
+        image
+            .iter()
+            .map(|color| self.apply(*color, white_balance))
+            .collect()
+    }
+}
+
+/// The sRGB transfer function, from linear to display encoded values in [0, 1]
+pub fn srgb_encode(v: f64) -> f64 {
+    let v = clamp(v, 0., 1.);
+    if v <= 0.0031308 {
+        12.92 * v
+    } else {
+        1.055 * v.powf(1. / 2.4) - 0.055
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post_process(tone_mapper: ToneMapper) -> PostProcess {
+        PostProcess {
+            exposure: 0.,
+            white_balance: None,
+            tone_mapper,
+            white_point: 4.,
+            lens: LensEffects::default(),
+        }
+    }
+
+    #[test]
+    fn tone_mappers() {
+        for name in TONE_MAPPER_NAMES.iter() {
+            let post = post_process(ToneMapper::parse(name).unwrap());
+            let apply = |v: f64| post.apply(Color::one() * v, Color::one()).x();
+            // Black stays black and brighter inputs are never darker
+            assert!(apply(0.) < 0.01, "{}", name);
+            let mut last = 0.;
+            for v in [0.01, 0.1, 0.18, 0.5, 1., 2., 8., 100.].iter() {
+                let mapped = apply(*v);
+                assert!(mapped >= last && mapped <= 1., "{} of {}", name, v);
+                last = mapped;
+            }
+        }
+        assert!(ToneMapper::parse("filmic").is_err());
+        let reinhard = post_process(ToneMapper::Reinhard);
+        assert_eq!(
+            reinhard.apply(Color::one(), Color::one()),
+            Color::one() * 0.5
+        );
+        // The white point is mapped to white
+        let extended = post_process(ToneMapper::ExtendedReinhard);
+        let white = extended.apply(Color::one() * 4., Color::one());
+        assert!((white - Color::one()).near_zero());
+        // Filmic curves leave a little headroom, unlike clamping
+        let clamp = post_process(ToneMapper::Clamp);
+        assert_eq!(clamp.apply(Color::one() * 1.5, Color::one()), Color::one());
+        assert!(
+            post_process(ToneMapper::Aces)
+                .apply(Color::one() * 1.5, Color::one())
+                .x()
+                < 1.
+        );
+    }
+
+    #[test]
+    fn exposure_and_white_balance() {
+        let post = PostProcess {
+            exposure: 1.,
+            ..post_process(ToneMapper::Clamp)
+        };
+        assert_eq!(
+            post.image(&[Color::one() * 0.25], 1, 1),
+            vec![Color::one() * 0.5]
+        );
+        // Balancing for candle light makes it gray
+        let candle = blackbody_rgb(2000.) * 0.2;
+        let balanced = PostProcess {
+            white_balance: Some(2000.),
+            ..post_process(ToneMapper::Clamp)
+        }
+        .image(&[candle], 1, 1)[0];
+        assert!((balanced.x() - balanced.z()).abs() < 0.02, "{:?}", balanced);
+    }
+
+    #[test]
+    fn srgb_transfer() {
+        assert_eq!(srgb_encode(0.), 0.);
+        assert!((srgb_encode(1.) - 1.).abs() < 1e-12);
+        assert_eq!(srgb_encode(2.), srgb_encode(1.));
+        assert!((srgb_encode(0.18) - 0.4614).abs() < 1e-3);
+        // Linear near black
+        assert!((srgb_encode(0.001) - 0.01292).abs() < 1e-9);
+    }
+}
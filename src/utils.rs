@@ -8,6 +8,17 @@ pub fn clamp<T: PartialOrd>(x: T, min: T, max: T) -> T {
     }
 }
 
+/// Power heuristic (with beta = 2) for multiple importance sampling.
+/// Returns the weight of a sample drawn with density `f_pdf`, when it could also have been drawn with density `g_pdf`.
+pub fn power_heuristic(f_pdf: f64, g_pdf: f64) -> f64 {
+    let f = f_pdf * f_pdf;
+    let g = g_pdf * g_pdf;
+    if f + g == 0. {
+        return 0.;
+    }
+    f / (f + g)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -17,4 +28,11 @@ mod tests {
         assert_eq!(clamp(100, 1, 3), 3);
         assert_eq!(clamp(-1, 1, 3), 1);
     }
+    #[test]
+    fn power_heuristic_test() {
+        assert_eq!(power_heuristic(1., 1.), 0.5);
+        assert_eq!(power_heuristic(1., 0.), 1.);
+        assert_eq!(power_heuristic(0., 0.), 0.);
+        assert_eq!(power_heuristic(3., 1.) + power_heuristic(1., 3.), 1.);
+    }
 }
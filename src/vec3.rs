@@ -1,28 +1,28 @@
-use rand::distributions::{Distribution, Uniform};
-use rand::Rng;
+use crate::sampler::Sampler;
 use std::cmp::PartialEq;
-use std::ops::{Add, AddAssign, Div, DivAssign, Mul, MulAssign, Neg, Sub, SubAssign};
+use std::ops::{Add, AddAssign, Div, DivAssign, Index, Mul, MulAssign, Neg, Sub, SubAssign};
 // Type aliases for Vec3
 pub type Point3 = Vec3; // #d point
 pub type Color = Vec3; // RGB color
 
 /// Generates a random vector in the unit sphere
-pub fn random_in_unit_sphere() -> Vec3 {
-    loop {
-        let p = Vec3::random_interval(-1., 1.);
-        if p.norm_squared() < 1. {
-            return p;
-        }
-    }
+pub fn random_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+    // A uniform direction, at a distance whose cube is uniform
+    let direction = random_unit_in_unit_sphere(sampler);
+    sampler.next_1d().cbrt() * direction
 }
 /// Generates a random unit vector in the unit sphere
-pub fn random_unit_in_unit_sphere() -> Vec3 {
-    random_in_unit_sphere().unit_vector()
+pub fn random_unit_in_unit_sphere(sampler: &mut dyn Sampler) -> Vec3 {
+    let (u1, u2) = sampler.next_2d();
+    let z = 1. - 2. * u1;
+    let r = (1. - z * z).max(0.).sqrt();
+    let phi = 2. * std::f64::consts::PI * u2;
+    Vec3::new(r * phi.cos(), r * phi.sin(), z)
 }
 
 /// Uniform scatter for all angles **away** from the hit point
-pub fn random_in_hemisphere(normal: &Vec3) -> Vec3 {
-    let in_unit_sphere = random_in_unit_sphere();
+pub fn random_in_hemisphere(normal: &Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+    let in_unit_sphere = random_in_unit_sphere(sampler);
     if in_unit_sphere.dot(normal) > 0. {
         in_unit_sphere
     } else {
@@ -30,14 +30,38 @@ pub fn random_in_hemisphere(normal: &Vec3) -> Vec3 {
     }
 }
 
-/// Generates a vector in a unit 2d disk
-pub fn random_in_unit_disk() -> Vec3 {
-    loop {
-        let p = Vec3::new(rand::random::<f64>(), rand::random::<f64>(), 0.);
-        if p.norm_squared() < 1. {
-            return p;
-        }
-    }
+/// Generates a vector in a unit 2d disk, with the concentric mapping of Shirley and Chiu
+pub fn random_in_unit_disk(sampler: &mut dyn Sampler) -> Vec3 {
+    let (u1, u2) = sampler.next_2d();
+    let (a, b) = (2. * u1 - 1., 2. * u2 - 1.);
+    if a == 0. && b == 0. {
+        return Vec3::zero();
+    }
+    let quarter = std::f64::consts::FRAC_PI_4;
+    let (r, phi) = if a.abs() > b.abs() {
+        (a, quarter * (b / a))
+    } else {
+        (b, 2. * quarter - quarter * (a / b))
+    };
+    Vec3::new(r * phi.cos(), r * phi.sin(), 0.)
+}
+
+/// Generates a cosine weighted direction on the hemisphere around the z axis
+pub fn random_cosine_direction(sampler: &mut dyn Sampler) -> Vec3 {
+    let (r1, r2) = sampler.next_2d();
+    let phi = 2. * std::f64::consts::PI * r1;
+    let z = (1. - r2).sqrt();
+    Vec3::new(phi.cos() * r2.sqrt(), phi.sin() * r2.sqrt(), z)
+}
+
+/// Generates a direction uniformly in the cone around the z axis that
+/// sees a sphere of the given radius at squared distance `distance_squared`
+pub fn random_to_sphere(radius: f64, distance_squared: f64, sampler: &mut dyn Sampler) -> Vec3 {
+    let (r1, r2) = sampler.next_2d();
+    let z = 1. + r2 * ((1. - radius * radius / distance_squared).max(0.).sqrt() - 1.);
+    let phi = 2. * std::f64::consts::PI * r1;
+    let sin_theta = (1. - z * z).max(0.).sqrt();
+    Vec3::new(phi.cos() * sin_theta, phi.sin() * sin_theta, z)
 }
 
 // Derive PartialEq implies 2 vectors are equal if all fields are equal
@@ -66,21 +90,18 @@ impl Vec3 {
         }
     }
     /// Returns a random vector
-    pub fn random() -> Self {
-        Self {
-            x: rand::random::<f64>(),
-            y: rand::random::<f64>(),
-            z: rand::random::<f64>(),
-        }
+    pub fn random(sampler: &mut dyn Sampler) -> Self {
+        Self::random_interval(0., 1., sampler)
     }
     /// Returns a random vector with coordinates uniformly sampled between min and max
-    pub fn random_interval(min: f64, max: f64) -> Self {
-        let d = Uniform::from(min..max);
-        let mut rng = rand::thread_rng();
+    pub fn random_interval(min: f64, max: f64, sampler: &mut dyn Sampler) -> Self {
+        let mut coordinate = || min + (max - min) * sampler.next_1d();
+        let x = coordinate();
+        let y = coordinate();
         Self {
-            x: d.sample(&mut rng),
-            y: d.sample(&mut rng),
-            z: d.sample(&mut rng),
+            x,
+            y,
+            z: coordinate(),
         }
     }
 
@@ -146,6 +167,14 @@ impl Vec3 {
         let r_out_parallel = -(1. - r_out_perp.norm_squared()).abs().sqrt() * *normal;
         r_out_perp + r_out_parallel
     }
+    /// Component-wise exponential
+    pub fn exp(&self) -> Vec3 {
+        Self {
+            x: self.x.exp(),
+            y: self.y.exp(),
+            z: self.z.exp(),
+        }
+    }
 }
 
 impl Default for Vec3 {
@@ -257,6 +286,20 @@ impl DivAssign<f64> for Vec3 {
     }
 }
 
+/// Gets a coordinate by its axis: 0 for x, 1 for y and 2 for z
+impl Index<usize> for Vec3 {
+    type Output = f64;
+
+    fn index(&self, axis: usize) -> &f64 {
+        match axis {
+            0 => &self.x,
+            1 => &self.y,
+            2 => &self.z,
+            _ => panic!("Vec3 axis out of range: {}", axis),
+        }
+    }
+}
+
 impl std::iter::Sum for Vec3 {
     fn sum<I: Iterator<Item = Self>>(iter: I) -> Self {
         iter.fold(Vec3::zero(), |acc, v| acc + v)
@@ -265,6 +308,7 @@ impl std::iter::Sum for Vec3 {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sampler::Seeded;
     #[test]
     fn vec3_default() {
         let v = Vec3::default();
@@ -306,9 +350,33 @@ mod tests {
     }
     #[test]
     fn vec3_random() {
-        let v1 = Vec3::random();
+        let v1 = Vec3::random(&mut Seeded::new(0));
         dbg!(v1);
-        let v2 = Vec3::random_interval(10., 100.);
+        let v2 = Vec3::random_interval(10., 100., &mut Seeded::new(1));
         dbg!(v2);
+        assert_eq!(v2, Vec3::random_interval(10., 100., &mut Seeded::new(1)));
+    }
+    #[test]
+    fn unit_disk_covers_all_quadrants() {
+        // The lens used to be sampled only in the [0, 1)² quadrant, which shifted the
+        // depth of field blur and biased the camera paths of BDPT
+        let mut sampler = Seeded::new(0);
+        let mut quadrants = [0; 4];
+        let mut center = Vec3::zero();
+        let n = 4000;
+        for _ in 0..n {
+            let p = random_in_unit_disk(&mut sampler);
+            assert!(p.norm_squared() <= 1. + 1e-12 && p.z() == 0.);
+            quadrants[(p.x() < 0.) as usize + 2 * (p.y() < 0.) as usize] += 1;
+            center += p;
+        }
+        for count in quadrants.iter() {
+            assert!(
+                (*count as f64 - n as f64 / 4.).abs() < 100.,
+                "{:?}",
+                quadrants
+            );
+        }
+        assert!((center / n as f64).norm() < 0.05);
     }
 }